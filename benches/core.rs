@@ -0,0 +1,228 @@
+//! Instructions per second through the core, run with `cargo bench`.
+//!
+//! "in place" is how the emulator steps now. "by value" copies the whole
+//! machine around every instruction, as `run_cycle` used to, to show what
+//! that cost. "cached" steps through a `DecodeCache`, as the window's core
+//! does, and "jit" runs compiled blocks when built with `--features jit`.
+//! The `registers` group repeats the first two on a loop without DXYN. The
+//! `runner` group times the core thread the window and terminal run, with
+//! its history, observers and frames. The `draw` group compares sprite
+//! drawing on the packed display with the `bool` per pixel loop it replaced.
+
+use std::thread;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use chip8::display::{self, Display};
+use chip8::decode::DecodeCache;
+use chip8::profile::Profiler;
+use chip8::runner::Runner;
+use chip8::trace::Tracer;
+use chip8::{Config, Machine};
+
+/// Instructions per benchmark iteration.
+const INSTRUCTIONS: u64 = 1000;
+
+// LD V0, 1; ADD V1, 3; ADD V1, V0; LD I, 0x300; DRW V0, V1, 5; JP 0x200
+const PROGRAM: [u8; 12] = [0x60, 0x01, 0x71, 0x03, 0x81, 0x04, 0xA3, 0x00, 0xD0, 0x15, 0x12, 0x00];
+
+// LD V0, 1; ADD V1, 3; ADD V1, V0; SHR V2, V1; XOR V3, V2; SE V3, 0x40; JP 0x200; JP 0x200
+const REGISTERS: [u8; 16] = [0x60, 0x01, 0x71, 0x03, 0x81, 0x04, 0x82, 0x16, 0x83, 0x23, 0x33, 0x40, 0x12, 0x00, 0x12, 0x00];
+
+// ADD V0, 1; ADD V2, V0; SE V0, 0; JP 0x200; ADD V1, 1; SE V1, 0; JP 0x200; RET
+// counts V1:V0 up to 65536, then returns with an empty stack to stop the core
+const COUNT: [u8; 16] = [0x70, 0x01, 0x82, 0x04, 0x30, 0x00, 0x12, 0x00, 0x71, 0x01, 0x31, 0x00, 0x12, 0x00, 0x00, 0xEE];
+
+fn machine() -> Machine {
+    loaded(&PROGRAM)
+}
+
+fn loaded(program: &[u8]) -> Machine {
+    let mut machine = Machine::new(Config::default());
+    machine.load_program(program, 0x200);
+    machine
+}
+
+/// The old `run_cycle`: takes the machine by value and hands back a
+/// changed copy.
+#[inline(never)]
+fn run_cycle(machine: &Machine) -> Machine {
+    let mut next = machine.clone();
+    next.step().unwrap();
+    next
+}
+
+fn step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+
+    group.bench_function("in place", |b| {
+        let mut machine = machine();
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                machine.step().unwrap();
+            }
+            black_box(&machine);
+        })
+    });
+
+    group.bench_function("cached", |b| {
+        let mut machine = machine();
+        let mut cache = DecodeCache::new();
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                machine.step_cached(&mut cache, &mut ()).unwrap();
+            }
+            black_box(&machine);
+        })
+    });
+
+    #[cfg(feature = "jit")]
+    group.bench_function("jit", |b| {
+        let mut machine = machine();
+        let mut jit = chip8::jit::Jit::new().unwrap();
+        b.iter(|| {
+            jit.run(&mut machine, INSTRUCTIONS).unwrap();
+            black_box(&machine);
+        })
+    });
+
+    group.bench_function("by value", |b| {
+        let mut machine = machine();
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                machine = run_cycle(&machine);
+            }
+            black_box(&machine);
+        })
+    });
+
+    group.finish();
+}
+
+/// The same comparison on a loop without DXYN, where decoding is a larger
+/// share of each instruction.
+fn registers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("registers");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+
+    group.bench_function("in place", |b| {
+        let mut machine = loaded(&REGISTERS);
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                machine.step().unwrap();
+            }
+            black_box(&machine);
+        })
+    });
+
+    group.bench_function("cached", |b| {
+        let mut machine = loaded(&REGISTERS);
+        let mut cache = DecodeCache::new();
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                machine.step_cached(&mut cache, &mut ()).unwrap();
+            }
+            black_box(&machine);
+        })
+    });
+
+    group.finish();
+}
+
+/// The loop the frontends run: a `Runner` at a speed it can't reach, so
+/// the core never sleeps, with the observers the window passes it when no
+/// trace or profile was asked for. An iteration is one run of `COUNT`, from
+/// spawning the core to the frame with its fault.
+fn runner(c: &mut Criterion) {
+    let program = loaded(&COUNT);
+    let mut machine = program.clone();
+    let mut instructions = 1;
+    while machine.step().is_ok() {
+        instructions += 1;
+    }
+
+    let mut group = c.benchmark_group("runner");
+    group.throughput(Throughput::Elements(instructions));
+    group.sample_size(20);
+
+    group.bench_function("core thread", |b| {
+        b.iter(|| {
+            let observers: (Option<Profiler>, Option<Tracer>) = (None, None);
+            // as many instructions as the debug panel lists
+            let runner = Runner::spawn(program.clone(), u32::MAX, observers, 8);
+            loop {
+                if runner.frame().is_some_and(|frame| frame.fault.is_some()) {
+                    break;
+                }
+                thread::yield_now();
+            }
+            runner.stop()
+        })
+    });
+
+    group.finish();
+}
+
+/// Sprites drawn per benchmark iteration: every position on a 64x32 grid.
+const SPRITES: u64 = (display::WIDTH * display::HEIGHT) as u64;
+
+/// An 8x15 sprite, the tallest DXYN draws.
+const SPRITE: [u8; 15] = [0xF0, 0x90, 0xF0, 0x90, 0x90, 0xE0, 0x90, 0xE0, 0x90, 0xE0, 0xF0, 0x80, 0x80, 0x80, 0xF0];
+
+/// DXYN as it was, one pixel at a time with a collision check on each.
+fn draw_bools(gfx: &mut [bool; 64*32], x: usize, y: usize, sprite: &[u8]) -> bool {
+    let mut collision = false;
+    for (yline, &pixel) in sprite.iter().enumerate() {
+        if y + yline >= 32 {
+            break;
+        }
+        for xline in 0..8 {
+            if x + xline >= 64 {
+                break;
+            }
+            if pixel & (0x80 >> xline) != 0 {
+                let index = x + xline + (y + yline) * 64;
+                collision |= gfx[index];
+                gfx[index] = !gfx[index];
+            }
+        }
+    }
+    collision
+}
+
+fn draw(c: &mut Criterion) {
+    let mut group = c.benchmark_group("draw");
+    group.throughput(Throughput::Elements(SPRITES));
+
+    group.bench_function("packed rows", |b| {
+        let mut gfx = Display::new();
+        b.iter(|| {
+            let mut collisions = 0;
+            for y in 0..display::HEIGHT {
+                for x in 0..display::WIDTH {
+                    collisions += gfx.draw_sprite(x, y, black_box(&SPRITE)) as u32;
+                }
+            }
+            black_box(collisions)
+        })
+    });
+
+    group.bench_function("bool per pixel", |b| {
+        let mut gfx = [false; 64*32];
+        b.iter(|| {
+            let mut collisions = 0;
+            for y in 0..display::HEIGHT {
+                for x in 0..display::WIDTH {
+                    collisions += draw_bools(&mut gfx, x, y, black_box(&SPRITE)) as u32;
+                }
+            }
+            black_box(collisions)
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, step, registers, runner, draw);
+criterion_main!(benches);
@@ -0,0 +1,121 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extensions of files the loader can open.
+const ROM_EXTENSIONS: [&str; 14] = [
+    "ch8", "c8", "sc8", "xo8", "bin", "rom", "gif", "zip",
+    "hex", "ihx", "srec", "s19", "s28", "s37"
+];
+
+pub struct Entry {
+    pub name: String,
+    pub path: PathBuf,
+    pub is_dir: bool
+}
+
+/// A list of the folders and ROMs in one directory, with a cursor.
+pub struct Browser {
+    pub dir: PathBuf,
+    pub entries: Vec<Entry>,
+    pub selected: usize
+}
+
+impl Browser {
+    pub fn open(dir: &Path) -> io::Result<Browser> {
+        let dir = dir.canonicalize()?;
+        let mut entries = Vec::new();
+        for item in fs::read_dir(&dir)? {
+            let item = item?;
+            let path = item.path();
+            let name = item.file_name().to_string_lossy().into_owned();
+            let is_dir = path.is_dir();
+            let is_rom = path.extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.as_str()));
+            if !name.starts_with('.') && (is_dir || is_rom) {
+                entries.push(Entry { name, path, is_dir });
+            }
+        }
+        // folders first, then files, each alphabetically
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase())));
+        if let Some(parent) = dir.parent() {
+            entries.insert(0, Entry { name: "..".to_owned(), path: parent.to_path_buf(), is_dir: true });
+        }
+        Ok(Browser { dir, entries, selected: 0 })
+    }
+
+    pub fn up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        if self.selected + 1 < self.entries.len() {
+            self.selected += 1;
+        }
+    }
+
+    /// Goes up to the parent folder, if there is one.
+    pub fn leave(&mut self) -> io::Result<()> {
+        if let Some(parent) = self.dir.parent() {
+            *self = Browser::open(parent)?;
+        }
+        Ok(())
+    }
+
+    /// Opens the selected folder, or returns the selected ROM.
+    pub fn activate(&mut self) -> io::Result<Option<PathBuf>> {
+        match self.entries.get(self.selected) {
+            Some(entry) if entry.is_dir => {
+                *self = Browser::open(&entry.path)?;
+                Ok(None)
+            },
+            Some(entry) => Ok(Some(entry.path.clone())),
+            None => Ok(None)
+        }
+    }
+
+    /// Index of the first entry to draw so the cursor stays within `rows`.
+    pub fn scroll(&self, rows: usize) -> usize {
+        (self.selected + 1).saturating_sub(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn lists_folders_then_roms() {
+        let dir = env::temp_dir().join("chip8-browser-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("Games")).unwrap();
+        for name in ["b.ch8", "A.zip", "notes.txt", ".hidden.ch8"] {
+            fs::write(dir.join(name), []).unwrap();
+        }
+
+        let mut browser = Browser::open(&dir).unwrap();
+        let names: Vec<&str> = browser.entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["..", "Games", "A.zip", "b.ch8"]);
+
+        browser.up();
+        assert_eq!(browser.selected, 0);
+        browser.down();
+        browser.down();
+        browser.down();
+        browser.down();
+        assert_eq!(browser.selected, 3);
+        assert_eq!(browser.scroll(2), 2);
+        assert_eq!(browser.activate().unwrap(), Some(browser.dir.join("b.ch8")));
+
+        browser.selected = 1;
+        assert_eq!(browser.activate().unwrap(), None);
+        assert!(browser.dir.ends_with("Games"));
+        assert_eq!(browser.entries.len(), 1);
+        browser.leave().unwrap();
+        assert_eq!(browser.dir, dir.canonicalize().unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Octo cartridges: GIFs whose palette indices carry a JSON payload with the
+//! program's Octo source and the options it was saved with. The source is
+//! assembled by `octo`.
+//!
+//! Each byte of the payload is spread over four pixels, two bits per pixel
+//! taken from the low bits of the colour index, most significant first,
+//! running through every frame in order. The first four bytes give the
+//! length of the JSON that follows, big-endian.
+
+use serde_json::Value;
+
+use crate::palette::{parse_colour, Palette};
+use crate::database::RomInfo;
+use crate::loader::LoadError;
+use crate::{octo, Config};
+
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    pub info: RomInfo
+}
+
+pub fn read(gif: &[u8]) -> Result<Cartridge, LoadError> {
+    let payload = payload(gif)?;
+    let json: Value = serde_json::from_slice(&payload)
+        .map_err(|err| LoadError::Cartridge(format!("payload isn't valid JSON: {}", err)))?;
+
+    let source = json["program"].as_str()
+        .ok_or_else(|| LoadError::Cartridge("payload has no program".to_owned()))?;
+    let program = octo::assemble(source).map_err(LoadError::Cartridge)?;
+
+    Ok(Cartridge { program, info: options_info(&json["options"]) })
+}
+
+fn payload(gif: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(gif)
+        .map_err(|err| LoadError::Cartridge(format!("not a GIF: {}", err)))?;
+
+    let mut bytes = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()
+        .map_err(|err| LoadError::Cartridge(format!("corrupt GIF: {}", err)))? {
+        for pixels in frame.buffer.chunks_exact(4) {
+            bytes.push(pixels.iter().fold(0, |byte, index| byte << 2 | (index & 3)));
+        }
+    }
+
+    if bytes.len() < 4 {
+        return Err(LoadError::Cartridge("no payload".to_owned()));
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    if size > bytes.len() - 4 {
+        return Err(LoadError::Cartridge("payload is truncated".to_owned()));
+    }
+    Ok(bytes[4..4 + size].to_vec())
+}
+
+/// Turns Octo's saved options into the settings the database would supply.
+fn options_info(options: &Value) -> RomInfo {
+    let shift = options["shiftQuirks"].as_bool();
+    let logic = options["logicQuirks"].as_bool();
+    let config = if shift.is_some() || logic.is_some() {
+        Some(Config {
+            shift_uses_vy: !shift.unwrap_or(false),
+            vf_reset: logic.unwrap_or(false),
+            ..Config::default()
+        })
+    } else {
+        None
+    };
+
+    let colour = |key: &str| options[key].as_str().and_then(|hex| parse_colour(hex).ok());
+    let palette = match (colour("fillColor"), colour("backgroundColor")) {
+        (Some(foreground), Some(background)) => Some(Palette { foreground, background }),
+        _ => None
+    };
+
+    RomInfo {
+        title: "Octo cartridge".to_owned(),
+        config,
+        tickrate: options["tickrate"].as_u64().map(|rate| rate as u32),
+        palette,
+        ..RomInfo::default()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Encodes `json` into a 128x64 cartridge GIF the way Octo lays it out.
+    pub fn cartridge_gif(json: &str) -> Vec<u8> {
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json.as_bytes());
+
+        let mut pixels: Vec<u8> = payload.iter()
+            .flat_map(|byte| (0..4).rev().map(move |n| (byte >> (n*2)) & 3))
+            .collect();
+        let frame_size = 128 * 64;
+        pixels.resize(pixels.len().div_ceil(frame_size) * frame_size, 0);
+
+        let palette = [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 128, 64, &palette).unwrap();
+            for frame in pixels.chunks(frame_size) {
+                encoder.write_frame(&gif::Frame::from_indexed_pixels(128, 64, frame, None)).unwrap();
+            }
+        }
+        gif
+    }
+
+    #[test]
+    fn reads_programs_and_options() {
+        let json = r##"{"key":"", "program": "0x00 0xE0 # clear\n0x12 0x00", "options": {
+            "tickrate": 20, "fillColor": "#FF6600", "backgroundColor": "#000000",
+            "shiftQuirks": true, "logicQuirks": false
+        }}"##;
+        let cartridge = read(&cartridge_gif(json)).ok().unwrap();
+        assert_eq!(cartridge.program, vec![0x00, 0xE0, 0x12, 0x00]);
+        assert_eq!(cartridge.info.tickrate, Some(20));
+        assert_eq!(cartridge.info.palette.unwrap().foreground, [1.0, 0.4, 0.0, 1.0]);
+        let config = cartridge.info.config.unwrap();
+        assert!(!config.shift_uses_vy);
+        assert!(!config.vf_reset);
+    }
+
+    #[test]
+    fn spans_payloads_over_several_frames() {
+        let program: Vec<String> = (0..3000).map(|n| format!("{}", n % 256)).collect();
+        let json = format!("{{\"program\": \"{}\", \"options\": {{}}}}", program.join(" "));
+        let cartridge = read(&cartridge_gif(&json)).ok().unwrap();
+        assert_eq!(cartridge.program.len(), 3000);
+        assert_eq!(cartridge.info.config, None);
+    }
+
+    #[test]
+    fn assembles_octo_source() {
+        let source = r#"# Bounces a ball around the screen.
+:alias x v0
+:alias y v1
+:alias dx v2
+:alias dy v3
+:const SPEED 1
+
+: ball
+  0b01100000 0b11110000
+  0b11110000 0b01100000
+
+: draw-ball
+  i := ball
+  sprite x y 4
+;
+
+: main
+  x := 10  y := 5  dx := SPEED  dy := SPEED
+  draw-ball
+  loop
+    draw-ball
+    x += dx  y += dy
+    if x == 60 then dx := -1
+    if x == 0 then dx := 1
+    if y == 28 begin
+      dy := -1
+    else
+      if y == 0 then dy := 1
+    end
+    draw-ball
+    vf := 1  delay := vf
+    loop
+      vf := delay
+      if vf != 0 then
+    again
+  again
+"#;
+        let json = serde_json::json!({ "key": "", "program": source, "options": { "tickrate": 20 } });
+        let cartridge = read(&cartridge_gif(&json.to_string())).ok().unwrap();
+        // main comes after the sprite and subroutine, so 0x200 jumps to it
+        assert_eq!(cartridge.program[..2], [0x12, 0x0C]);
+
+        let mut machine = crate::Machine::new(Config::default());
+        machine.load_program(&cartridge.program, 0x200);
+        for _ in 0..200 {
+            machine.step().unwrap();
+            machine.tick_timers();
+        }
+        // the ball has moved down and to the right
+        assert!(machine.v[0] > 10 && machine.v[1] > 5);
+        assert_eq!((machine.v[2], machine.v[3]), (1, 1));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(read(b"not a gif"), Err(LoadError::Cartridge(_))));
+    }
+}
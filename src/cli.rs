@@ -0,0 +1,454 @@
+use std::path::PathBuf;
+
+use crate::database::RomInfo;
+use crate::palette::{parse_colour, Palette};
+use crate::{Config, MEMORY_SIZE};
+
+pub const USAGE: &str = "\
+Usage: chip8 [COMMAND] <ROM> [OPTIONS]
+
+ROM can be a raw binary, an Octo cartridge (.gif), an Intel HEX (.hex) or
+S-record (.srec, .s19) file, or a zip archive.
+
+Commands:
+    run       Run a ROM (the default when no command is given)
+    debug     Run a ROM, printing its memory first
+    terminal  Run a ROM in the terminal, without a window
+    disasm    Print a disassembly of a ROM
+    info      Print the size, hash, database entry and any warnings for a ROM
+    compare   Run a ROM without a window against a reference trace: chip8 compare <ROM> <TRACE>
+    recompile Translate a ROM into a Rust program that runs it natively: chip8 recompile <ROM> -o <FILE>
+
+Options:
+    --speed <HZ>          Instructions per second [default: 500]
+    --quirks <PRESET>     Quirk preset: default, vip or schip [default: default]
+    --palette <FG,BG>     Foreground and background colours as hex, e.g. ffffff,000000
+    --scale <N>           Size of a CHIP-8 pixel on screen [default: 16]
+    --seed <N>            Seed for the random number generator
+    --keymap <KEYS>       16 keyboard keys for the keypad, row by row [default: 1234qwerasdfzxcv]
+    --load-address <ADDR> Where raw binaries are loaded and start, e.g. 0x600 for the ETI-660 [default: 0x200]
+    --entry <NAME>        ROM to run from a zip archive, instead of picking one when asked
+    --database <DIR>      Per-game settings from programs.json in a chip-8-database checkout
+    --no-database         Don't look the ROM up in the database
+    --watch               Restart the ROM whenever the file changes, keeping the current settings
+                          unless the new build is a ROM the database knows
+    --watch-reset-settings
+                          Like --watch, but pick the settings afresh on every reload
+    --trace <FILE>        Write every instruction run, with the registers before it, to FILE
+    --trace-range <A-B>   Only trace instructions at addresses A to B, e.g. 0x200-0x2FF
+    --profile <FILE>      On exit, write a profile to FILE and folded call stacks to FILE.folded
+    --braille             In the terminal, draw with braille dots rather than half blocks
+    -h, --help            Print this help
+    -V, --version         Print the version
+
+Speed, quirks and palette default to the ROM database entry, if there is one. The
+built-in database only knows a couple of test programs; use --database for the rest.
+
+Keys while running:
+    Space                 Pause; while paused, arrows move the memory cursor and hex digits poke bytes
+    F5                    Soft reset: restart the program, keeping memory
+    F6                    Hard reset: reload the ROM from disk
+    F2                    Browse for another ROM; dropping a file on the window opens it too
+    F3                    Memory view follows PC, I or nothing
+    F4                    Jump the memory view to a typed address (Enter to go)
+    PageUp, PageDown      Scroll the memory view
+    Esc                   Quit";
+
+/// Keyboard keys for the keypad, in layout order: 1 2 3 C / 4 5 6 D / 7 8 9 E / A 0 B F.
+pub const DEFAULT_KEYMAP: &str = "1234qwerasdfzxcv";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Debug(Options),
+    /// Run in the terminal instead of a window.
+    Terminal(Options),
+    Disasm(PathBuf),
+    /// Print what's known about a ROM; only the database options apply.
+    Info(Options),
+    /// Check a headless run against a reference trace.
+    Compare(Options, PathBuf),
+    /// Translate a ROM into Rust source, written to the path.
+    Recompile(Options, PathBuf),
+    Help,
+    Version
+}
+
+/// Options given on the command line. Those left as `None` fall back to the
+/// ROM database, then to the defaults, in `resolve`.
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub rom: PathBuf,
+    pub speed: Option<u32>,
+    pub quirks: Option<Config>,
+    pub load_address: Option<u16>,
+    pub palette: Option<Palette>,
+    pub scale: f64,
+    pub seed: Option<u64>,
+    pub keymap: String,
+    pub entry: Option<String>,
+    pub database: Option<PathBuf>,
+    pub use_database: bool,
+    pub watch: bool,
+    /// Resolve the settings again each time `watch` reloads the ROM.
+    pub watch_reset_settings: bool,
+    pub trace: Option<PathBuf>,
+    /// Inclusive range of addresses to trace.
+    pub trace_range: Option<(u16, u16)>,
+    pub profile: Option<PathBuf>,
+    /// Draw the terminal frontend's display with braille dots.
+    pub braille: bool
+}
+
+/// The settings a ROM runs with once the database and command line are combined.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub config: Config,
+    pub speed: u32,
+    pub palette: Palette,
+    pub game_keys: Vec<(String, u8)>
+}
+
+impl Options {
+    fn new(rom: PathBuf) -> Options {
+        Options {
+            rom,
+            speed: None,
+            quirks: None,
+            load_address: None,
+            palette: None,
+            scale: 16.0,
+            seed: None,
+            keymap: DEFAULT_KEYMAP.to_owned(),
+            entry: None,
+            database: None,
+            use_database: true,
+            watch: false,
+            watch_reset_settings: false,
+            trace: None,
+            trace_range: None,
+            profile: None,
+            braille: false
+        }
+    }
+
+    pub fn resolve(&self, info: Option<&RomInfo>) -> Settings {
+        let mut config = self.quirks.or_else(|| info.and_then(|info| info.config)).unwrap_or_default();
+        // a preset only picks the quirks; where the program loads stays the entry's
+        if let Some(load_address) = self.load_address.or_else(|| info.and_then(|info| info.load_address)) {
+            config.load_address = load_address;
+        }
+        Settings {
+            config,
+            speed: self.speed.or_else(|| info.and_then(|info| info.tickrate).map(|rate| rate * 60)).unwrap_or(500),
+            palette: self.palette.or_else(|| info.and_then(|info| info.palette)).unwrap_or_default(),
+            game_keys: info.map(|info| info.keys.clone()).unwrap_or_default()
+        }
+    }
+}
+
+/// The options `info` takes.
+const INFO_OPTIONS: [&str; 3] = ["--database", "--no-database", "--entry"];
+
+/// Options the terminal frontend doesn't support, since it can't reload a ROM.
+const NOT_IN_TERMINAL: [&str; 2] = ["--watch", "--watch-reset-settings"];
+
+/// Options that don't take a value.
+const SWITCHES: [&str; 4] = ["--no-database", "--watch", "--watch-reset-settings", "--braille"];
+
+/// Parses the arguments after the program name.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        return Ok(Command::Help);
+    }
+    if args.iter().any(|arg| arg == "-V" || arg == "--version") {
+        return Ok(Command::Version);
+    }
+
+    let (command, rest) = match args.first().map(String::as_str) {
+        None => return Err("no ROM given".to_owned()),
+        Some(name @ "run") | Some(name @ "debug") | Some(name @ "terminal") | Some(name @ "disasm") |
+        Some(name @ "info") | Some(name @ "compare") | Some(name @ "recompile") => (name, &args[1..]),
+        Some(_) => ("run", args)
+    };
+
+    let mut files = Vec::new();
+    let mut flags = Vec::new();
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        if SWITCHES.contains(&arg.as_str()) {
+            flags.push((arg.as_str(), ""));
+        } else if arg.starts_with("--") || arg == "-o" {
+            let value = rest.next().ok_or(format!("{} needs a value", arg))?;
+            flags.push((arg.as_str(), value.as_str()));
+        } else {
+            files.push(PathBuf::from(arg));
+        }
+    }
+    let expected = if command == "compare" { 2 } else { 1 };
+    if files.len() > expected {
+        return Err(format!("unexpected argument '{}'", files[expected].display()));
+    }
+    // -o, recompile's output file, is the only short option with a value
+    let output = match flags.iter().position(|&(flag, _)| flag == "-o" || flag == "--output") {
+        Some(n) if command == "recompile" => Some(PathBuf::from(flags.remove(n).1)),
+        _ => None
+    };
+    let mut files = files.into_iter();
+    let rom = files.next().ok_or_else(|| "no ROM given".to_owned())?;
+
+    match command {
+        "disasm" => {
+            if let Some((flag, _)) = flags.first() {
+                return Err(format!("{} doesn't take {}", command, flag));
+            }
+            Ok(Command::Disasm(rom))
+        },
+        _ => {
+            let mut options = Options::new(rom);
+            for (flag, value) in flags {
+                if (command == "info" && !INFO_OPTIONS.contains(&flag)) ||
+                    (command == "terminal" && NOT_IN_TERMINAL.contains(&flag)) {
+                    return Err(format!("{} doesn't take {}", command, flag));
+                }
+                apply_option(&mut options, flag, value)?;
+            }
+            Ok(match command {
+                "debug" => Command::Debug(options),
+                "info" => Command::Info(options),
+                "terminal" => Command::Terminal(options),
+                "compare" => Command::Compare(options, files.next().ok_or_else(|| "no reference trace given".to_owned())?),
+                "recompile" => Command::Recompile(options, output.ok_or_else(|| "no output file given, use -o <FILE>".to_owned())?),
+                _ => Command::Run(options)
+            })
+        }
+    }
+}
+
+fn apply_option(options: &mut Options, flag: &str, value: &str) -> Result<(), String> {
+    match flag {
+        "--speed" => {
+            options.speed = Some(value.parse().ok().filter(|&hz| hz > 0)
+                .ok_or(format!("invalid speed '{}', expected instructions per second", value))?);
+        },
+        "--quirks" => {
+            options.quirks = Some(Config::preset(value)
+                .ok_or(format!("unknown quirk preset '{}', expected default, vip or schip", value))?);
+        },
+        "--load-address" => {
+            options.load_address = Some(parse_address(value)
+                .ok_or(format!("invalid load address '{}', expected an address below 0x{:X}", value, MEMORY_SIZE))?);
+        },
+        "--palette" => {
+            options.palette = Some(parse_palette(value)?);
+        },
+        "--scale" => {
+            options.scale = value.parse().ok().filter(|&scale: &f64| scale >= 1.0 && scale.is_finite())
+                .ok_or(format!("invalid scale '{}', expected a number of at least 1", value))?;
+        },
+        "--seed" => {
+            options.seed = Some(value.parse().map_err(|_| format!("invalid seed '{}'", value))?);
+        },
+        "--keymap" => {
+            options.keymap = parse_keymap(value)?;
+        },
+        "--entry" => {
+            options.entry = Some(value.to_owned());
+        },
+        "--database" => {
+            options.database = Some(PathBuf::from(value));
+        },
+        "--no-database" => {
+            options.use_database = false;
+        },
+        "--watch" => {
+            options.watch = true;
+        },
+        "--watch-reset-settings" => {
+            options.watch = true;
+            options.watch_reset_settings = true;
+        },
+        "--braille" => {
+            options.braille = true;
+        },
+        "--trace" => {
+            options.trace = Some(PathBuf::from(value));
+        },
+        "--profile" => {
+            options.profile = Some(PathBuf::from(value));
+        },
+        "--trace-range" => {
+            let range = value.split_once('-')
+                .and_then(|(start, end)| Some((parse_address(start)?, parse_address(end)?)))
+                .filter(|(start, end)| start <= end);
+            options.trace_range = Some(range
+                .ok_or(format!("invalid trace range '{}', expected two addresses like 0x200-0x2FF", value))?);
+        },
+        _ => return Err(format!("unknown option '{}'", flag))
+    }
+    Ok(())
+}
+
+/// An address in memory, in hex with `0x` or in decimal.
+fn parse_address(value: &str) -> Option<u16> {
+    let address = match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok()
+    };
+    address.filter(|&address| (address as usize) < MEMORY_SIZE)
+}
+
+fn parse_palette(value: &str) -> Result<Palette, String> {
+    let colours: Vec<&str> = value.split(',').collect();
+    if colours.len() != 2 {
+        return Err(format!("invalid palette '{}', expected two colours like ffffff,000000", value));
+    }
+    Ok(Palette {
+        foreground: parse_colour(colours[0])?,
+        background: parse_colour(colours[1])?
+    })
+}
+
+fn parse_keymap(value: &str) -> Result<String, String> {
+    let keys = value.to_lowercase();
+    if keys.chars().count() != 16 || !keys.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("invalid keymap '{}', expected 16 letters or digits", value));
+    }
+    if keys.chars().enumerate().any(|(n, c)| keys.chars().skip(n + 1).any(|other| other == c)) {
+        return Err(format!("invalid keymap '{}', keys must be unique", value));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn a_bare_rom_runs_it() {
+        assert_eq!(parse(&args("game.ch8")), Ok(Command::Run(Options::new(PathBuf::from("game.ch8")))));
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        assert_eq!(parse(&args("disasm game.ch8")), Ok(Command::Disasm(PathBuf::from("game.ch8"))));
+        assert_eq!(parse(&args("info game.ch8 --database db")),
+            Ok(Command::Info(Options { database: Some(PathBuf::from("db")), ..Options::new(PathBuf::from("game.ch8")) })));
+        assert!(parse(&args("info game.ch8 --speed 700")).is_err());
+        assert!(matches!(parse(&args("debug game.ch8")), Ok(Command::Debug(_))));
+        assert_eq!(parse(&args("terminal game.ch8 --braille")),
+            Ok(Command::Terminal(Options { braille: true, ..Options::new(PathBuf::from("game.ch8")) })));
+        assert_eq!(parse(&args("terminal game.ch8 --watch")), Err("terminal doesn't take --watch".to_owned()));
+        assert_eq!(parse(&args("compare game.ch8 ref.log --seed 1")),
+            Ok(Command::Compare(Options { seed: Some(1), ..Options::new(PathBuf::from("game.ch8")) }, PathBuf::from("ref.log"))));
+        assert_eq!(parse(&args("recompile game.ch8 -o game.rs --speed 700")),
+            Ok(Command::Recompile(Options { speed: Some(700), ..Options::new(PathBuf::from("game.ch8")) }, PathBuf::from("game.rs"))));
+        assert!(parse(&args("recompile game.ch8")).is_err());
+        assert!(parse(&args("run game.ch8 -o game.rs")).is_err());
+        assert_eq!(parse(&args("run game.ch8 --help")), Ok(Command::Help));
+        assert_eq!(parse(&args("--version")), Ok(Command::Version));
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = match parse(&args("run game.ch8 --speed 1000 --quirks vip --palette #ff0000,000080 --scale 8 --load-address 0x600 --seed 42 --keymap 1234QWERASDFZXCV")) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("unexpected parse: {:?}", other)
+        };
+        assert_eq!(options.speed, Some(1000));
+        assert_eq!(options.quirks, Config::preset("vip"));
+        let palette = options.palette.unwrap();
+        assert_eq!(palette.foreground, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(palette.background, [0.0, 0.0, 128.0 / 255.0, 1.0]);
+        assert_eq!(options.scale, 8.0);
+        assert_eq!(options.load_address, Some(0x600));
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(options.keymap, "1234qwerasdfzxcv");
+    }
+
+    #[test]
+    fn parses_loading_options() {
+        match parse(&args("pack.zip --entry game.ch8 --no-database --watch --database db --trace out.log --trace-range 0x200-767 --profile prof.txt")) {
+            Ok(Command::Run(options)) => {
+                assert!(options.watch && !options.watch_reset_settings);
+                assert_eq!(options.trace, Some(PathBuf::from("out.log")));
+                assert_eq!(options.trace_range, Some((0x200, 0x2FF)));
+                assert_eq!(options.profile, Some(PathBuf::from("prof.txt")));
+                assert_eq!(options.entry.as_deref(), Some("game.ch8"));
+                assert!(!options.use_database);
+                assert_eq!(options.database, Some(PathBuf::from("db")));
+            },
+            other => panic!("unexpected parse: {:?}", other)
+        }
+        match parse(&args("game.ch8 --watch-reset-settings")) {
+            Ok(Command::Run(options)) => assert!(options.watch && options.watch_reset_settings),
+            other => panic!("unexpected parse: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn resolves_settings_from_database_then_command_line() {
+        let info = RomInfo {
+            config: Config::preset("vip"),
+            tickrate: Some(15),
+            palette: Some(Palette { foreground: [1.0, 0.0, 0.0, 1.0], ..Palette::default() }),
+            keys: vec![("up".to_owned(), 5)],
+            ..RomInfo::default()
+        };
+        let mut options = Options::new(PathBuf::from("game.ch8"));
+
+        let settings = options.resolve(None);
+        assert_eq!((settings.config, settings.speed, settings.palette), (Config::default(), 500, Palette::default()));
+
+        let settings = options.resolve(Some(&info));
+        assert_eq!(settings.config, Config::preset("vip").unwrap());
+        assert_eq!(settings.speed, 900);
+        assert_eq!(settings.palette, info.palette.unwrap());
+        assert_eq!(settings.game_keys, info.keys);
+
+        options.load_address = Some(0x600);
+        assert_eq!(options.resolve(Some(&info)).config.load_address, 0x600);
+        options.load_address = None;
+
+        options.speed = Some(1000);
+        options.quirks = Config::preset("schip");
+        options.palette = Some(Palette::default());
+        let settings = options.resolve(Some(&info));
+        assert_eq!((settings.config, settings.speed, settings.palette), (Config::preset("schip").unwrap(), 1000, Palette::default()));
+    }
+
+    #[test]
+    fn quirk_presets_keep_the_entrys_start_address() {
+        let info = RomInfo { config: Config::preset("vip"), load_address: Some(0x600), ..RomInfo::default() };
+        let options = Options { quirks: Config::preset("schip"), ..Options::new(PathBuf::from("game.ch8")) };
+        assert_eq!(options.resolve(Some(&info)).config, Config { load_address: 0x600, ..Config::preset("schip").unwrap() });
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(parse(&args("")).is_err());
+        assert!(parse(&args("run")).is_err());
+        assert!(parse(&args("game.ch8 other.ch8")).is_err());
+        assert!(parse(&args("game.ch8 --speed")).is_err());
+        assert!(parse(&args("game.ch8 --speed fast")).is_err());
+        assert!(parse(&args("game.ch8 --speed 0")).is_err());
+        assert!(parse(&args("game.ch8 --quirks nes")).is_err());
+        assert!(parse(&args("game.ch8 --palette ffffff")).is_err());
+        assert!(parse(&args("game.ch8 --load-address 0x1000")).is_err());
+        assert!(parse(&args("game.ch8 --load-address start")).is_err());
+        assert!(parse(&args("game.ch8 --palette fff,000")).is_err());
+        assert!(parse(&args("game.ch8 --scale 0")).is_err());
+        assert!(parse(&args("game.ch8 --keymap 1234")).is_err());
+        assert!(parse(&args("game.ch8 --keymap 1111qwerasdfzxcv")).is_err());
+        assert!(parse(&args("game.ch8 --trace-range 0x300-0x200")).is_err());
+        assert!(parse(&args("game.ch8 --trace-range 0x300")).is_err());
+        assert!(parse(&args("game.ch8 --frobnicate 1")).is_err());
+        assert!(parse(&args("disasm game.ch8 --speed 10")).is_err());
+        assert!(parse(&args("compare game.ch8")).is_err());
+        assert!(parse(&args("compare game.ch8 ref.log extra")).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! `chip8 compare`: runs a ROM without a window, checking the state before
+//! each instruction against a trace from a reference emulator.
+//!
+//! The reference is either in the `--trace` text format (see `trace`), or
+//! JSON lines with one object per instruction:
+//!
+//! ```text
+//! {"pc": 512, "opcode": "0x6A02", "v": [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], "i": 0, "sp": 0}
+//! ```
+//!
+//! Numbers can be JSON numbers or hex strings starting `0x`; `cycle` is
+//! optional. Blank lines and lines starting with `#` are skipped.
+
+use serde_json::Value;
+
+use crate::decode::DecodeCache;
+use crate::disasm::disassemble;
+use crate::trace::Record;
+use crate::{Fault, Machine};
+
+/// Where our run first disagreed with the reference.
+pub enum Mismatch {
+    Diverged { line: usize, ours: Record, theirs: Record, previous: Option<Record> },
+    Faulted { line: usize, fault: Fault, previous: Option<Record> }
+}
+
+/// Reads a reference trace, keeping the line number of each record.
+pub fn read_reference(text: &str) -> Result<Vec<(usize, Record)>, String> {
+    let mut records = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = if line.starts_with('{') {
+            json_record(line, records.len() as u64 + 1)
+        } else {
+            Record::parse(line)
+        };
+        records.push((n + 1, record.map_err(|err| format!("line {}: {}", n + 1, err))?));
+    }
+    Ok(records)
+}
+
+fn json_record(line: &str, cycle: u64) -> Result<Record, String> {
+    let json: Value = serde_json::from_str(line).map_err(|err| format!("invalid JSON: {}", err))?;
+    let field = |key: &str, max: u64| {
+        number(&json[key]).filter(|&n| n <= max).ok_or(format!("missing or invalid \"{}\"", key))
+    };
+
+    let registers = json["v"].as_array().filter(|v| v.len() == 16)
+        .ok_or("\"v\" should list 16 registers")?;
+    let mut v = [0; 16];
+    for (x, value) in v.iter_mut().enumerate() {
+        *value = number(&registers[x]).filter(|&n| n <= 0xFF).ok_or(format!("invalid V{:X}", x))? as u8;
+    }
+
+    Ok(Record {
+        cycle: if json["cycle"].is_null() { cycle } else { field("cycle", u64::MAX)? },
+        pc: field("pc", 0xFFFF)? as u16,
+        opcode: field("opcode", 0xFFFF)? as u16,
+        v,
+        i: field("i", 0xFFFF)? as u16,
+        sp: field("sp", 0xFF)? as u8
+    })
+}
+
+fn number(value: &Value) -> Option<u64> {
+    match value.as_str() {
+        Some(text) => u64::from_str_radix(text.strip_prefix("0x")?, 16).ok(),
+        None => value.as_u64()
+    }
+}
+
+/// Runs `machine` for as many instructions as the reference has, ticking
+/// the timers `speed / 60` instructions apart. Returns the number checked.
+pub fn compare(mut machine: Machine, reference: &[(usize, Record)], speed: u32) -> Result<usize, Mismatch> {
+    let mut cache = DecodeCache::new();
+    let mut previous = None;
+    for (n, &(line, theirs)) in reference.iter().enumerate() {
+        let cycle = n as u64 + 1;
+        let ours = Record::of(cycle, &machine);
+        if !same_state(&ours, &theirs) {
+            return Err(Mismatch::Diverged { line, ours, theirs, previous });
+        }
+
+        machine.step_cached(&mut cache, &mut ()).map_err(|fault| Mismatch::Faulted { line, fault, previous: Some(ours) })?;
+        if cycle * 60 / speed as u64 != (cycle - 1) * 60 / speed as u64 {
+            machine.tick_timers();
+        }
+        previous = Some(ours);
+    }
+    Ok(reference.len())
+}
+
+/// Cycle counts aren't compared, since emulators count from different places.
+fn same_state(ours: &Record, theirs: &Record) -> bool {
+    ours.pc == theirs.pc && ours.opcode == theirs.opcode && ours.v == theirs.v && ours.i == theirs.i && ours.sp == theirs.sp
+}
+
+/// Describes a mismatch, with both states side by side when they diverged.
+pub fn report(mismatch: &Mismatch) -> String {
+    let (line, previous) = match mismatch {
+        Mismatch::Diverged { line, previous, .. } | Mismatch::Faulted { line, previous, .. } => (line, previous)
+    };
+    let mut lines = Vec::new();
+    if let Some(previous) = previous {
+        lines.push(format!("after {:04X}: {:04X}  {}", previous.pc, previous.opcode, disassemble(previous.opcode)));
+    }
+
+    match mismatch {
+        Mismatch::Faulted { fault, .. } => {
+            lines.insert(0, format!("Faulted at line {} of the reference: {}", line, fault));
+        },
+        Mismatch::Diverged { ours, theirs, .. } => {
+            lines.insert(0, format!("Diverged at line {} of the reference", line));
+            lines.push(format!("{:<8}{:<14}{}", "", "ours", "reference"));
+            let mut row = |name: String, ours: String, theirs: String, compared: bool| {
+                let marker = if compared && ours != theirs { "<<" } else { "" };
+                lines.push(format!("{:<8}{:<14}{:<14}{}", name, ours, theirs, marker).trim_end().to_owned());
+            };
+            row("cycle".to_owned(), ours.cycle.to_string(), theirs.cycle.to_string(), false);
+            row("PC".to_owned(), format!("{:04X}", ours.pc), format!("{:04X}", theirs.pc), true);
+            row("opcode".to_owned(), format!("{:04X}", ours.opcode), format!("{:04X}", theirs.opcode), true);
+            for x in 0..16 {
+                row(format!("V{:X}", x), format!("{:02X}", ours.v[x]), format!("{:02X}", theirs.v[x]), true);
+            }
+            row("I".to_owned(), format!("{:04X}", ours.i), format!("{:04X}", theirs.i), true);
+            row("SP".to_owned(), ours.sp.to_string(), theirs.sp.to_string(), true);
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    // LD V0, 1; ADD V0, 1; JP 0x204
+    const PROGRAM: [u8; 6] = [0x60, 0x01, 0x70, 0x01, 0x12, 0x04];
+
+    fn machine() -> Machine {
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(&PROGRAM, 0x200);
+        machine
+    }
+
+    fn json_line(pc: u16, opcode: u16, v0: u8) -> String {
+        let mut v = vec!["0".to_owned(); 16];
+        v[0] = v0.to_string();
+        format!(r#"{{"pc": {}, "opcode": "0x{:04X}", "v": [{}], "i": 0, "sp": 0}}"#, pc, opcode, v.join(", "))
+    }
+
+    #[test]
+    fn matches_its_own_trace() {
+        let mut running = machine();
+        let mut trace = String::from("# from chip8 --trace\n");
+        for cycle in 1..=5 {
+            trace.push_str(&format!("{}\n", Record::of(cycle, &running)));
+            running.step().unwrap();
+        }
+        let reference = read_reference(&trace).unwrap();
+        assert!(matches!(compare(machine(), &reference, 500), Ok(5)));
+    }
+
+    #[test]
+    fn stops_at_the_first_divergence() {
+        let trace = [json_line(0x200, 0x6001, 0), json_line(0x202, 0x7001, 1), json_line(0x204, 0x1204, 3)].join("\n");
+        let reference = read_reference(&trace).unwrap();
+        let mismatch = match compare(machine(), &reference, 500) {
+            Err(mismatch) => mismatch,
+            Ok(_) => panic!("expected a divergence")
+        };
+        match &mismatch {
+            Mismatch::Diverged { line, ours, theirs, previous } => {
+                assert_eq!(*line, 3);
+                assert_eq!((ours.v[0], theirs.v[0]), (2, 3));
+                assert_eq!(previous.unwrap().pc, 0x202);
+            },
+            Mismatch::Faulted { .. } => panic!("expected a divergence")
+        }
+
+        let report = report(&mismatch);
+        assert!(report.starts_with("Diverged at line 3"));
+        assert!(report.contains("after 0202: 7001  ADD V0, 0x01"));
+        assert!(report.contains("V0      02            03            <<"));
+        assert!(report.lines().any(|line| line == "V1      00            00"));
+    }
+
+    #[test]
+    fn reports_faults() {
+        let mut faulty = machine();
+        faulty.memory[0x200..0x202].copy_from_slice(&[0x00, 0xEE]);
+        let reference = read_reference(&json_line(0x200, 0x00EE, 0)).unwrap();
+        let mismatch = compare(faulty, &reference, 500).err().unwrap();
+        assert!(report(&mismatch).starts_with("Faulted at line 1 of the reference: "));
+    }
+
+    #[test]
+    fn rejects_bad_reference_lines() {
+        assert!(read_reference("{\"pc\": 512}").unwrap_err().starts_with("line 1:"));
+        assert!(read_reference(&json_line(0x200, 0x6001, 0).replace("\"i\": 0", "\"i\": \"512\"")).is_err());
+        assert!(read_reference("\n\nnonsense").unwrap_err().starts_with("line 3:"));
+    }
+}
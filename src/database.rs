@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::palette::{parse_colour, Palette};
+use crate::{Config, MEMORY_SIZE};
+
+/// A sample `programs.json` in the community chip-8-database format, built
+/// into the binary. It only knows two test programs; per-game settings for
+/// anything else need `--database` and a checkout of the community list.
+const EMBEDDED_PROGRAMS: &str = include_str!("../assets/database/programs.json");
+
+/// What the database knows about one ROM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    /// The first platform listed for the ROM, e.g. `originalChip8`.
+    pub platform: Option<String>,
+    pub config: Option<Config>,
+    /// Where the ROM is loaded and starts, if not 0x200.
+    pub load_address: Option<u16>,
+    /// Instructions per 60 Hz frame.
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    /// Named game controls (`up`, `left`, `a`, ...) and the keypad key for each.
+    pub keys: Vec<(String, u8)>
+}
+
+/// ROM metadata keyed by lowercase SHA-1.
+pub struct Database {
+    roms: HashMap<String, RomInfo>
+}
+
+impl Database {
+    pub fn embedded() -> Database {
+        Database::from_json(EMBEDDED_PROGRAMS).expect("embedded database is valid")
+    }
+
+    /// Reads `programs.json` from a checkout of the community database.
+    pub fn from_dir(dir: &Path) -> Result<Database, String> {
+        let path = dir.join("programs.json");
+        let json = fs::read_to_string(&path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+        Database::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> Result<Database, String> {
+        let programs: Value = serde_json::from_str(json).map_err(|err| format!("invalid database: {}", err))?;
+        let programs = programs.as_array().ok_or("invalid database: expected a list of programs")?;
+
+        let mut roms = HashMap::new();
+        for program in programs {
+            let title = program["title"].as_str().unwrap_or("Unknown").to_owned();
+            let authors: Vec<String> = program["authors"].as_array().map(|authors| {
+                authors.iter().filter_map(Value::as_str).map(String::from).collect()
+            }).unwrap_or_default();
+
+            if let Some(entries) = program["roms"].as_object() {
+                for (sha1, rom) in entries {
+                    let info = rom_info(title.clone(), authors.clone(), rom);
+                    roms.insert(sha1.to_lowercase(), info);
+                }
+            }
+        }
+        Ok(Database { roms })
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<&RomInfo> {
+        self.roms.get(&sha1.to_lowercase())
+    }
+}
+
+fn rom_info(title: String, authors: Vec<String>, rom: &Value) -> RomInfo {
+    let platform = rom["platforms"].as_array()
+        .and_then(|platforms| platforms.first())
+        .and_then(Value::as_str)
+        .map(String::from);
+
+    let config = platform.as_ref().map(|platform| {
+        let mut config = platform_config(platform);
+        // quirkyPlatforms overrides individual quirks of the chosen platform
+        let quirks = &rom["quirkyPlatforms"][platform.as_str()];
+        if let Some(logic) = quirks["logic"].as_bool() {
+            config.vf_reset = logic;
+        }
+        if let Some(shift) = quirks["shift"].as_bool() {
+            config.shift_uses_vy = !shift;
+        }
+        config
+    });
+
+    // an address with no room for an instruction after it is a bad entry
+    let load_address = rom["startAddress"].as_u64()
+        .filter(|&start| start < (MEMORY_SIZE - 2) as u64)
+        .map(|start| start as u16);
+
+    let pixels = rom["colors"]["pixels"].as_array();
+    let palette = pixels.filter(|pixels| pixels.len() >= 2).and_then(|pixels| {
+        let colour = |n: usize| pixels[n].as_str().and_then(|hex| parse_colour(hex).ok());
+        Some(Palette { background: colour(0)?, foreground: colour(1)? })
+    });
+
+    let keys = rom["keys"].as_object().map(|keys| {
+        keys.iter()
+            .filter_map(|(name, key)| key.as_u64().filter(|&k| k < 16).map(|k| (name.clone(), k as u8)))
+            .collect()
+    }).unwrap_or_default();
+
+    RomInfo {
+        title,
+        authors,
+        platform,
+        config,
+        load_address,
+        tickrate: rom["tickrate"].as_u64().map(|rate| rate as u32),
+        palette,
+        keys
+    }
+}
+
+/// The closest quirk preset for a chip-8-database platform id.
+fn platform_config(platform: &str) -> Config {
+    let preset = match platform {
+        "originalChip8" | "hybridVIP" => "vip",
+        "chip48" | "superchip1" | "superchip" => "schip",
+        _ => "default"
+    };
+    Config::preset(preset).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAMS: &str = r##"[
+        {
+            "title": "Breakout",
+            "authors": ["Someone", "Someone Else"],
+            "roms": {
+                "ABCDEF0123456789ABCDEF0123456789ABCDEF01": {
+                    "file": "breakout.ch8",
+                    "platforms": ["originalChip8", "modernChip8"],
+                    "quirkyPlatforms": { "originalChip8": { "logic": false } },
+                    "tickrate": 15,
+                    "colors": { "pixels": ["#102030", "#ffffff"], "buzzer": "#990000" },
+                    "keys": { "left": 4, "right": 6, "bogus": 99 }
+                }
+            }
+        },
+        {
+            "title": "Elsewhere",
+            "roms": {
+                "2000000000000000000000000000000000000000": { "file": "eti.ch8", "startAddress": 1536 },
+                "3000000000000000000000000000000000000000": { "file": "high.ch8", "platforms": ["chip48"], "startAddress": 4094 },
+                "4000000000000000000000000000000000000000": { "file": "wrapped.ch8", "startAddress": 66048 }
+            }
+        },
+        {
+            "title": "Bare",
+            "roms": { "1111111111111111111111111111111111111111": { "file": "bare.ch8" } }
+        }
+    ]"##;
+
+    #[test]
+    fn looks_up_roms_by_sha1() {
+        let database = Database::from_json(PROGRAMS).unwrap();
+        let info = database.lookup("abcdef0123456789abcdef0123456789abcdef01").unwrap();
+        assert_eq!(info.title, "Breakout");
+        assert_eq!(info.authors, vec!["Someone", "Someone Else"]);
+        assert_eq!(info.platform.as_deref(), Some("originalChip8"));
+        assert_eq!(info.tickrate, Some(15));
+        assert_eq!(info.keys, vec![("left".to_owned(), 4), ("right".to_owned(), 6)]);
+        assert!(database.lookup("2222222222222222222222222222222222222222").is_none());
+    }
+
+    #[test]
+    fn applies_platform_quirks_and_overrides() {
+        let database = Database::from_json(PROGRAMS).unwrap();
+        let config = database.lookup("abcdef0123456789abcdef0123456789abcdef01").unwrap().config.unwrap();
+        let vip = Config::preset("vip").unwrap();
+        assert_eq!(config, Config { vf_reset: false, ..vip });
+    }
+
+    #[test]
+    fn reads_start_addresses_that_fit_in_memory() {
+        let database = Database::from_json(PROGRAMS).unwrap();
+        let lookup = |sha1: &str| database.lookup(sha1).unwrap();
+        let eti = lookup("2000000000000000000000000000000000000000");
+        assert_eq!((eti.load_address, eti.config), (Some(0x600), None));
+        assert_eq!(lookup("3000000000000000000000000000000000000000").load_address, None);
+        assert_eq!(lookup("4000000000000000000000000000000000000000").load_address, None);
+    }
+
+    #[test]
+    fn reads_palettes() {
+        let database = Database::from_json(PROGRAMS).unwrap();
+        let palette = database.lookup("abcdef0123456789abcdef0123456789abcdef01").unwrap().palette.unwrap();
+        assert_eq!(palette.foreground, [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(palette.background[0], 0x10 as f32 / 255.0);
+    }
+
+    #[test]
+    fn missing_fields_are_left_unset() {
+        let database = Database::from_json(PROGRAMS).unwrap();
+        let info = database.lookup("1111111111111111111111111111111111111111").unwrap();
+        assert_eq!(info, &RomInfo { title: "Bare".to_owned(), ..RomInfo::default() });
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        assert!(Database::from_json("{").is_err());
+        assert!(Database::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn embedded_database_knows_the_ibm_logo() {
+        // IBM Logo.ch8, 132 bytes
+        let ibm_logo = [
+            0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0x61, 0x08, 0xD0, 0x1F, 0x70, 0x09, 0xA2, 0x39, 0xD0, 0x1F,
+            0xA2, 0x48, 0x70, 0x08, 0xD0, 0x1F, 0x70, 0x04, 0xA2, 0x57, 0xD0, 0x1F, 0x70, 0x08, 0xA2, 0x66,
+            0xD0, 0x1F, 0x70, 0x08, 0xA2, 0x75, 0xD0, 0x1F, 0x12, 0x28, 0xFF, 0x00, 0xFF, 0x00, 0x3C, 0x00,
+            0x3C, 0x00, 0x3C, 0x00, 0x3C, 0x00, 0xFF, 0x00, 0xFF, 0xFF, 0x00, 0xFF, 0x00, 0x38, 0x00, 0x3F,
+            0x00, 0x3F, 0x00, 0x38, 0x00, 0xFF, 0x00, 0xFF, 0x80, 0x00, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0x00,
+            0x80, 0x00, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0xF8, 0x00, 0xFC, 0x00, 0x3E, 0x00, 0x3F, 0x00, 0x3B,
+            0x00, 0x39, 0x00, 0xF8, 0x00, 0xF8, 0x03, 0x00, 0x07, 0x00, 0x0F, 0x00, 0xBF, 0x00, 0xFB, 0x00,
+            0xF3, 0x00, 0xE3, 0x00, 0x43, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80,
+            0x00, 0xE0, 0x00, 0xE0
+        ];
+        let rom = crate::loader::validate(ibm_logo.to_vec(), &Config::default()).unwrap();
+        let database = Database::embedded();
+        let info = database.lookup(&rom.sha1).expect("IBM Logo is in the embedded database");
+        assert_eq!(info.title, "IBM Logo");
+        assert_eq!(info.platform.as_deref(), Some("originalChip8"));
+        assert_eq!(info.config, Config::preset("vip"));
+    }
+}
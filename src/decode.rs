@@ -0,0 +1,239 @@
+//! Opcodes decoded into instructions with their operands pulled out, and a
+//! cache of them by address so a loop is only decoded once.
+
+use crate::{Machine, Observer, OpCode, MEMORY_SIZE};
+
+/// A decoded opcode. Registers are indices into V; words the core doesn't
+/// run are `Unknown`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    /// 00E0
+    Clear,
+    /// 00EE
+    Return,
+    /// 0NNN, a machine code routine on the original hardware; skipped.
+    Sys,
+    /// 1NNN
+    Jump(u16),
+    /// 2NNN
+    Call(u16),
+    /// 3XNN
+    SkipIfEqual { x: u8, nn: u8 },
+    /// 4XNN
+    SkipIfNotEqual { x: u8, nn: u8 },
+    /// 5XY0
+    SkipIfRegistersEqual { x: u8, y: u8 },
+    /// 6XNN
+    Load { x: u8, nn: u8 },
+    /// 7XNN
+    Add { x: u8, nn: u8 },
+    /// 8XY0
+    Copy { x: u8, y: u8 },
+    /// 8XY1
+    Or { x: u8, y: u8 },
+    /// 8XY2
+    And { x: u8, y: u8 },
+    /// 8XY3
+    Xor { x: u8, y: u8 },
+    /// 8XY4
+    AddRegisters { x: u8, y: u8 },
+    /// 8XY5
+    Sub { x: u8, y: u8 },
+    /// 8XY6
+    ShiftRight { x: u8, y: u8 },
+    /// 8XY7
+    SubReversed { x: u8, y: u8 },
+    /// 8XYE
+    ShiftLeft { x: u8, y: u8 },
+    /// 9XY0
+    SkipIfRegistersDiffer { x: u8, y: u8 },
+    /// ANNN
+    LoadIndex(u16),
+    /// BNNN
+    JumpOffset(u16),
+    /// CXNN
+    Random { x: u8, mask: u8 },
+    /// DXYN
+    Draw { x: u8, y: u8, height: u8 },
+    /// EX9E
+    SkipIfKey { x: u8 },
+    /// EXA1
+    SkipUnlessKey { x: u8 },
+    /// FX07
+    LoadDelay { x: u8 },
+    /// FX0A
+    WaitKey { x: u8 },
+    /// FX15
+    SetDelay { x: u8 },
+    /// FX18
+    SetSound { x: u8 },
+    /// FX1E
+    AddIndex { x: u8 },
+    /// FX29
+    LoadFont { x: u8 },
+    /// FX33
+    StoreBcd { x: u8 },
+    /// FX55
+    StoreRegisters { x: u8 },
+    /// FX65
+    LoadRegisters { x: u8 },
+    Unknown
+}
+
+/// Decodes an opcode the way the core runs it. 5XYN and 9XYN with N other
+/// than 0 are `Unknown`, matching the disassembler.
+pub fn decode(opcode: OpCode) -> Instruction {
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => Instruction::Clear,
+            0x00EE => Instruction::Return,
+            _ => Instruction::Sys
+        },
+        0x1000 => Instruction::Jump(nnn),
+        0x2000 => Instruction::Call(nnn),
+        0x3000 => Instruction::SkipIfEqual { x, nn },
+        0x4000 => Instruction::SkipIfNotEqual { x, nn },
+        0x5000 if n == 0 => Instruction::SkipIfRegistersEqual { x, y },
+        0x6000 => Instruction::Load { x, nn },
+        0x7000 => Instruction::Add { x, nn },
+        0x8000 => match n {
+            0x0 => Instruction::Copy { x, y },
+            0x1 => Instruction::Or { x, y },
+            0x2 => Instruction::And { x, y },
+            0x3 => Instruction::Xor { x, y },
+            0x4 => Instruction::AddRegisters { x, y },
+            0x5 => Instruction::Sub { x, y },
+            0x6 => Instruction::ShiftRight { x, y },
+            0x7 => Instruction::SubReversed { x, y },
+            0xE => Instruction::ShiftLeft { x, y },
+            _ => Instruction::Unknown
+        },
+        0x9000 if n == 0 => Instruction::SkipIfRegistersDiffer { x, y },
+        0xA000 => Instruction::LoadIndex(nnn),
+        0xB000 => Instruction::JumpOffset(nnn),
+        0xC000 => Instruction::Random { x, mask: nn },
+        0xD000 => Instruction::Draw { x, y, height: n },
+        0xE000 => match nn {
+            0x9E => Instruction::SkipIfKey { x },
+            0xA1 => Instruction::SkipUnlessKey { x },
+            _ => Instruction::Unknown
+        },
+        0xF000 => match nn {
+            0x07 => Instruction::LoadDelay { x },
+            0x0A => Instruction::WaitKey { x },
+            0x15 => Instruction::SetDelay { x },
+            0x18 => Instruction::SetSound { x },
+            0x1E => Instruction::AddIndex { x },
+            0x29 => Instruction::LoadFont { x },
+            0x33 => Instruction::StoreBcd { x },
+            0x55 => Instruction::StoreRegisters { x },
+            0x65 => Instruction::LoadRegisters { x },
+            _ => Instruction::Unknown
+        },
+        // 5XYN and 9XYN with N other than 0
+        _ => Instruction::Unknown
+    }
+}
+
+/// Instructions decoded by address, for `Machine::step_cached`.
+///
+/// Writes made by the program, through FX33 and FX55, drop the entries
+/// they overlap as they happen. Bytes poked from outside go through
+/// `Machine::poke_with` with the cache as its observer; anything else that
+/// changes memory, like loading another program, must call `invalidate` or
+/// `clear`. An address with no entry is decoded from
+/// memory, so clearing too much only costs a decode.
+pub struct DecodeCache {
+    entries: Vec<Option<(OpCode, Instruction)>>
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache { entries: vec![None; MEMORY_SIZE] }
+    }
+
+    /// The opcode at `pc` and its instruction, decoding it if needed.
+    pub fn get(&mut self, machine: &Machine) -> (OpCode, Instruction) {
+        let pc = machine.pc as usize;
+        match self.entries[pc] {
+            Some(entry) => entry,
+            None => {
+                let opcode = machine.opcode();
+                let entry = (opcode, decode(opcode));
+                self.entries[pc] = Some(entry);
+                entry
+            }
+        }
+    }
+
+    /// Forgets the instructions that include the byte at `address`: the
+    /// one starting there and the one starting just before it.
+    pub fn invalidate(&mut self, address: u16) {
+        let address = address as usize;
+        if address < MEMORY_SIZE {
+            self.entries[address] = None;
+        }
+        if address > 0 && address <= MEMORY_SIZE {
+            self.entries[address - 1] = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache::new()
+    }
+}
+
+/// Watches the program's own writes to memory.
+impl Observer for DecodeCache {
+    fn memory_write(&mut self, address: u16, _value: u8) {
+        self.invalidate(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(decode(0x00E0), Instruction::Clear);
+        assert_eq!(decode(0x0123), Instruction::Sys);
+        assert_eq!(decode(0x2ABC), Instruction::Call(0xABC));
+        assert_eq!(decode(0x8AB6), Instruction::ShiftRight { x: 0xA, y: 0xB });
+        assert_eq!(decode(0xC37F), Instruction::Random { x: 3, mask: 0x7F });
+        assert_eq!(decode(0xD12F), Instruction::Draw { x: 1, y: 2, height: 0xF });
+        assert_eq!(decode(0xF455), Instruction::StoreRegisters { x: 4 });
+        assert_eq!(decode(0x800F), Instruction::Unknown);
+        assert_eq!(decode(0xE3A2), Instruction::Unknown);
+        assert_eq!(decode(0xF0FF), Instruction::Unknown);
+    }
+
+    #[test]
+    fn invalidates_both_instructions_a_byte_belongs_to() {
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(&[0x60, 0x01, 0x70, 0x02], 0x200);
+        let mut cache = DecodeCache::new();
+        cache.get(&machine);
+        machine.pc = 0x202;
+        cache.get(&machine);
+
+        machine.memory[0x201] = 0x05;
+        cache.invalidate(0x201);
+        assert!(cache.entries[0x202].is_some());
+        machine.pc = 0x200;
+        assert_eq!(cache.get(&machine), (0x6005, Instruction::Load { x: 0, nn: 5 }));
+    }
+}
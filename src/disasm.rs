@@ -0,0 +1,124 @@
+use crate::OpCode;
+
+/// Renders an opcode in Cowgod-style assembly, e.g. `LD V3, 0x2A`.
+/// Words that aren't instructions come out as `DW 0xNNNN`.
+pub fn disassemble(opcode: OpCode) -> String {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "CLS".to_owned(),
+            0x00EE => "RET".to_owned(),
+            _ => format!("SYS 0x{:03X}", nnn)
+        },
+        0x1000 => format!("JP 0x{:03X}", nnn),
+        0x2000 => format!("CALL 0x{:03X}", nnn),
+        0x3000 => format!("SE V{:X}, 0x{:02X}", x, nn),
+        0x4000 => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        0x5000 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, 0x{:02X}", x, nn),
+        0x7000 => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        0x8000 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("DW 0x{:04X}", opcode)
+        },
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, 0x{:03X}", nnn),
+        0xB000 => format!("JP V0, 0x{:03X}", nnn),
+        0xC000 => format!("RND V{:X}, 0x{:02X}", x, nn),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("DW 0x{:04X}", opcode)
+        },
+        0xF000 => match nn {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => format!("DW 0x{:04X}", opcode)
+        },
+        _ => format!("DW 0x{:04X}", opcode)
+    }
+}
+
+/// Disassembles `program` as if loaded at `origin`, one line per word:
+/// `0x200: 00E0  CLS`.
+pub fn listing(program: &[u8], origin: u16) -> Vec<String> {
+    program.chunks(2).enumerate().map(|(n, word)| {
+        let addr = origin as usize + n*2;
+        if word.len() == 2 {
+            let opcode = (word[0] as u16) << 8 | word[1] as u16;
+            format!("0x{:03X}: {:04X}  {}", addr, opcode, disassemble(opcode))
+        } else {
+            format!("0x{:03X}: {:02X}    DB 0x{:02X}", addr, word[0], word[0])
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembles_instructions() {
+        assert_eq!(disassemble(0x00E0), "CLS");
+        assert_eq!(disassemble(0x00EE), "RET");
+        assert_eq!(disassemble(0x1234), "JP 0x234");
+        assert_eq!(disassemble(0x6A2B), "LD VA, 0x2B");
+        assert_eq!(disassemble(0x8124), "ADD V1, V2");
+        assert_eq!(disassemble(0xB300), "JP V0, 0x300");
+        assert_eq!(disassemble(0xD125), "DRW V1, V2, 5");
+        assert_eq!(disassemble(0xE59E), "SKP V5");
+        assert_eq!(disassemble(0xF733), "LD B, V7");
+        assert_eq!(disassemble(0xF265), "LD V2, [I]");
+    }
+
+    #[test]
+    fn unknown_words_become_data() {
+        assert_eq!(disassemble(0x5121), "DW 0x5121");
+        assert_eq!(disassemble(0x8128), "DW 0x8128");
+        assert_eq!(disassemble(0xF0FF), "DW 0xF0FF");
+    }
+
+    #[test]
+    fn agrees_with_the_decoder_on_register_skips() {
+        use crate::decode::{decode, Instruction};
+        assert_eq!(disassemble(0x5120), "SE V1, V2");
+        assert_eq!(decode(0x5120), Instruction::SkipIfRegistersEqual { x: 1, y: 2 });
+        assert_eq!(disassemble(0x9120), "SNE V1, V2");
+        assert_eq!(decode(0x9120), Instruction::SkipIfRegistersDiffer { x: 1, y: 2 });
+        // a nonzero low nibble is no instruction to either
+        for opcode in [0x5121, 0x5107, 0x912F] {
+            assert_eq!(disassemble(opcode), format!("DW 0x{:04X}", opcode));
+            assert_eq!(decode(opcode), Instruction::Unknown);
+        }
+    }
+
+    #[test]
+    fn lists_programs_with_addresses() {
+        assert_eq!(listing(&[0x00, 0xE0, 0x12, 0x00, 0xFF], 0x200), vec![
+            "0x200: 00E0  CLS",
+            "0x202: 1200  JP 0x200",
+            "0x204: FF    DB 0xFF"
+        ]);
+    }
+}
@@ -0,0 +1,141 @@
+//! The 64x32 monochrome display, one `u64` per row.
+//!
+//! Bit 63 of a row is its leftmost pixel, so a sprite byte lines up with a
+//! row by shifting it to the top of a `u64` and then right by x. Pixels
+//! that would fall off the right edge are shifted out, which is the
+//! clipping DXYN wants, and drawing and collision checks take a shift, an
+//! AND and an XOR per sprite row. SCHIP's 128-pixel hi-res rows would fit
+//! a `u128` the same way, should that mode be added.
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Display {
+    rows: [u64; HEIGHT]
+}
+
+impl Display {
+    pub fn new() -> Display {
+        Display { rows: [0; HEIGHT] }
+    }
+
+    pub fn clear(&mut self) {
+        self.rows = [0; HEIGHT];
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.rows[y] & bit(x) != 0
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, lit: bool) {
+        if lit {
+            self.rows[y] |= bit(x);
+        } else {
+            self.rows[y] &= !bit(x);
+        }
+    }
+
+    /// Row `y` with its leftmost pixel in bit 63.
+    pub fn row(&self, y: usize) -> u64 {
+        self.rows[y]
+    }
+
+    /// XORs a sprite onto the display with its top left corner at (x, y),
+    /// which must be on the display, clipping it at the right and bottom
+    /// edges. Returns whether any lit pixel was turned off.
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        let mut collision = 0;
+        for (row, &byte) in self.rows[y..].iter_mut().zip(sprite) {
+            let bits = ((byte as u64) << (WIDTH - 8)) >> x;
+            collision |= *row & bits;
+            *row ^= bits;
+        }
+        collision != 0
+    }
+
+    pub fn lit_count(&self) -> usize {
+        self.rows.iter().map(|row| row.count_ones() as usize).sum()
+    }
+
+    /// The (x, y) of every lit pixel, row by row, for renderers that draw
+    /// pixels one at a time.
+    pub fn lit_pixels(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.rows.iter().enumerate().flat_map(|(y, &row)| {
+            let mut rest = row;
+            std::iter::from_fn(move || {
+                if rest == 0 {
+                    return None;
+                }
+                let x = rest.leading_zeros() as usize;
+                rest &= !bit(x);
+                Some((x, y))
+            })
+        })
+    }
+
+    /// One `bool` per pixel, row by row.
+    pub fn to_bools(&self) -> [bool; WIDTH * HEIGHT] {
+        let mut pixels = [false; WIDTH * HEIGHT];
+        for (x, y) in self.lit_pixels() {
+            pixels[y * WIDTH + x] = true;
+        }
+        pixels
+    }
+
+    pub fn from_bools(pixels: &[bool; WIDTH * HEIGHT]) -> Display {
+        let mut display = Display::new();
+        for (n, &lit) in pixels.iter().enumerate() {
+            display.set(n % WIDTH, n / WIDTH, lit);
+        }
+        display
+    }
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
+fn bit(x: usize) -> u64 {
+    1 << (WIDTH - 1 - x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_and_erases_with_collision() {
+        let mut display = Display::new();
+        assert!(!display.draw_sprite(3, 1, &[0b1010_0000, 0b0100_0000]));
+        assert_eq!(display.lit_pixels().collect::<Vec<_>>(), vec![(3, 1), (5, 1), (4, 2)]);
+        assert_eq!(display.row(1), 0b101 << 58);
+
+        assert!(display.draw_sprite(5, 1, &[0b1000_0000]));
+        assert!(!display.get(5, 1));
+        assert_eq!(display.lit_count(), 2);
+    }
+
+    #[test]
+    fn clips_at_the_edges() {
+        let mut display = Display::new();
+        display.draw_sprite(60, 30, &[0xFF, 0xFF, 0xFF]);
+        assert_eq!(display.lit_count(), 8);
+        assert!(display.get(63, 31));
+        assert!(!display.get(0, 31));
+    }
+
+    #[test]
+    fn converts_to_and_from_bools() {
+        let mut display = Display::new();
+        display.set(0, 0, true);
+        display.set(63, 31, true);
+        display.set(17, 9, true);
+        let pixels = display.to_bools();
+        assert_eq!(pixels.iter().filter(|&&p| p).count(), 3);
+        assert!(pixels[9 * WIDTH + 17]);
+        assert_eq!(Display::from_bools(&pixels), display);
+    }
+}
@@ -0,0 +1,140 @@
+//! The debug panel beside the display: registers, stack, timers, keypad
+//! and the last few instructions, laid out as rows of text cells.
+
+use chip8::disasm::disassemble;
+use chip8::runner::Registers;
+use chip8::{Machine, OpCode, KEYPAD_LAYOUT};
+
+/// Cells across the panel; a field starts at one and may run into the next.
+pub const CELLS: usize = 4;
+
+/// Instructions listed at the bottom of the panel.
+pub const HISTORY_ROWS: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub struct Field {
+    pub cell: usize,
+    pub text: String,
+    /// The value differs from the one in the frame before.
+    pub changed: bool
+}
+
+fn field(cell: usize, text: String, changed: bool) -> Field {
+    Field { cell, text, changed }
+}
+
+/// Lays out the panel for `after`, flagging what differs from `before`.
+/// `history` holds the address and opcode of recent instructions, oldest first.
+pub fn panel(before: &Registers, after: &Machine, history: &[(u16, OpCode)]) -> Vec<Vec<Field>> {
+    let mut rows = Vec::new();
+
+    rows.push(vec![
+        field(0, format!("PC {:03X}", after.pc), after.pc != before.pc),
+        field(2, format!("I {:03X}", after.i), after.i != before.i)
+    ]);
+    rows.push(vec![
+        field(0, format!("DT {:02X}", after.delay_timer), after.delay_timer != before.delay_timer),
+        field(1, format!("ST {:02X}", after.sound_timer), after.sound_timer != before.sound_timer),
+        field(2, format!("SP {}", after.sp), after.sp != before.sp)
+    ]);
+
+    for row in 0..16 / CELLS {
+        rows.push((0..CELLS).map(|cell| {
+            let x = row * CELLS + cell;
+            field(cell, format!("V{:X} {:02X}", x, after.v[x]), after.v[x] != before.v[x])
+        }).collect());
+    }
+
+    // only the frames in use, four to a row
+    let depth = after.sp as usize;
+    rows.push(vec![field(0, "Stack".to_owned(), false)]);
+    if depth == 0 {
+        rows.push(vec![field(0, "-".to_owned(), depth != before.sp as usize)]);
+    }
+    for start in (0..depth).step_by(CELLS) {
+        rows.push((start..depth.min(start + CELLS)).map(|n| {
+            let changed = n >= before.sp as usize || after.stack[n] != before.stack[n];
+            field(n - start, format!("{:03X}", after.stack[n]), changed)
+        }).collect());
+    }
+
+    // the keypad as laid out on the device, pressed keys in brackets
+    rows.push(vec![field(0, "Keys".to_owned(), false)]);
+    for layout_row in KEYPAD_LAYOUT.chunks(CELLS) {
+        rows.push(layout_row.iter().enumerate().map(|(cell, &k)| {
+            let pressed = after.key[k as usize];
+            let text = if pressed { format!("[{:X}]", k) } else { format!(" {:X} ", k) };
+            field(cell, text, pressed != before.key[k as usize])
+        }).collect());
+    }
+
+    let wait = match (after.await_keypress, after.awaited_key) {
+        (false, _) => "Wait -".to_owned(),
+        (true, None) => format!("Wait V{:X}", after.keypress_register),
+        (true, Some(key)) => format!("Wait V{:X}, {:X} held", after.keypress_register, key)
+    };
+    let wait_changed = after.await_keypress != before.await_keypress || after.awaited_key != before.awaited_key;
+    rows.push(vec![field(0, wait, wait_changed)]);
+
+    rows.push(vec![field(0, "Recent".to_owned(), false)]);
+    let skip = history.len().saturating_sub(HISTORY_ROWS);
+    for &(pc, opcode) in &history[skip..] {
+        rows.push(vec![field(0, format!("{:03X} {:04X} {}", pc, opcode, disassemble(opcode)), false)]);
+    }
+
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::Config;
+
+    fn find<'a>(rows: &'a [Vec<Field>], prefix: &str) -> &'a Field {
+        rows.iter().flatten().find(|field| field.text.starts_with(prefix)).unwrap()
+    }
+
+    #[test]
+    fn flags_values_changed_since_the_frame_before() {
+        let before = Machine::new(Config::default());
+        let mut after = before.clone();
+        after.v[3] = 0x2A;
+        after.pc = 0x202;
+        after.stack[0] = 0x200;
+        after.sp = 1;
+        after.key[0xC] = true;
+
+        let rows = panel(&Registers::of(&before), &after, &[(0x200, 0x632A)]);
+        assert_eq!(find(&rows, "V3"), &field(3, "V3 2A".to_owned(), true));
+        assert!(!find(&rows, "V4").changed);
+        assert!(find(&rows, "PC 202").changed);
+        assert!(!find(&rows, "I 000").changed);
+        assert!(find(&rows, "200").changed);
+        assert_eq!(find(&rows, "[C]").cell, 3);
+        assert!(!find(&rows, "Wait -").changed);
+        assert_eq!(rows.last().unwrap()[0].text, "200 632A LD V3, 0x2A");
+    }
+
+    #[test]
+    fn shows_the_awaited_key() {
+        let before = Machine::new(Config::default());
+        let mut after = before.clone();
+        after.await_keypress = true;
+        after.keypress_register = 5;
+        after.awaited_key = Some(0xB);
+
+        let rows = panel(&Registers::of(&before), &after, &[]);
+        assert!(find(&rows, "Wait V5, B held").changed);
+        assert_eq!(find(&rows, "Stack").cell, 0);
+        assert_eq!(find(&rows, "-").text, "-");
+    }
+
+    #[test]
+    fn keeps_only_the_latest_history() {
+        let machine = Machine::new(Config::default());
+        let history: Vec<(u16, OpCode)> = (0..20).map(|n| (0x200 + n * 2, 0x00E0)).collect();
+        let rows = panel(&Registers::of(&machine), &machine, &history);
+        assert_eq!(rows.iter().filter(|row| row[0].text.ends_with("CLS")).count(), HISTORY_ROWS);
+        assert!(find(&rows, "226 00E0").text.ends_with("CLS"));
+    }
+}
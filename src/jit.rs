@@ -0,0 +1,576 @@
+//! A JIT backend for batch runs, built with `--features jit`.
+//!
+//! Straight-line runs of register instructions starting at some PC are
+//! compiled with Cranelift into a native function that reads and writes
+//! the `Machine` in place, using the offsets of its fields. A block ends
+//! at a jump or skip, which it compiles, or before anything it doesn't
+//! compile (drawing, calls, key waits, CXNN, memory access), which is left
+//! to the interpreter for one step.
+//!
+//! Compiled blocks don't report to observers. Writes the interpreter makes
+//! over compiled code drop the blocks they touch; writes from outside need
+//! `Machine::poke_with`, `invalidate` or `clear`. A `Jit` bakes in the quirks of the machine it
+//! compiled for, so keep one per machine.
+//!
+//! Cranelift can't free one function at a time, so dropped blocks keep
+//! their code until `MAX_STALE_BLOCKS` of them have piled up; then the
+//! whole module is freed and the live blocks are compiled again as they
+//! are reached.
+
+use std::fmt;
+use std::mem::{self, offset_of, ManuallyDrop};
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Value};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::decode::{decode, Instruction};
+use crate::{Fault, Machine, Observer, MEMORY_SIZE};
+
+/// Longest run of instructions compiled into one block.
+const MAX_BLOCK: usize = 64;
+
+/// Dropped blocks whose code is kept before the module is rebuilt.
+const MAX_STALE_BLOCKS: usize = 1024;
+
+type BlockFn = extern "C" fn(*mut Machine);
+
+enum Slot {
+    Unseen,
+    /// The instruction here can't start a block, so it's interpreted.
+    Interpreted,
+    Compiled(Block)
+}
+
+struct Block {
+    function: BlockFn,
+    /// Instructions the block runs, including its closing jump or skip.
+    length: u64,
+    /// Bytes of memory it was compiled from.
+    end: u16
+}
+
+/// Where the JIT and the interpreter disagreed in `run_lockstep`.
+#[derive(Debug, PartialEq)]
+pub enum LockstepError {
+    Fault(Fault),
+    /// The block at `pc` left the machine in a different state from
+    /// interpreting its instructions, `instructions` into the run.
+    Diverged { pc: u16, instructions: u64 }
+}
+
+impl fmt::Display for LockstepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockstepError::Fault(fault) => write!(f, "{}", fault),
+            LockstepError::Diverged { pc, instructions } =>
+                write!(f, "Compiled block at 0x{:03X} diverged from the interpreter after {} instructions", pc, instructions)
+        }
+    }
+}
+
+pub struct Jit {
+    isa: OwnedTargetIsa,
+    /// Freed by hand, as dropping a `JITModule` leaks its code.
+    module: ManuallyDrop<JITModule>,
+    context: Context,
+    builder_context: FunctionBuilderContext,
+    /// What to do at each address.
+    blocks: Vec<Slot>,
+    /// Bytes some compiled block was built from.
+    covered: Vec<bool>,
+    /// Blocks dropped since the module was built, whose code it still holds.
+    stale: usize
+}
+
+impl Jit {
+    /// Sets up Cranelift for the host. Fails on hosts it can't target.
+    pub fn new() -> Result<Jit, String> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|err| err.to_string())?;
+        let isa = cranelift_native::builder()
+            .map_err(|err| err.to_owned())?
+            .finish(settings::Flags::new(flags))
+            .map_err(|err| err.to_string())?;
+        let module = JITModule::new(JITBuilder::with_isa(isa.clone(), default_libcall_names()));
+        Ok(Jit {
+            isa,
+            context: module.make_context(),
+            module: ManuallyDrop::new(module),
+            builder_context: FunctionBuilderContext::new(),
+            blocks: (0..MEMORY_SIZE).map(|_| Slot::Unseen).collect(),
+            covered: vec![false; MEMORY_SIZE],
+            stale: 0
+        })
+    }
+
+    /// Runs at least `instructions` instructions, finishing the last block,
+    /// and returns how many ran. Timers aren't ticked.
+    pub fn run(&mut self, machine: &mut Machine, instructions: u64) -> Result<u64, Fault> {
+        let mut ran = 0;
+        while ran < instructions {
+            ran += self.step(machine)?.0;
+        }
+        Ok(ran)
+    }
+
+    /// Like `run`, but also interprets each compiled block on a copy of
+    /// the machine and stops at the first block that disagrees.
+    pub fn run_lockstep(&mut self, machine: &mut Machine, instructions: u64) -> Result<u64, LockstepError> {
+        let mut ran = 0;
+        while ran < instructions {
+            let mut expected = machine.clone();
+            let pc = machine.pc;
+            let (count, compiled) = self.step(machine).map_err(LockstepError::Fault)?;
+            ran += count;
+            if compiled {
+                for _ in 0..count {
+                    expected.step().map_err(LockstepError::Fault)?;
+                }
+                if expected != *machine {
+                    return Err(LockstepError::Diverged { pc, instructions: ran });
+                }
+            }
+        }
+        Ok(ran)
+    }
+
+    /// Drops the blocks built from the byte at `address`.
+    pub fn invalidate(&mut self, address: u16) {
+        if !self.covered.get(address as usize).copied().unwrap_or(false) {
+            return;
+        }
+        self.covered.iter_mut().for_each(|covered| *covered = false);
+        for (start, slot) in self.blocks.iter_mut().enumerate() {
+            if let Slot::Compiled(block) = slot {
+                if (start..block.end as usize).contains(&(address as usize)) {
+                    *slot = Slot::Unseen;
+                    self.stale += 1;
+                } else {
+                    self.covered[start..block.end as usize].iter_mut().for_each(|covered| *covered = true);
+                }
+            }
+        }
+    }
+
+    /// Drops every block, e.g. after loading another program.
+    pub fn clear(&mut self) {
+        self.stale += self.blocks.iter().filter(|slot| matches!(slot, Slot::Compiled(_))).count();
+        self.blocks.iter_mut().for_each(|slot| *slot = Slot::Unseen);
+        self.covered.iter_mut().for_each(|covered| *covered = false);
+    }
+
+    /// Frees the code of every block, dropped or not, by starting a new module.
+    fn rebuild(&mut self) {
+        self.blocks.iter_mut().for_each(|slot| *slot = Slot::Unseen);
+        self.covered.iter_mut().for_each(|covered| *covered = false);
+        self.stale = 0;
+        let module = JITModule::new(JITBuilder::with_isa(self.isa.clone(), default_libcall_names()));
+        let old = mem::replace(&mut *self.module, module);
+        // no block is running and every pointer into the old module was just dropped
+        unsafe { old.free_memory() };
+    }
+
+    /// Runs the block at PC, compiling it first if needed, or interprets
+    /// one instruction. Returns how many ran and whether they were compiled.
+    fn step(&mut self, machine: &mut Machine) -> Result<(u64, bool), Fault> {
+        let pc = machine.pc as usize;
+        if let Slot::Unseen = self.blocks[pc] {
+            if self.stale >= MAX_STALE_BLOCKS {
+                self.rebuild();
+            }
+            self.blocks[pc] = match self.compile(machine) {
+                Some(block) => {
+                    self.covered[pc..block.end as usize].iter_mut().for_each(|covered| *covered = true);
+                    Slot::Compiled(block)
+                },
+                None => Slot::Interpreted
+            };
+        }
+        if let Slot::Compiled(block) = &self.blocks[pc] {
+            (block.function)(machine);
+            return Ok((block.length, true));
+        }
+
+        let mut writes = Writes(Vec::new());
+        machine.step_with(&mut writes)?;
+        for address in writes.0 {
+            self.invalidate(address);
+        }
+        Ok((1, false))
+    }
+
+    /// Compiles the instructions at PC, or returns `None` if the first one
+    /// can't be compiled.
+    fn compile(&mut self, machine: &Machine) -> Option<Block> {
+        let start = machine.pc;
+        let mut pc = start;
+        let mut length = 0;
+        let mut closing = None;
+        while length < MAX_BLOCK && (pc as usize) + 1 < MEMORY_SIZE {
+            let opcode = (machine.memory[pc as usize] as u16) << 8 | machine.memory[pc as usize + 1] as u16;
+            let instruction = decode(opcode);
+            if closes_block(instruction) {
+                closing = Some(instruction);
+                break;
+            }
+            if !compiles(instruction) {
+                break;
+            }
+            length += 1;
+            pc += 2;
+        }
+        if length == 0 && closing.is_none() {
+            return None;
+        }
+
+        // start clean, as a compile that gave up part way leaves its function behind
+        self.module.clear_context(&mut self.context);
+        let pointer = self.module.target_config().pointer_type();
+        self.context.func.signature.params.push(AbiParam::new(pointer));
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let base = builder.block_params(entry)[0];
+
+        let mut emitter = Emitter { builder, base, v: [None; 16], dirty: [false; 16], i: None, i_dirty: false };
+        let mut address = start;
+        for _ in 0..length {
+            let opcode = (machine.memory[address as usize] as u16) << 8 | machine.memory[address as usize + 1] as u16;
+            emitter.instruction(decode(opcode), machine);
+            address += 2;
+        }
+        let next_pc = match closing {
+            Some(instruction) => {
+                length += 1;
+                emitter.closing(instruction, address)
+            },
+            None => emitter.builder.ins().iconst(types::I16, address as i64)
+        };
+        emitter.finish(next_pc);
+        let end = if closing.is_some() { address + 2 } else { address };
+
+        let id = self.module.declare_anonymous_function(&self.context.func.signature).ok()?;
+        let defined = self.module.define_function(id, &mut self.context);
+        self.module.clear_context(&mut self.context);
+        defined.ok()?;
+        self.module.finalize_definitions().ok()?;
+        let code = self.module.get_finalized_function(id);
+        // the function was built above with the `BlockFn` signature
+        let function = unsafe { mem::transmute::<*const u8, BlockFn>(code) };
+        Some(Block { function, length: length as u64, end })
+    }
+}
+
+/// Instructions compiled in the middle of a block.
+fn compiles(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Sys | Instruction::Load { .. } | Instruction::Add { .. } | Instruction::Copy { .. }
+        | Instruction::Or { .. } | Instruction::And { .. } | Instruction::Xor { .. }
+        | Instruction::AddRegisters { .. } | Instruction::Sub { .. } | Instruction::ShiftRight { .. }
+        | Instruction::SubReversed { .. } | Instruction::ShiftLeft { .. } | Instruction::LoadIndex(_)
+        | Instruction::AddIndex { .. } | Instruction::LoadFont { .. } | Instruction::LoadDelay { .. }
+        | Instruction::SetDelay { .. } | Instruction::SetSound { .. })
+}
+
+/// Jumps and skips, which end a block by choosing the next PC.
+fn closes_block(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Jump(_) | Instruction::SkipIfEqual { .. } | Instruction::SkipIfNotEqual { .. }
+        | Instruction::SkipIfRegistersEqual { .. } | Instruction::SkipIfRegistersDiffer { .. })
+}
+
+/// Builds a block's code, keeping V and I in SSA values and writing back
+/// the ones that changed at the end.
+struct Emitter<'a> {
+    builder: FunctionBuilder<'a>,
+    base: Value,
+    v: [Option<Value>; 16],
+    dirty: [bool; 16],
+    i: Option<Value>,
+    i_dirty: bool
+}
+
+impl Emitter<'_> {
+    fn v(&mut self, x: u8) -> Value {
+        let x = x as usize;
+        match self.v[x] {
+            Some(value) => value,
+            None => {
+                let offset = (offset_of!(Machine, v) + x) as i32;
+                let value = self.builder.ins().load(types::I8, MemFlags::trusted(), self.base, offset);
+                self.v[x] = Some(value);
+                value
+            }
+        }
+    }
+
+    fn set_v(&mut self, x: u8, value: Value) {
+        self.v[x as usize] = Some(value);
+        self.dirty[x as usize] = true;
+    }
+
+    fn i(&mut self) -> Value {
+        match self.i {
+            Some(value) => value,
+            None => {
+                let value = self.builder.ins().load(types::I16, MemFlags::trusted(), self.base, offset_of!(Machine, i) as i32);
+                self.i = Some(value);
+                value
+            }
+        }
+    }
+
+    fn set_i(&mut self, value: Value) {
+        self.i = Some(value);
+        self.i_dirty = true;
+    }
+
+    fn byte(&mut self, value: u8) -> Value {
+        self.builder.ins().iconst(types::I8, value as i64)
+    }
+
+    fn instruction(&mut self, instruction: Instruction, machine: &Machine) {
+        let vf_reset = machine.config.vf_reset;
+        let shift_uses_vy = machine.config.shift_uses_vy;
+        match instruction {
+            Instruction::Load { x, nn } => {
+                let value = self.byte(nn);
+                self.set_v(x, value);
+            },
+            Instruction::Add { x, nn } => {
+                let vx = self.v(x);
+                let value = self.builder.ins().iadd_imm(vx, nn as i64);
+                self.set_v(x, value);
+            },
+            Instruction::Copy { x, y } => {
+                let vy = self.v(y);
+                self.set_v(x, vy);
+            },
+            Instruction::Or { x, y } | Instruction::And { x, y } | Instruction::Xor { x, y } => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let value = match instruction {
+                    Instruction::Or { .. } => self.builder.ins().bor(vx, vy),
+                    Instruction::And { .. } => self.builder.ins().band(vx, vy),
+                    _ => self.builder.ins().bxor(vx, vy)
+                };
+                self.set_v(x, value);
+                if vf_reset {
+                    let zero = self.byte(0);
+                    self.set_v(0xF, zero);
+                }
+            },
+            Instruction::AddRegisters { x, y } => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let value = self.builder.ins().iadd(vx, vy);
+                let carry = self.builder.ins().icmp(IntCC::UnsignedLessThan, value, vx);
+                self.set_v(x, value);
+                self.set_v(0xF, carry);
+            },
+            Instruction::Sub { x, y } | Instruction::SubReversed { x, y } => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let (from, take) = if let Instruction::Sub { .. } = instruction { (vx, vy) } else { (vy, vx) };
+                let value = self.builder.ins().isub(from, take);
+                let no_borrow = self.builder.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, from, take);
+                self.set_v(x, value);
+                self.set_v(0xF, no_borrow);
+            },
+            Instruction::ShiftRight { x, y } => {
+                let src = if shift_uses_vy { self.v(y) } else { self.v(x) };
+                let value = self.builder.ins().ushr_imm(src, 1);
+                let flag = self.builder.ins().band_imm(src, 1);
+                self.set_v(x, value);
+                self.set_v(0xF, flag);
+            },
+            Instruction::ShiftLeft { x, y } => {
+                let src = if shift_uses_vy { self.v(y) } else { self.v(x) };
+                let value = self.builder.ins().ishl_imm(src, 1);
+                let flag = self.builder.ins().ushr_imm(src, 7);
+                self.set_v(x, value);
+                self.set_v(0xF, flag);
+            },
+            Instruction::LoadIndex(address) => {
+                let value = self.builder.ins().iconst(types::I16, address as i64);
+                self.set_i(value);
+            },
+            Instruction::AddIndex { x } => {
+                let i = self.i();
+                let vx = self.v(x);
+                let vx = self.builder.ins().uextend(types::I16, vx);
+                let value = self.builder.ins().iadd(i, vx);
+                let carry = self.builder.ins().icmp(IntCC::UnsignedLessThan, value, i);
+                self.set_i(value);
+                self.set_v(0xF, carry);
+            },
+            Instruction::LoadFont { x } => {
+                let vx = self.v(x);
+                let vx = self.builder.ins().uextend(types::I16, vx);
+                let value = self.builder.ins().imul_imm(vx, 5);
+                self.set_i(value);
+            },
+            Instruction::LoadDelay { x } => {
+                let value = self.builder.ins().load(types::I8, MemFlags::trusted(), self.base, offset_of!(Machine, delay_timer) as i32);
+                self.set_v(x, value);
+            },
+            Instruction::SetDelay { x } | Instruction::SetSound { x } => {
+                let offset = match instruction {
+                    Instruction::SetDelay { .. } => offset_of!(Machine, delay_timer),
+                    _ => offset_of!(Machine, sound_timer)
+                };
+                let vx = self.v(x);
+                self.builder.ins().store(MemFlags::trusted(), vx, self.base, offset as i32);
+            },
+            _ => ()
+        }
+    }
+
+    /// Emits the jump or skip at `address` and returns the PC it leads to.
+    fn closing(&mut self, instruction: Instruction, address: u16) -> Value {
+        let condition = match instruction {
+            Instruction::Jump(target) => return self.builder.ins().iconst(types::I16, target as i64),
+            Instruction::SkipIfEqual { x, nn } => {
+                let (vx, nn) = (self.v(x), self.byte(nn));
+                self.builder.ins().icmp(IntCC::Equal, vx, nn)
+            },
+            Instruction::SkipIfNotEqual { x, nn } => {
+                let (vx, nn) = (self.v(x), self.byte(nn));
+                self.builder.ins().icmp(IntCC::NotEqual, vx, nn)
+            },
+            Instruction::SkipIfRegistersEqual { x, y } => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                self.builder.ins().icmp(IntCC::Equal, vx, vy)
+            },
+            Instruction::SkipIfRegistersDiffer { x, y } => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                self.builder.ins().icmp(IntCC::NotEqual, vx, vy)
+            },
+            _ => unreachable!("not a closing instruction")
+        };
+        let skip = self.builder.ins().iconst(types::I16, address as i64 + 4);
+        let next = self.builder.ins().iconst(types::I16, address as i64 + 2);
+        self.builder.ins().select(condition, skip, next)
+    }
+
+    /// Writes back changed registers and the new PC, and returns.
+    fn finish(mut self, pc: Value) {
+        let flags = MemFlags::trusted();
+        for x in 0..16 {
+            if let (true, Some(value)) = (self.dirty[x], self.v[x]) {
+                self.builder.ins().store(flags, value, self.base, (offset_of!(Machine, v) + x) as i32);
+            }
+        }
+        if let (true, Some(value)) = (self.i_dirty, self.i) {
+            self.builder.ins().store(flags, value, self.base, offset_of!(Machine, i) as i32);
+        }
+        self.builder.ins().store(flags, pc, self.base, offset_of!(Machine, pc) as i32);
+        self.builder.ins().return_(&[]);
+        self.builder.finalize();
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        // the blocks go with the `Jit`, so nothing can call into the module
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() };
+    }
+}
+
+/// Watches writes from outside, made through `Machine::poke_with`.
+impl Observer for Jit {
+    fn memory_write(&mut self, address: u16, _value: u8) {
+        self.invalidate(address);
+    }
+}
+
+/// Collects the addresses an interpreted step writes.
+struct Writes(Vec<u16>);
+
+impl Observer for Writes {
+    fn memory_write(&mut self, address: u16, _value: u8) {
+        self.0.push(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn machine(program: &[u8], config: Config) -> Machine {
+        let mut machine = Machine::new(config);
+        machine.load_program(program, 0x200);
+        machine
+    }
+
+    // a loop of the register instructions blocks are built from:
+    // 200: LD V0, 0xF0; LD V1, 0x20; ADD V0, V1; SUB V2, V0; SHL V3, V0; OR V4, V1;
+    //      ADD I, V0; LD F, V1; ADD V5, 1; SHR V6, V5; SUBN V7, V1; LD DT, V5;
+    //      LD V8, DT; SNE V5, 0x40
+    // 21C: JP 0x220 / 21E: JP 0x200 / 220: LD V5, 0; JP 0x200
+    const PROGRAM: [u8; 36] = [
+        0x60, 0xF0, 0x61, 0x20, 0x80, 0x14, 0x82, 0x05, 0x83, 0x0E, 0x84, 0x11,
+        0xF0, 0x1E, 0xF1, 0x29, 0x75, 0x01, 0x86, 0x56, 0x87, 0x17, 0xF5, 0x15,
+        0xF8, 0x07, 0x45, 0x40, 0x12, 0x20, 0x12, 0x00, 0x65, 0x00, 0x12, 0x00
+    ];
+
+    #[test]
+    fn agrees_with_the_interpreter() {
+        for config in [Config::default(), Config::preset("vip").unwrap()] {
+            let mut jit = Jit::new().unwrap();
+            let mut machine = machine(&PROGRAM, config);
+            assert!(jit.run_lockstep(&mut machine, 5000).unwrap() >= 5000);
+        }
+    }
+
+    #[test]
+    fn runs_calls_and_draws_through_the_interpreter() {
+        // CALL 0x206; JP 0x200; (spare); 206: LD I, 0; DRW V0, V1, 5; ADD V0, 8; RET
+        let program = [0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0xA0, 0x00, 0xD0, 0x15, 0x70, 0x08, 0x00, 0xEE];
+        let mut jit = Jit::new().unwrap();
+        let mut machine = machine(&program, Config::default());
+        jit.run_lockstep(&mut machine, 1000).unwrap();
+        assert!(machine.gfx.lit_count() > 0);
+    }
+
+    #[test]
+    fn recompiles_code_the_program_overwrites() {
+        // 200: LD V1, 1; LD I, 0x202; LD V0, 0x61; LD [I], V0; JP 0x200
+        // the store turns the LD I at 0x202 into LD V1, 0x02 for the next pass
+        let program = [0x61, 0x01, 0xA2, 0x02, 0x60, 0x61, 0xF0, 0x55, 0x12, 0x00];
+        let mut jit = Jit::new().unwrap();
+        let mut machine = machine(&program, Config::default());
+        jit.run_lockstep(&mut machine, 4).unwrap();
+        assert_eq!((machine.pc, machine.v[1]), (0x208, 0x01));
+        jit.run_lockstep(&mut machine, 4).unwrap();
+        assert_eq!((machine.pc, machine.v[1]), (0x206, 0x02));
+    }
+
+    #[test]
+    fn recompiles_code_poked_from_outside() {
+        // LD V0, 1; JP 0x200
+        let mut jit = Jit::new().unwrap();
+        let mut machine = machine(&[0x60, 0x01, 0x12, 0x00], Config::default());
+        jit.run_lockstep(&mut machine, 2).unwrap();
+        machine.poke_with(0x201, 0x09, &mut jit);
+        jit.run_lockstep(&mut machine, 2).unwrap();
+        assert_eq!(machine.v[0], 0x09);
+    }
+
+    #[test]
+    fn frees_code_once_enough_blocks_are_dropped() {
+        // 200: LD V1, 0; LD I, 0x201; LD V0, V1; ADD V0, 1; LD [I], V0; JP 0x200
+        // every pass rewrites the first instruction, dropping the block it starts
+        let program = [0x61, 0x00, 0xA2, 0x01, 0x80, 0x10, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x00];
+        let mut jit = Jit::new().unwrap();
+        let mut machine = machine(&program, Config::default());
+        jit.run_lockstep(&mut machine, 6 * (MAX_STALE_BLOCKS as u64 + 10)).unwrap();
+        assert!(jit.stale < MAX_STALE_BLOCKS);
+    }
+}
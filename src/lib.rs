@@ -0,0 +1,1460 @@
+//! A CHIP-8 interpreter core, along with the loaders, the ROM database and
+//! the debugging tools built on it. The window lives in the `chip8` binary.
+
+use std::fmt;
+
+use decode::{decode, DecodeCache, Instruction};
+use display::Display;
+
+pub mod cartridge;
+pub mod cli;
+pub mod compare;
+pub mod database;
+pub mod decode;
+pub mod disasm;
+pub mod display;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod loader;
+pub mod native;
+pub mod octo;
+pub mod palette;
+pub mod profile;
+pub mod records;
+pub mod recompile;
+pub mod runner;
+pub mod trace;
+pub mod watch;
+
+/// The CHIP-8 keypad, row by row, in the order keymaps list their keys.
+pub const KEYPAD_LAYOUT: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC,
+    0x4, 0x5, 0x6, 0xD,
+    0x7, 0x8, 0x9, 0xE,
+    0xA, 0x0, 0xB, 0xF
+];
+
+pub type OpCode = u16;
+
+pub const MEMORY_SIZE: usize = 4096;
+
+/// Slots in the call stack, the most nesting any configuration can allow.
+pub const STACK_SIZE: usize = 16;
+
+/// Behaviours that differ between CHIP-8 implementations.
+///
+/// Every ALU op writes its result to VX before writing the flag to VF, so
+/// when X is F the flag always wins, as on the COSMAC VIP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    /// 8XY1/8XY2/8XY3 reset VF to 0 (COSMAC VIP).
+    pub vf_reset: bool,
+    /// 8XY6/8XYE shift VY into VX instead of shifting VX in place (COSMAC VIP).
+    pub shift_uses_vy: bool,
+    /// Number of nested 2NNN calls allowed: 12 on the COSMAC VIP, 16 on SCHIP.
+    /// At most `STACK_SIZE`.
+    pub stack_depth: usize,
+    /// FX0A completes when the key is released (COSMAC VIP) rather than pressed.
+    pub key_wait_release: bool,
+    /// Where programs are loaded and execution starts: 0x200, or 0x600 on the ETI-660.
+    pub load_address: u16
+}
+
+impl Config {
+    /// Looks up a named set of quirks: `default`, `vip` or `schip`.
+    pub fn preset(name: &str) -> Option<Config> {
+        match name {
+            "default" => Some(Config::default()),
+            "vip" => Some(Config {
+                vf_reset: true,
+                shift_uses_vy: true,
+                stack_depth: 12,
+                ..Config::default()
+            }),
+            "schip" => Some(Config {
+                vf_reset: false,
+                shift_uses_vy: false,
+                stack_depth: 16,
+                ..Config::default()
+            }),
+            _ => None
+        }
+    }
+
+    /// Checks the settings are ones the core can run.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.stack_depth > STACK_SIZE {
+            return Err(ConfigError::StackTooDeep(self.stack_depth));
+        }
+        Ok(())
+    }
+}
+
+/// Settings the core can't run with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// A stack depth beyond the `STACK_SIZE` slots there are.
+    StackTooDeep(usize)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::StackTooDeep(depth) => {
+                write!(f, "stack depth {} is more than the {} slots the stack has", depth, STACK_SIZE)
+            }
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            vf_reset: false,
+            shift_uses_vy: false,
+            stack_depth: 16,
+            key_wait_release: true,
+            load_address: 0x200
+        }
+    }
+}
+
+/// An error raised by the core that stops execution.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// 2NNN with every stack slot in use. `call_trace` holds the return
+    /// addresses on the stack, outermost first, followed by the faulting PC.
+    StackOverflow { pc: u16, depth: usize, call_trace: Vec<u16> },
+    /// 00EE with an empty stack.
+    StackUnderflow { pc: u16 },
+    UnknownOpcode { pc: u16, opcode: OpCode }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StackOverflow { pc, depth, call_trace } => {
+                write!(f, "Stack overflow at 0x{:03X} (depth {}), call trace:", pc, depth)?;
+                for addr in call_trace {
+                    write!(f, " 0x{:03X}", addr)?;
+                }
+                Ok(())
+            },
+            Fault::StackUnderflow { pc } => write!(f, "Stack underflow at 0x{:03X}: return with an empty stack", pc),
+            Fault::UnknownOpcode { pc, opcode } => write!(f, "Unknown opcode: {:X} at 0x{:03X}", opcode, pc)
+        }
+    }
+}
+
+/// The state of a CHIP-8 system. It's changed in place by `step`; clone it
+/// to keep a snapshot.
+#[derive(Clone, PartialEq)]
+pub struct Machine {
+   pub memory: [u8; MEMORY_SIZE],
+   pub gfx: Display,
+   pub v: [u8; 16],
+   pub stack: [u16; STACK_SIZE],
+   pub key: [bool; 16],
+   
+   pub i: u16,
+   pub pc: u16,
+   pub sp: u8,
+   pub delay_timer: u8,
+   pub sound_timer: u8,
+   // the display changed since a frontend last took it, see `take_frame`
+   pub draw_flag: bool,
+   pub await_keypress: bool,
+   pub keypress_register: u8,
+   // key pressed during an FX0A wait that still has to be released
+   pub awaited_key: Option<u8>,
+   // keypad state at the previous FX0A poll, to spot new presses
+   pub wait_keys: [bool; 16],
+   // xorshift state for CXNN, kept here so a seeded run repeats exactly
+   pub rng_state: u64,
+
+   pub config: Config
+}
+
+/// Hooks the core calls as a program runs, so debuggers, tracers and
+/// profilers can live outside it. Every hook does nothing by default, and
+/// `()` implements none of them, so running without an observer compiles
+/// the calls away.
+pub trait Observer {
+    /// An instruction is about to run on `machine`.
+    fn fetch(&mut self, _machine: &Machine, _opcode: OpCode) {}
+    /// An instruction read memory other than its own opcode.
+    fn memory_read(&mut self, _address: u16, _value: u8) {}
+    fn memory_write(&mut self, _address: u16, _value: u8) {}
+    /// VX was written, even with the value it already held.
+    fn register_write(&mut self, _register: u8, _value: u8) {}
+    /// A sprite was drawn at (x, y), after wrapping; `collision` is the new VF.
+    fn draw(&mut self, _x: u8, _y: u8, _height: u8, _collision: bool) {}
+    /// The delay or sound timer was set or ticked.
+    fn timers(&mut self, _delay: u8, _sound: u8) {}
+    /// The buzzer turned on or off.
+    fn sound(&mut self, _playing: bool) {}
+    /// Something the observer couldn't do, such as write its trace, for a
+    /// frontend to show while the program runs. Each is handed out once.
+    fn take_notice(&mut self) -> Option<String> {
+        None
+    }
+    /// The machine was reset or replaced, so the call stack and anything
+    /// else followed from one instruction to the next starts over.
+    fn reset(&mut self) {}
+}
+
+impl Observer for () {}
+
+impl<O: Observer> Observer for Option<O> {
+    fn fetch(&mut self, machine: &Machine, opcode: OpCode) {
+        if let Some(observer) = self { observer.fetch(machine, opcode) }
+    }
+    fn memory_read(&mut self, address: u16, value: u8) {
+        if let Some(observer) = self { observer.memory_read(address, value) }
+    }
+    fn memory_write(&mut self, address: u16, value: u8) {
+        if let Some(observer) = self { observer.memory_write(address, value) }
+    }
+    fn register_write(&mut self, register: u8, value: u8) {
+        if let Some(observer) = self { observer.register_write(register, value) }
+    }
+    fn draw(&mut self, x: u8, y: u8, height: u8, collision: bool) {
+        if let Some(observer) = self { observer.draw(x, y, height, collision) }
+    }
+    fn timers(&mut self, delay: u8, sound: u8) {
+        if let Some(observer) = self { observer.timers(delay, sound) }
+    }
+    fn sound(&mut self, playing: bool) {
+        if let Some(observer) = self { observer.sound(playing) }
+    }
+    fn take_notice(&mut self) -> Option<String> {
+        self.as_mut().and_then(|observer| observer.take_notice())
+    }
+    fn reset(&mut self) {
+        if let Some(observer) = self { observer.reset() }
+    }
+}
+
+impl<O: Observer + ?Sized> Observer for &mut O {
+    fn fetch(&mut self, machine: &Machine, opcode: OpCode) {
+        (**self).fetch(machine, opcode)
+    }
+    fn memory_read(&mut self, address: u16, value: u8) {
+        (**self).memory_read(address, value)
+    }
+    fn memory_write(&mut self, address: u16, value: u8) {
+        (**self).memory_write(address, value)
+    }
+    fn register_write(&mut self, register: u8, value: u8) {
+        (**self).register_write(register, value)
+    }
+    fn draw(&mut self, x: u8, y: u8, height: u8, collision: bool) {
+        (**self).draw(x, y, height, collision)
+    }
+    fn timers(&mut self, delay: u8, sound: u8) {
+        (**self).timers(delay, sound)
+    }
+    fn sound(&mut self, playing: bool) {
+        (**self).sound(playing)
+    }
+    fn take_notice(&mut self) -> Option<String> {
+        (**self).take_notice()
+    }
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+}
+
+/// Two observers watching the same run.
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn fetch(&mut self, machine: &Machine, opcode: OpCode) {
+        self.0.fetch(machine, opcode);
+        self.1.fetch(machine, opcode);
+    }
+    fn memory_read(&mut self, address: u16, value: u8) {
+        self.0.memory_read(address, value);
+        self.1.memory_read(address, value);
+    }
+    fn memory_write(&mut self, address: u16, value: u8) {
+        self.0.memory_write(address, value);
+        self.1.memory_write(address, value);
+    }
+    fn register_write(&mut self, register: u8, value: u8) {
+        self.0.register_write(register, value);
+        self.1.register_write(register, value);
+    }
+    fn draw(&mut self, x: u8, y: u8, height: u8, collision: bool) {
+        self.0.draw(x, y, height, collision);
+        self.1.draw(x, y, height, collision);
+    }
+    fn timers(&mut self, delay: u8, sound: u8) {
+        self.0.timers(delay, sound);
+        self.1.timers(delay, sound);
+    }
+    fn sound(&mut self, playing: bool) {
+        self.0.sound(playing);
+        self.1.sound(playing);
+    }
+    /// The second observer's notice waits for the next call if both have one.
+    fn take_notice(&mut self) -> Option<String> {
+        self.0.take_notice().or_else(|| self.1.take_notice())
+    }
+    fn reset(&mut self) {
+        self.0.reset();
+        self.1.reset();
+    }
+}
+
+const CHIP8_FONTSET: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+];
+
+
+impl Machine {
+    /// A machine with the font loaded and nothing else, starting at the
+    /// configured load address.
+    ///
+    /// Panics if `config` doesn't validate; `start` returns an error instead.
+    pub fn new(config: Config) -> Machine {
+        if let Err(err) = config.validate() {
+            panic!("invalid config: {}", err);
+        }
+        let mut machine = Machine {
+            memory: [0; MEMORY_SIZE],
+            gfx: Display::new(),
+            v: [0; 16],
+            stack: [0; STACK_SIZE],
+            key: [false; 16],
+
+            i: 0,
+            pc: config.load_address,
+            sp: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            draw_flag: true,
+            await_keypress: false,
+            keypress_register: 0,
+            awaited_key: None,
+            wait_keys: [false; 16],
+            rng_state: rng_state_from_seed(rand::random()),
+
+            config
+        };
+
+        machine.memory[..80].copy_from_slice(&CHIP8_FONTSET);
+        machine
+    }
+
+    /// Builds a fresh machine running `rom`, seeding its random numbers if asked.
+    pub fn start(rom: loader::Rom, config: Config, seed: Option<u64>) -> Result<Machine, loader::LoadError> {
+        config.validate().map_err(loader::LoadError::Config)?;
+        let rom = loader::relocate(rom, &config)?;
+        let mut machine = Machine::new(config);
+        if let Some(seed) = seed {
+            machine.seed(seed);
+        }
+        machine.load_program(&rom.data, rom.origin);
+        machine.pc = rom.entry;
+        Ok(machine)
+    }
+
+    /// Restarts the random number generator from `seed`, so CXNN repeats
+    /// the same sequence.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng_state = rng_state_from_seed(seed);
+    }
+
+    /// Copies a program into memory at `origin`; the loader checks that it fits.
+    pub fn load_program(&mut self, program: &[u8], origin: u16) {
+        let start = origin as usize;
+        self.memory[start..start + program.len()].copy_from_slice(program);
+    }
+
+    /// Writes a byte from outside the program, as a debugger does.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.poke_with(address, value, &mut ());
+    }
+
+    /// Writes a byte like `poke`, telling `observer` about the write so a
+    /// `DecodeCache` or `Jit` kept for this machine drops what it overlaps.
+    pub fn poke_with<O: Observer>(&mut self, address: u16, value: u8, observer: &mut O) {
+        self.memory[address as usize] = value;
+        observer.memory_write(address, value);
+    }
+
+    /// The instruction at PC.
+    pub fn opcode(&self) -> OpCode {
+        let pc = self.pc as usize;
+        (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16
+    }
+
+    /// Runs one instruction. On a fault the machine is left as it was.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        self.step_with(&mut ())
+    }
+
+    /// Runs one instruction like `step`, telling `observer` what it does.
+    pub fn step_with<O: Observer>(&mut self, observer: &mut O) -> Result<(), Fault> {
+        let opcode = self.opcode();
+        observer.fetch(self, opcode);
+        self.execute(opcode, decode(opcode), observer)
+    }
+
+    /// Runs one instruction like `step_with`, taking it from `cache` rather
+    /// than decoding it again. The cache must only be used with this machine,
+    /// and told about changes made to memory from outside (see `DecodeCache`).
+    pub fn step_cached<O: Observer>(&mut self, cache: &mut DecodeCache, observer: &mut O) -> Result<(), Fault> {
+        let (opcode, instruction) = cache.get(self);
+        observer.fetch(self, opcode);
+        self.execute(opcode, instruction, &mut (cache, observer))
+    }
+
+    /// Runs `opcode` as if it were the instruction at PC, for recompiled
+    /// programs handing back the instructions they don't translate.
+    pub fn run_opcode(&mut self, opcode: OpCode) -> Result<(), Fault> {
+        self.execute(opcode, decode(opcode), &mut ())
+    }
+
+    fn execute<O: Observer>(&mut self, opcode: OpCode, instruction: Instruction, observer: &mut O) -> Result<(), Fault> {
+        let pc = self.pc;
+        match instruction {
+            Instruction::Clear => {
+                self.gfx.clear();
+                self.draw_flag = true;
+                self.pc += 2;
+            },
+            Instruction::Return => {
+                if self.sp == 0 {
+                    return Err(Fault::StackUnderflow { pc });
+                }
+                self.sp -= 1;
+                self.pc = self.stack[self.sp as usize];
+                self.pc += 2;
+            },
+            Instruction::Sys => {
+                self.pc += 2;
+            },
+            Instruction::Jump(address) => {
+                self.pc = address;
+            },
+            Instruction::Call(address) => {
+                let depth = self.config.stack_depth;
+                if self.sp as usize >= depth {
+                    let mut call_trace = self.stack[..depth].to_vec();
+                    call_trace.push(pc);
+                    return Err(Fault::StackOverflow { pc, depth, call_trace });
+                }
+                self.stack[self.sp as usize] = self.pc;
+                self.sp += 1;
+                self.pc = address;
+            },
+            Instruction::SkipIfEqual { x, nn } => {
+                self.skip_if(self.v[x as usize] == nn);
+            },
+            Instruction::SkipIfNotEqual { x, nn } => {
+                self.skip_if(self.v[x as usize] != nn);
+            },
+            Instruction::SkipIfRegistersEqual { x, y } => {
+                self.skip_if(self.v[x as usize] == self.v[y as usize]);
+            },
+            Instruction::Load { x, nn } => {
+                self.set_v(x, nn, observer);
+                self.pc += 2;
+            },
+            Instruction::Add { x, nn } => {
+                self.set_v(x, self.v[x as usize].wrapping_add(nn), observer);
+                self.pc += 2;
+            },
+            Instruction::Copy { x, y } => {
+                self.set_v(x, self.v[y as usize], observer);
+                self.pc += 2;
+            },
+            Instruction::Or { x, y } => {
+                self.set_v(x, self.v[x as usize] | self.v[y as usize], observer);
+                self.reset_vf(observer);
+                self.pc += 2;
+            },
+            Instruction::And { x, y } => {
+                self.set_v(x, self.v[x as usize] & self.v[y as usize], observer);
+                self.reset_vf(observer);
+                self.pc += 2;
+            },
+            Instruction::Xor { x, y } => {
+                self.set_v(x, self.v[x as usize] ^ self.v[y as usize], observer);
+                self.reset_vf(observer);
+                self.pc += 2;
+            },
+            Instruction::AddRegisters { x, y } => {
+                let (val, has_overflow) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+                self.set_v(x, val, observer);
+                self.set_v(0xF, has_overflow as u8, observer);
+                self.pc += 2;
+            },
+            Instruction::Sub { x, y } => {
+                let (val, has_overflow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+                self.set_v(x, val, observer);
+                self.set_v(0xF, !has_overflow as u8, observer);
+                self.pc += 2;
+            },
+            Instruction::ShiftRight { x, y } => {
+                let src = if self.config.shift_uses_vy { self.v[y as usize] } else { self.v[x as usize] };
+                self.set_v(x, src >> 1, observer);
+                self.set_v(0xF, src & 0x01, observer);
+                self.pc += 2;
+            },
+            Instruction::SubReversed { x, y } => {
+                let (val, has_overflow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+                self.set_v(x, val, observer);
+                self.set_v(0xF, !has_overflow as u8, observer);
+                self.pc += 2;
+            },
+            Instruction::ShiftLeft { x, y } => {
+                let src = if self.config.shift_uses_vy { self.v[y as usize] } else { self.v[x as usize] };
+                self.set_v(x, src << 1, observer);
+                self.set_v(0xF, (src & 0x80) >> 7, observer);
+                self.pc += 2;
+            },
+            Instruction::SkipIfRegistersDiffer { x, y } => {
+                self.skip_if(self.v[x as usize] != self.v[y as usize]);
+            },
+            Instruction::LoadIndex(address) => {
+                self.i = address;
+                self.pc += 2;
+            },
+            Instruction::JumpOffset(address) => {
+                self.pc = address + self.v[0] as u16;
+            },
+            Instruction::Random { x, mask } => {
+                let rand_val = next_random(&mut self.rng_state);
+                self.set_v(x, rand_val & mask, observer);
+                self.pc += 2;
+            },
+            Instruction::Draw { x, y, height } => {
+                let x = self.v[x as usize] as usize % display::WIDTH;
+                let y = self.v[y as usize] as usize % display::HEIGHT;
+
+                // rows below the bottom edge are clipped, so they aren't read
+                let visible = (height as usize).min(display::HEIGHT - y);
+                let i = self.i as usize;
+                let sprite = &self.memory[i..i + visible];
+                for (n, &row) in sprite.iter().enumerate() {
+                    observer.memory_read((i + n) as u16, row);
+                }
+
+                let collision = self.gfx.draw_sprite(x, y, sprite);
+                self.set_v(0xF, collision as u8, observer);
+                observer.draw(x as u8, y as u8, height, collision);
+                self.draw_flag = true;
+                self.pc += 2;
+            },
+            Instruction::SkipIfKey { x } => {
+                self.skip_if(self.key[self.v[x as usize] as usize]);
+            },
+            Instruction::SkipUnlessKey { x } => {
+                self.skip_if(!self.key[self.v[x as usize] as usize]);
+            },
+            Instruction::LoadDelay { x } => {
+                self.set_v(x, self.delay_timer, observer);
+                self.pc += 2;
+            },
+            Instruction::WaitKey { x } => {
+                // pc stays on FX0A, which is polled every cycle until a key
+                // that was not already held is pressed (and released)
+                let mut completed_key = None;
+                if !self.await_keypress {
+                    self.await_keypress = true;
+                    self.keypress_register = x;
+                    self.awaited_key = None;
+                } else if let Some(k) = self.awaited_key {
+                    if !self.key[k as usize] {
+                        completed_key = Some(k);
+                    }
+                } else if let Some(k) = (0..16).find(|&k| self.key[k] && !self.wait_keys[k]) {
+                    if self.config.key_wait_release {
+                        self.awaited_key = Some(k as u8);
+                    } else {
+                        completed_key = Some(k as u8);
+                    }
+                }
+                self.wait_keys = self.key;
+
+                if let Some(k) = completed_key {
+                    self.set_v(x, k, observer);
+                    self.await_keypress = false;
+                    self.awaited_key = None;
+                    self.pc += 2;
+                }
+            },
+            Instruction::SetDelay { x } => {
+                self.delay_timer = self.v[x as usize];
+                observer.timers(self.delay_timer, self.sound_timer);
+                self.pc += 2;
+            },
+            Instruction::SetSound { x } => {
+                let was_playing = self.sound_timer > 0;
+                self.sound_timer = self.v[x as usize];
+                observer.timers(self.delay_timer, self.sound_timer);
+                if was_playing != (self.sound_timer > 0) {
+                    observer.sound(self.sound_timer > 0);
+                }
+                self.pc += 2;
+            },
+            Instruction::AddIndex { x } => {
+                let (val, has_overflow) = self.i.overflowing_add(self.v[x as usize] as u16);
+                self.i = val;
+                self.set_v(0xF, has_overflow as u8, observer);
+                self.pc += 2;
+            },
+            Instruction::LoadFont { x } => {
+                self.i = self.v[x as usize] as u16 * 5;
+                self.pc += 2;
+            },
+            Instruction::StoreBcd { x } => {
+                let value = self.v[x as usize];
+                let digits = [value / 100, (value / 10) % 10, value % 10];
+                for (offset, &digit) in digits.iter().enumerate() {
+                    self.write_memory(self.i + offset as u16, digit, observer);
+                }
+                self.pc += 2;
+            },
+            Instruction::StoreRegisters { x } => {
+                for offset in 0..=x {
+                    self.write_memory(self.i + offset as u16, self.v[offset as usize], observer);
+                }
+                self.i += x as u16 + 1;
+                self.pc += 2;
+            },
+            Instruction::LoadRegisters { x } => {
+                for offset in 0..=x {
+                    let address = self.i + offset as u16;
+                    let value = self.memory[address as usize];
+                    observer.memory_read(address, value);
+                    self.set_v(offset, value, observer);
+                }
+                self.i += x as u16 + 1;
+                self.pc += 2;
+            },
+            Instruction::Unknown => {
+                return Err(Fault::UnknownOpcode { pc, opcode });
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips the next instruction if `condition` holds.
+    fn skip_if(&mut self, condition: bool) {
+        self.pc += if condition { 4 } else { 2 };
+    }
+
+    fn set_v<O: Observer>(&mut self, x: u8, value: u8, observer: &mut O) {
+        self.v[x as usize] = value;
+        observer.register_write(x, value);
+    }
+
+    /// The COSMAC VIP's logic ops leave VF cleared.
+    fn reset_vf<O: Observer>(&mut self, observer: &mut O) {
+        if self.config.vf_reset {
+            self.set_v(0xF, 0, observer);
+        }
+    }
+
+    fn write_memory<O: Observer>(&mut self, address: u16, value: u8, observer: &mut O) {
+        self.memory[address as usize] = value;
+        observer.memory_write(address, value);
+    }
+
+    /// The display, if it changed since the last call: drawn to, cleared or
+    /// reset, or never taken yet. Frontends can skip redrawing otherwise.
+    pub fn take_frame(&mut self) -> Option<&Display> {
+        if std::mem::replace(&mut self.draw_flag, false) {
+            Some(&self.gfx)
+        } else {
+            None
+        }
+    }
+
+    /// Counts both timers down by one; call at 60 Hz, including while FX0A waits.
+    pub fn tick_timers(&mut self) {
+        self.tick_timers_with(&mut ())
+    }
+
+    pub fn tick_timers_with<O: Observer>(&mut self, observer: &mut O) {
+        let before = (self.delay_timer, self.sound_timer);
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+        if (self.delay_timer, self.sound_timer) != before {
+            observer.timers(self.delay_timer, self.sound_timer);
+        }
+        if before.1 == 1 {
+            observer.sound(false);
+        }
+    }
+
+    /// Restarts the program at `entry` without reloading it. Registers, stack,
+    /// timers, any FX0A wait and the display are cleared; memory is kept, so
+    /// self-modified code and saved data survive as they would on hardware.
+    pub fn soft_reset(&mut self, entry: u16) {
+        self.v = [0; 16];
+        self.i = 0;
+        self.pc = entry;
+        self.sp = 0;
+        self.stack = [0; STACK_SIZE];
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.await_keypress = false;
+        self.awaited_key = None;
+        self.wait_keys = [false; 16];
+        self.gfx.clear();
+        self.draw_flag = true;
+    }
+}
+
+/// Spreads a seed into a non-zero xorshift state, so small seeds still
+/// produce well-mixed sequences.
+fn rng_state_from_seed(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)) | 1
+}
+
+/// Advances the xorshift64* generator and returns its top byte.
+fn next_random(state: &mut u64) -> u8 {
+    let mut x = *state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tiny DSL for single-instruction tests: describe the machine with
+    // `given()`, run one opcode with `exec`, then chain assertions on the
+    // resulting state.
+    struct Given {
+        machine: Machine
+    }
+
+    struct Outcome {
+        before: Machine,
+        after: Machine
+    }
+
+    fn given() -> Given {
+        let mut machine = Machine::new(Config::default());
+        // start with the display shown, so `draw_flag` checks see the instruction's doing
+        machine.take_frame();
+        Given { machine }
+    }
+
+    impl Given {
+        fn v(mut self, x: usize, val: u8) -> Given {
+            self.machine.v[x] = val;
+            self
+        }
+
+        fn i(mut self, val: u16) -> Given {
+            self.machine.i = val;
+            self
+        }
+
+        fn pc(mut self, val: u16) -> Given {
+            self.machine.pc = val;
+            self
+        }
+
+        fn call_stack(mut self, frames: &[u16]) -> Given {
+            self.machine.stack[..frames.len()].copy_from_slice(frames);
+            self.machine.sp = frames.len() as u8;
+            self
+        }
+
+        fn mem(mut self, addr: usize, bytes: &[u8]) -> Given {
+            self.machine.memory[addr..addr + bytes.len()].copy_from_slice(bytes);
+            self
+        }
+
+        fn key(mut self, k: usize) -> Given {
+            self.machine.key[k] = true;
+            self
+        }
+
+        fn delay_timer(mut self, val: u8) -> Given {
+            self.machine.delay_timer = val;
+            self
+        }
+
+        fn pixel(mut self, x: usize, y: usize) -> Given {
+            self.machine.gfx.set(x, y, true);
+            self
+        }
+
+        fn config(mut self, config: Config) -> Given {
+            self.machine.config = config;
+            self
+        }
+
+        fn with_opcode(self, opcode: OpCode) -> Machine {
+            let mut machine = self.machine;
+            let pc = machine.pc as usize;
+            machine.memory[pc] = (opcode >> 8) as u8;
+            machine.memory[pc+1] = (opcode & 0xFF) as u8;
+            machine
+        }
+
+        fn exec(self, opcode: OpCode) -> Outcome {
+            let before = self.with_opcode(opcode);
+            let mut after = before.clone();
+            match after.step() {
+                Ok(()) => Outcome { before, after },
+                Err(fault) => panic!("unexpected fault: {}", fault)
+            }
+        }
+
+        fn fault(self, opcode: OpCode) -> Fault {
+            let mut machine = self.with_opcode(opcode);
+            let before = machine.clone();
+            match machine.step() {
+                Ok(()) => panic!("expected a fault"),
+                Err(fault) => {
+                    assert_eq!(machine.pc, before.pc, "a fault leaves the machine as it was");
+                    fault
+                }
+            }
+        }
+    }
+
+    impl Outcome {
+        fn v(&self, x: usize, expected: u8) -> &Outcome {
+            assert_eq!(self.after.v[x], expected, "V{:X}", x);
+            self
+        }
+
+        fn vf(&self, expected: u8) -> &Outcome {
+            self.v(0xF, expected)
+        }
+
+        fn i(&self, expected: u16) -> &Outcome {
+            assert_eq!(self.after.i, expected, "I");
+            self
+        }
+
+        fn pc(&self, expected: u16) -> &Outcome {
+            assert_eq!(self.after.pc, expected, "PC");
+            self
+        }
+
+        fn advanced(&self) -> &Outcome {
+            self.pc(self.before.pc + 2)
+        }
+
+        fn skipped(&self) -> &Outcome {
+            self.pc(self.before.pc + 4)
+        }
+
+        fn sp(&self, expected: u8) -> &Outcome {
+            assert_eq!(self.after.sp, expected, "SP");
+            self
+        }
+
+        fn stack(&self, index: usize, expected: u16) -> &Outcome {
+            assert_eq!(self.after.stack[index], expected, "stack[{}]", index);
+            self
+        }
+
+        fn mem(&self, addr: usize, expected: &[u8]) -> &Outcome {
+            assert_eq!(&self.after.memory[addr..addr + expected.len()], expected, "memory at 0x{:03X}", addr);
+            self
+        }
+
+        fn delay_timer(&self, expected: u8) -> &Outcome {
+            assert_eq!(self.after.delay_timer, expected, "delay timer");
+            self
+        }
+
+        fn sound_timer(&self, expected: u8) -> &Outcome {
+            assert_eq!(self.after.sound_timer, expected, "sound timer");
+            self
+        }
+
+        fn pixel(&self, x: usize, y: usize, expected: bool) -> &Outcome {
+            assert_eq!(self.after.gfx.get(x, y), expected, "pixel ({}, {})", x, y);
+            self
+        }
+
+        fn lit_pixels(&self, expected: usize) -> &Outcome {
+            assert_eq!(self.after.gfx.lit_count(), expected, "lit pixels");
+            self
+        }
+
+        fn draw_flag(&self, expected: bool) -> &Outcome {
+            assert_eq!(self.after.draw_flag, expected, "draw flag");
+            self
+        }
+
+        fn awaiting_key_into(&self, x: u8) -> &Outcome {
+            assert!(self.after.await_keypress, "expected to be waiting for a key");
+            assert_eq!(self.after.keypress_register, x, "key register");
+            self
+        }
+    }
+
+    #[test]
+    fn op_00e0_clears_screen() {
+        given().pixel(0, 0).pixel(63, 31).exec(0x00E0)
+            .lit_pixels(0).draw_flag(true).advanced();
+    }
+
+    #[test]
+    fn op_00ee_returns_from_subroutine() {
+        given().call_stack(&[0x200, 0x340]).pc(0x600).exec(0x00EE)
+            .sp(1).pc(0x342);
+    }
+
+    #[test]
+    fn op_0nnn_is_ignored() {
+        given().v(0, 7).exec(0x0123).v(0, 7).advanced();
+    }
+
+    #[test]
+    fn op_1nnn_jumps() {
+        given().exec(0x1ABC).pc(0xABC);
+    }
+
+    #[test]
+    fn op_2nnn_calls_subroutine() {
+        given().pc(0x204).exec(0x2456).sp(1).stack(0, 0x204).pc(0x456);
+    }
+
+    #[test]
+    fn op_2nnn_faults_when_stack_is_full() {
+        let frames: Vec<u16> = (0..16).map(|n| 0x200 + n*2).collect();
+        let fault = given().call_stack(&frames).pc(0x300).fault(0x2400);
+        let mut call_trace = frames;
+        call_trace.push(0x300);
+        assert_eq!(fault, Fault::StackOverflow { pc: 0x300, depth: 16, call_trace });
+    }
+
+    #[test]
+    fn op_2nnn_respects_configured_stack_depth() {
+        let vip = Config { stack_depth: 12, ..Config::default() };
+        let frames: Vec<u16> = (0..11).map(|n| 0x200 + n*2).collect();
+        given().config(vip).call_stack(&frames).exec(0x2400).sp(12);
+
+        let frames: Vec<u16> = (0..12).map(|n| 0x200 + n*2).collect();
+        match given().config(vip).call_stack(&frames).fault(0x2400) {
+            Fault::StackOverflow { depth, call_trace, .. } => {
+                assert_eq!(depth, 12);
+                assert_eq!(call_trace.len(), 13);
+            },
+            fault => panic!("unexpected fault: {}", fault)
+        }
+    }
+
+    #[test]
+    fn rejects_a_stack_deeper_than_the_stack() {
+        let deep = Config { stack_depth: 17, ..Config::default() };
+        assert_eq!(deep.validate(), Err(ConfigError::StackTooDeep(17)));
+        let rom = loader::validate(vec![0x12, 0x00], &Config::default()).unwrap();
+        match Machine::start(rom, deep, None) {
+            Err(loader::LoadError::Config(ConfigError::StackTooDeep(17))) => (),
+            other => panic!("unexpected result: {:?}", other.map(|machine| machine.pc))
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "stack depth 17")]
+    fn new_panics_on_an_invalid_config() {
+        Machine::new(Config { stack_depth: 17, ..Config::default() });
+    }
+
+    #[test]
+    fn op_00ee_faults_on_empty_stack() {
+        assert_eq!(given().pc(0x240).fault(0x00EE), Fault::StackUnderflow { pc: 0x240 });
+    }
+
+    #[test]
+    fn unknown_opcodes_fault() {
+        assert_eq!(given().fault(0x8008), Fault::UnknownOpcode { pc: 0x200, opcode: 0x8008 });
+        assert_eq!(given().fault(0xE000), Fault::UnknownOpcode { pc: 0x200, opcode: 0xE000 });
+        assert_eq!(given().fault(0xF0FF), Fault::UnknownOpcode { pc: 0x200, opcode: 0xF0FF });
+    }
+
+    #[test]
+    fn op_3xnn_skips_if_equal() {
+        given().v(3, 0x42).exec(0x3342).skipped();
+        given().v(3, 0x41).exec(0x3342).advanced();
+    }
+
+    #[test]
+    fn op_4xnn_skips_if_not_equal() {
+        given().v(4, 0x41).exec(0x4442).skipped();
+        given().v(4, 0x42).exec(0x4442).advanced();
+    }
+
+    #[test]
+    fn op_5xy0_skips_if_registers_equal() {
+        given().v(1, 9).v(2, 9).exec(0x5120).skipped();
+        given().v(1, 9).v(2, 8).exec(0x5120).advanced();
+    }
+
+    #[test]
+    fn op_6xnn_loads_immediate() {
+        given().exec(0x6A5C).v(0xA, 0x5C).advanced();
+    }
+
+    #[test]
+    fn op_7xnn_adds_without_carry() {
+        given().v(2, 0x10).exec(0x7205).v(2, 0x15).advanced();
+        given().v(2, 0xFF).v(0xF, 7).exec(0x7202).v(2, 0x01).vf(7);
+    }
+
+    #[test]
+    fn op_8xy0_copies() {
+        given().v(2, 0x33).exec(0x8120).v(1, 0x33).v(2, 0x33).advanced();
+    }
+
+    #[test]
+    fn op_8xy1_8xy2_8xy3_bitwise() {
+        given().v(1, 0b1100).v(2, 0b1010).exec(0x8121).v(1, 0b1110).advanced();
+        given().v(1, 0b1100).v(2, 0b1010).exec(0x8122).v(1, 0b1000).advanced();
+        given().v(1, 0b1100).v(2, 0b1010).exec(0x8123).v(1, 0b0110).advanced();
+    }
+
+    #[test]
+    fn op_8xy4_adds_with_carry() {
+        given().v(1, 0x10).v(2, 0x20).v(0xF, 9).exec(0x8124).v(1, 0x30).vf(0).advanced();
+        given().v(1, 0xF0).v(2, 0x20).exec(0x8124).v(1, 0x10).vf(1).advanced();
+    }
+
+    #[test]
+    fn op_8xy5_subtracts_with_not_borrow() {
+        given().v(1, 0x30).v(2, 0x10).exec(0x8125).v(1, 0x20).vf(1).advanced();
+        given().v(1, 0x10).v(2, 0x30).exec(0x8125).v(1, 0xE0).vf(0).advanced();
+        given().v(1, 0x10).v(2, 0x10).exec(0x8125).v(1, 0x00).vf(1);
+    }
+
+    #[test]
+    fn op_8xy6_shifts_right() {
+        given().v(1, 0b0000_0101).exec(0x8126).v(1, 0b0000_0010).vf(1).advanced();
+        given().v(1, 0b0000_0100).exec(0x8126).v(1, 0b0000_0010).vf(0).advanced();
+    }
+
+    #[test]
+    fn op_8xy7_subtracts_reversed_with_not_borrow() {
+        given().v(1, 0x10).v(2, 0x30).exec(0x8127).v(1, 0x20).vf(1).advanced();
+        given().v(1, 0x30).v(2, 0x10).exec(0x8127).v(1, 0xE0).vf(0).advanced();
+    }
+
+    #[test]
+    fn op_8xye_shifts_left() {
+        given().v(1, 0b1000_0001).exec(0x812E).v(1, 0b0000_0010).vf(1).advanced();
+        given().v(1, 0b0100_0001).exec(0x812E).v(1, 0b1000_0010).vf(0).advanced();
+    }
+
+    #[test]
+    fn alu_flag_is_written_after_result_when_x_is_f() {
+        given().v(0xF, 0xF0).v(1, 0x20).exec(0x8F14).vf(1);
+        given().v(0xF, 0x30).v(1, 0x10).exec(0x8F15).vf(1);
+        given().v(0xF, 0b10).exec(0x8F16).vf(0);
+        given().v(0xF, 0b11).exec(0x8F16).vf(1);
+        given().v(0xF, 0x30).v(1, 0x10).exec(0x8F17).vf(0);
+        given().v(0xF, 0x81).exec(0x8F1E).vf(1);
+        given().v(0xF, 0x41).exec(0x8F1E).vf(0);
+    }
+
+    #[test]
+    fn alu_reads_vf_as_operand_when_y_is_f() {
+        given().v(1, 0xF0).v(0xF, 0x20).exec(0x81F4).v(1, 0x10).vf(1);
+        given().v(1, 0x10).v(0xF, 0x30).exec(0x81F5).v(1, 0xE0).vf(0);
+        given().v(1, 0x10).v(0xF, 0x30).exec(0x81F7).v(1, 0x20).vf(1);
+        given().v(1, 0x04).v(0xF, 0x03).exec(0x81F6).v(1, 0x02).vf(0);
+        given().v(1, 0x40).v(0xF, 0x03).exec(0x81FE).v(1, 0x80).vf(0);
+    }
+
+    #[test]
+    fn alu_flag_wins_when_x_and_y_are_f() {
+        given().v(0xF, 0x80).exec(0x8FF4).vf(1);
+        given().v(0xF, 0x80).exec(0x8FF5).vf(1);
+        given().v(0xF, 0x80).exec(0x8FF7).vf(1);
+        given().v(0xF, 0x80).exec(0x8FFE).vf(1);
+        given().v(0xF, 0x80).exec(0x8FF6).vf(0);
+    }
+
+    #[test]
+    fn quirk_vf_reset_clears_flag_after_logic_ops() {
+        let vip = Config { vf_reset: true, ..Config::default() };
+        given().config(vip).v(1, 0b1100).v(2, 0b1010).v(0xF, 5).exec(0x8121).v(1, 0b1110).vf(0);
+        given().config(vip).v(1, 0b1100).v(2, 0b1010).v(0xF, 5).exec(0x8122).v(1, 0b1000).vf(0);
+        given().config(vip).v(1, 0b1100).v(2, 0b1010).v(0xF, 5).exec(0x8123).v(1, 0b0110).vf(0);
+        given().config(vip).v(0xF, 0x0F).v(1, 0xF0).exec(0x8F11).vf(0);
+        given().v(1, 0b1100).v(2, 0b1010).v(0xF, 5).exec(0x8121).vf(5);
+    }
+
+    #[test]
+    fn quirk_shift_uses_vy() {
+        let vip = Config { shift_uses_vy: true, ..Config::default() };
+        given().config(vip).v(1, 0xFF).v(2, 0b0000_0011).exec(0x8126).v(1, 0b0000_0001).v(2, 0b0000_0011).vf(1);
+        given().config(vip).v(1, 0xFF).v(2, 0b0100_0000).exec(0x812E).v(1, 0b1000_0000).vf(0);
+        given().config(vip).v(0xF, 0b10).v(2, 0b01).exec(0x8F26).vf(1);
+        given().config(vip).v(1, 0x00).v(0xF, 0x03).exec(0x81F6).v(1, 0x01).vf(1);
+    }
+
+    #[test]
+    fn op_9xy0_skips_if_registers_differ() {
+        given().v(1, 9).v(2, 8).exec(0x9120).skipped();
+        given().v(1, 9).v(2, 9).exec(0x9120).advanced();
+    }
+
+    #[test]
+    fn op_annn_loads_index() {
+        given().exec(0xA123).i(0x123).advanced();
+    }
+
+    #[test]
+    fn op_bnnn_jumps_with_offset() {
+        given().v(0, 0x10).exec(0xB300).pc(0x310);
+    }
+
+    #[test]
+    fn op_cxnn_masks_random_value() {
+        given().v(5, 0xFF).exec(0xC500).v(5, 0).advanced();
+        for _ in 0..32 {
+            let outcome = given().exec(0xC50F);
+            assert_eq!(outcome.after.v[5] & 0xF0, 0);
+        }
+    }
+
+    #[test]
+    fn op_cxnn_repeats_with_the_same_seed() {
+        let run = |seed| {
+            let mut machine = given().exec(0x6000).after;
+            machine.rng_state = rng_state_from_seed(seed);
+            (0..8).map(|_| {
+                machine.pc = 0x200;
+                machine.memory[0x200] = 0xC5;
+                machine.memory[0x201] = 0xFF;
+                machine.step().unwrap();
+                machine.v[5]
+            }).collect::<Vec<u8>>()
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn op_dxyn_draws_sprite() {
+        given().v(0, 2).v(1, 3).i(0x300).mem(0x300, &[0b1100_0000, 0b0000_0001]).exec(0xD012)
+            .pixel(2, 3, true).pixel(3, 3, true).pixel(9, 4, true)
+            .lit_pixels(3).vf(0).draw_flag(true).advanced();
+    }
+
+    #[test]
+    fn op_dxyn_reports_collision() {
+        given().v(0, 2).v(1, 3).i(0x300).mem(0x300, &[0b1100_0000]).pixel(3, 3).exec(0xD011)
+            .pixel(2, 3, true).pixel(3, 3, false).vf(1);
+    }
+
+    #[test]
+    fn op_dxyn_wraps_start_position() {
+        given().v(0, 64 + 1).v(1, 32 + 2).i(0x300).mem(0x300, &[0b1000_0000]).exec(0xD011)
+            .pixel(1, 2, true).lit_pixels(1);
+    }
+
+    #[test]
+    fn op_dxyn_clips_at_the_edges() {
+        given().v(0, 62).v(1, 30).i(0x300).mem(0x300, &[0xFF, 0xFF, 0xFF]).exec(0xD013)
+            .pixel(62, 30, true).pixel(63, 31, true).pixel(0, 31, false).pixel(0, 0, false)
+            .lit_pixels(4).vf(0);
+    }
+
+    #[test]
+    fn op_ex9e_skips_if_key_pressed() {
+        given().v(3, 0xA).key(0xA).exec(0xE39E).skipped();
+        given().v(3, 0xA).exec(0xE39E).advanced();
+    }
+
+    #[test]
+    fn op_exa1_skips_if_key_not_pressed() {
+        given().v(3, 0xA).exec(0xE3A1).skipped();
+        given().v(3, 0xA).key(0xA).exec(0xE3A1).advanced();
+    }
+
+    #[test]
+    fn op_fx07_reads_delay_timer() {
+        given().delay_timer(0x2A).exec(0xF407).v(4, 0x2A).advanced();
+    }
+
+    #[test]
+    fn op_fx0a_waits_for_key() {
+        given().exec(0xF70A).awaiting_key_into(7).pc(0x200);
+    }
+
+    #[test]
+    fn op_fx0a_completes_on_release() {
+        let mut machine = given().exec(0xF70A).after;
+        machine.step().unwrap();
+        assert_eq!(machine.pc, 0x200);
+
+        machine.key[0x5] = true;
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert!(machine.await_keypress);
+        assert_eq!(machine.pc, 0x200);
+
+        machine.key[0x5] = false;
+        machine.step().unwrap();
+        assert!(!machine.await_keypress);
+        assert_eq!(machine.v[7], 0x5);
+        assert_eq!(machine.pc, 0x202);
+    }
+
+    #[test]
+    fn op_fx0a_completes_on_press_when_configured() {
+        let config = Config { key_wait_release: false, ..Config::default() };
+        let mut machine = given().config(config).exec(0xF70A).after;
+        machine.key[0xB] = true;
+        machine.step().unwrap();
+        assert!(!machine.await_keypress);
+        assert_eq!(machine.v[7], 0xB);
+        assert_eq!(machine.pc, 0x202);
+    }
+
+    #[test]
+    fn op_fx0a_ignores_keys_held_before_the_wait() {
+        let mut machine = given().key(0x3).exec(0xF70A).after;
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert!(machine.await_keypress);
+
+        machine.key[0x3] = false;
+        machine.step().unwrap();
+        assert!(machine.await_keypress);
+
+        machine.key[0x3] = true;
+        machine.step().unwrap();
+        machine.key[0x3] = false;
+        machine.step().unwrap();
+        assert!(!machine.await_keypress);
+        assert_eq!(machine.v[7], 0x3);
+    }
+
+    #[test]
+    fn timers_keep_running_during_key_wait() {
+        let mut machine = given().delay_timer(10).exec(0xF70A).after;
+        machine.sound_timer = 3;
+        machine.step().unwrap();
+        machine.tick_timers();
+        assert!(machine.await_keypress);
+        assert_eq!(machine.delay_timer, 9);
+        assert_eq!(machine.sound_timer, 2);
+    }
+
+    #[test]
+    fn op_fx15_fx18_set_timers() {
+        given().v(2, 60).exec(0xF215).delay_timer(60).advanced();
+        given().v(2, 30).exec(0xF218).sound_timer(30).advanced();
+    }
+
+    #[test]
+    fn op_fx1e_adds_to_index() {
+        given().i(0x300).v(2, 0x20).exec(0xF21E).i(0x320).advanced();
+    }
+
+    #[test]
+    fn op_fx29_points_at_font_glyph() {
+        given().v(6, 0xA).exec(0xF629).i(50).advanced();
+    }
+
+    #[test]
+    fn op_fx33_stores_bcd() {
+        given().v(3, 254).i(0x400).exec(0xF333).mem(0x400, &[2, 5, 4]).i(0x400).advanced();
+        given().v(3, 7).i(0x400).exec(0xF333).mem(0x400, &[0, 0, 7]);
+        given().v(3, 40).i(0x400).exec(0xF333).mem(0x400, &[0, 4, 0]);
+    }
+
+    #[test]
+    fn op_fx55_stores_registers() {
+        given().v(0, 1).v(1, 2).v(2, 3).v(3, 4).i(0x400).exec(0xF255)
+            .mem(0x400, &[1, 2, 3, 0]).i(0x403).advanced();
+    }
+
+    #[test]
+    fn op_fx65_loads_registers() {
+        given().mem(0x400, &[9, 8, 7, 6]).v(3, 0xEE).i(0x400).exec(0xF265)
+            .v(0, 9).v(1, 8).v(2, 7).v(3, 0xEE).i(0x403).advanced();
+    }
+
+    #[test]
+    fn take_frame_reports_each_change_once() {
+        let mut machine = Machine::new(Config::default());
+        assert!(machine.take_frame().is_some());
+        assert!(machine.take_frame().is_none());
+
+        // LD V0, 1; DRW V0, V0, 1 with I on the top row of the font's 0
+        machine.load_program(&[0x60, 0x01, 0xD0, 0x01], 0x200);
+        machine.step().unwrap();
+        assert!(machine.take_frame().is_none());
+        machine.step().unwrap();
+        assert_eq!(machine.take_frame().map(Display::lit_count), Some(4));
+        assert!(machine.take_frame().is_none());
+    }
+
+    #[test]
+    fn soft_reset_restarts_without_reloading() {
+        let mut machine = given().v(3, 7).i(0x400).pc(0x260).call_stack(&[0x222]).delay_timer(9).pixel(1, 1)
+            .mem(0x300, &[0xAB]).with_opcode(0xF20A);
+        machine.step().unwrap();
+        machine.soft_reset(0x200);
+        assert_eq!(machine.pc, 0x200);
+        assert_eq!(machine.v, [0; 16]);
+        assert_eq!((machine.i, machine.sp, machine.delay_timer), (0, 0, 0));
+        assert!(!machine.await_keypress);
+        assert_eq!(machine.gfx.lit_count(), 0);
+        assert!(machine.draw_flag);
+        assert_eq!(machine.memory[0x300], 0xAB);
+        assert_eq!(machine.memory[0x260..0x262], [0xF2, 0x0A]);
+    }
+
+    /// Writes down every hook call, to check what the core reports.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>
+    }
+
+    impl Observer for Recorder {
+        fn fetch(&mut self, machine: &Machine, opcode: OpCode) {
+            self.events.push(format!("fetch {:03X} {:04X}", machine.pc, opcode));
+        }
+        fn memory_read(&mut self, address: u16, value: u8) {
+            self.events.push(format!("read {:03X} {:02X}", address, value));
+        }
+        fn memory_write(&mut self, address: u16, value: u8) {
+            self.events.push(format!("write {:03X} {:02X}", address, value));
+        }
+        fn register_write(&mut self, register: u8, value: u8) {
+            self.events.push(format!("V{:X} = {:02X}", register, value));
+        }
+        fn draw(&mut self, x: u8, y: u8, height: u8, collision: bool) {
+            self.events.push(format!("draw {} {} {} {}", x, y, height, collision));
+        }
+        fn timers(&mut self, delay: u8, sound: u8) {
+            self.events.push(format!("timers {} {}", delay, sound));
+        }
+        fn sound(&mut self, playing: bool) {
+            self.events.push(format!("sound {}", playing));
+        }
+    }
+
+    fn observe(mut machine: Machine) -> Vec<String> {
+        let mut recorder = Recorder::default();
+        machine.step_with(&mut recorder).unwrap();
+        recorder.events
+    }
+
+    #[test]
+    fn observer_sees_register_and_flag_writes() {
+        assert_eq!(observe(given().v(1, 0xFF).v(2, 1).with_opcode(0x8124)),
+            vec!["fetch 200 8124", "V1 = 00", "VF = 01"]);
+    }
+
+    #[test]
+    fn observer_sees_memory_traffic() {
+        assert_eq!(observe(given().v(0, 123).i(0x400).with_opcode(0xF033)),
+            vec!["fetch 200 F033", "write 400 01", "write 401 02", "write 402 03"]);
+        assert_eq!(observe(given().mem(0x400, &[7]).i(0x400).with_opcode(0xF065)),
+            vec!["fetch 200 F065", "read 400 07", "V0 = 07"]);
+    }
+
+    #[test]
+    fn observer_sees_draws_with_collisions() {
+        let events = observe(given().pixel(0, 0).v(0, 64).v(1, 32).i(0x400).mem(0x400, &[0x80]).with_opcode(0xD011));
+        assert_eq!(events, vec!["fetch 200 D011", "read 400 80", "VF = 01", "draw 0 0 1 true"]);
+    }
+
+    #[test]
+    fn observer_sees_the_buzzer_start_and_stop() {
+        assert_eq!(observe(given().v(3, 2).with_opcode(0xF318)),
+            vec!["fetch 200 F318", "timers 0 2", "sound true"]);
+
+        let mut recorder = Recorder::default();
+        let mut machine = given().v(3, 2).with_opcode(0xF318);
+        machine.step().unwrap();
+        machine.tick_timers_with(&mut recorder);
+        machine.tick_timers_with(&mut recorder);
+        assert_eq!(recorder.events, vec!["timers 0 1", "timers 0 0", "sound false"]);
+    }
+
+    #[test]
+    fn cached_steps_match_uncached_ones() {
+        // a loop that draws, counts, stores BCD and calls a subroutine
+        let program = [
+            0x60, 0x05, 0x71, 0x03, 0xA3, 0x00, 0xD0, 0x15, 0xF1, 0x33,
+            0x22, 0x10, 0x12, 0x02, 0x00, 0x00, 0x80, 0x14, 0x00, 0xEE
+        ];
+        let mut plain = Machine::new(Config::default());
+        plain.load_program(&program, 0x200);
+        let mut cached = plain.clone();
+        let mut cache = DecodeCache::new();
+        for _ in 0..500 {
+            plain.step().unwrap();
+            cached.step_cached(&mut cache, &mut ()).unwrap();
+            assert_eq!((cached.pc, cached.v, cached.i, cached.sp), (plain.pc, plain.v, plain.i, plain.sp));
+            assert_eq!(cached.gfx, plain.gfx);
+        }
+    }
+
+    #[test]
+    fn cached_steps_see_self_modifying_code() {
+        // 200: LD I, 0x206; LD V0, 0x61; LD [I], V0 (rewrites 206 to 6105); 206: LD V0, 0x05
+        let program = [0xA2, 0x06, 0x60, 0x61, 0xF0, 0x55, 0x60, 0x05];
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(&program, 0x200);
+        let mut cache = DecodeCache::new();
+        machine.pc = 0x206;
+        cache.get(&machine);
+        machine.pc = 0x200;
+
+        for _ in 0..4 {
+            machine.step_cached(&mut cache, &mut ()).unwrap();
+        }
+        assert_eq!(machine.v[1], 0x05);
+        assert_eq!(machine.v[0], 0x61);
+    }
+
+    #[test]
+    fn pokes_drop_cached_instructions() {
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(&[0x60, 0x01, 0x12, 0x00], 0x200);
+        let mut cache = DecodeCache::new();
+        machine.step_cached(&mut cache, &mut ()).unwrap();
+
+        machine.poke_with(0x201, 0x07, &mut cache);
+        machine.pc = 0x200;
+        machine.step_cached(&mut cache, &mut ()).unwrap();
+        assert_eq!(machine.v[0], 0x07);
+    }
+}
@@ -0,0 +1,387 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use crate::cartridge;
+use crate::database::RomInfo;
+use crate::records::{self, Image};
+use crate::{Config, ConfigError, MEMORY_SIZE};
+
+/// A program read from disk and checked against the platform's memory map.
+pub struct Rom {
+    pub data: Vec<u8>,
+    /// Address of the first byte of `data`.
+    pub origin: u16,
+    /// Initial value of PC.
+    pub entry: u16,
+    /// Raw binaries go wherever the platform loads programs; record files
+    /// (Intel HEX, S-records) name their own addresses.
+    pub relocatable: bool,
+    pub sha1: String,
+    pub warnings: Vec<LoadWarning>,
+    /// Settings saved alongside the program, as in Octo cartridges.
+    pub info: Option<RomInfo>,
+    /// The file inside a zip archive the ROM came from.
+    pub archive_entry: Option<String>
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotFound,
+    Io(io::Error),
+    Empty,
+    /// The program doesn't fit between the load address and the end of memory.
+    TooLarge { size: usize, capacity: usize },
+    Cartridge(String),
+    /// An Intel HEX or S-record file that doesn't parse.
+    BadRecords(String),
+    Archive(String),
+    /// The archive holds several ROMs; pass one of them to `read_rom`.
+    ChooseEntry(Vec<String>),
+    MissingEntry(String),
+    /// The settings picked for the ROM can't be run.
+    Config(ConfigError)
+}
+
+/// Something odd about a ROM that still loads.
+#[derive(Debug, PartialEq)]
+pub enum LoadWarning {
+    /// Instructions are two bytes, so a trailing odd byte is usually a bad dump.
+    OddLength(usize),
+    /// Record data starting below 0x200 overwrites the font and the
+    /// interpreter's own memory.
+    BelowProgramSpace(u16)
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotFound => write!(f, "file not found"),
+            LoadError::Io(err) => write!(f, "couldn't read the file: {}", err),
+            LoadError::Empty => write!(f, "the file is empty"),
+            LoadError::TooLarge { size, capacity } => {
+                write!(f, "ROM is {} bytes but only {} bytes fit after the load address", size, capacity)
+            },
+            LoadError::Cartridge(err) => write!(f, "bad Octo cartridge: {}", err),
+            LoadError::BadRecords(err) => write!(f, "bad record file: {}", err),
+            LoadError::Archive(err) => write!(f, "bad zip archive: {}", err),
+            LoadError::ChooseEntry(names) => write!(f, "the archive holds several ROMs: {}", names.join(", ")),
+            LoadError::MissingEntry(name) => write!(f, "the archive has no entry named {}", name),
+            LoadError::Config(err) => write!(f, "bad settings: {}", err)
+        }
+    }
+}
+
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadWarning::OddLength(size) => write!(f, "ROM has an odd length ({} bytes)", size),
+            LoadWarning::BelowProgramSpace(origin) => {
+                write!(f, "data starts at 0x{:03X}, below 0x200, and overwrites the font", origin)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        if err.kind() == io::ErrorKind::NotFound {
+            LoadError::NotFound
+        } else {
+            LoadError::Io(err)
+        }
+    }
+}
+
+/// Number of bytes available for a program under `config`.
+pub fn capacity(config: &Config) -> usize {
+    MEMORY_SIZE.saturating_sub(config.load_address as usize)
+}
+
+/// Reads a raw binary, an Octo cartridge (`.gif`), an Intel HEX (`.hex`,
+/// `.ihx`) or S-record (`.srec`, `.s19`, `.s28`, `.s37`) file, or a zip
+/// archive of any of these. `entry`
+/// picks a file out of an archive, and can be left out if it holds one ROM.
+pub fn read_rom(path: &Path, entry: Option<&str>, config: &Config) -> Result<Rom, LoadError> {
+    let bytes = fs::read(path)?;
+    let name = path.to_string_lossy();
+    if has_extension(&name, "zip") {
+        let (entry, bytes) = archive_entry(bytes, entry)?;
+        let mut rom = from_file(&entry, bytes, config)?;
+        rom.archive_entry = Some(entry);
+        Ok(rom)
+    } else {
+        from_file(&name, bytes, config)
+    }
+}
+
+fn from_file(name: &str, bytes: Vec<u8>, config: &Config) -> Result<Rom, LoadError> {
+    if has_extension(name, "gif") {
+        let cartridge = cartridge::read(&bytes)?;
+        let mut rom = validate(cartridge.program, config)?;
+        rom.info = Some(cartridge.info);
+        Ok(rom)
+    } else if ["hex", "ihx"].iter().any(|ext| has_extension(name, ext)) {
+        from_image(records::parse_intel_hex(&String::from_utf8_lossy(&bytes)), config)
+    } else if ["srec", "s19", "s28", "s37", "mot"].iter().any(|ext| has_extension(name, ext)) {
+        from_image(records::parse_srecord(&String::from_utf8_lossy(&bytes)), config)
+    } else {
+        validate(bytes, config)
+    }
+}
+
+/// Record files start wherever their first record is, unless they give an entry point.
+fn from_image(image: Result<Image, String>, config: &Config) -> Result<Rom, LoadError> {
+    let image = image.map_err(LoadError::BadRecords)?;
+    let mut rom = validate(image.data, &Config { load_address: image.origin, ..*config })?;
+    rom.entry = image.entry.unwrap_or(image.origin);
+    rom.relocatable = false;
+    if image.origin < 0x200 {
+        rom.warnings.push(LoadWarning::BelowProgramSpace(image.origin));
+    }
+    Ok(rom)
+}
+
+/// Moves a raw binary to the load address of `config`, for when the
+/// platform changes after reading it. Other ROMs stay where they are.
+pub fn relocate(rom: Rom, config: &Config) -> Result<Rom, LoadError> {
+    if !rom.relocatable {
+        return Ok(rom);
+    }
+    let capacity = capacity(config);
+    if rom.data.len() > capacity {
+        return Err(LoadError::TooLarge { size: rom.data.len(), capacity });
+    }
+    Ok(Rom { origin: config.load_address, entry: config.load_address, ..rom })
+}
+
+fn has_extension(name: &str, extension: &str) -> bool {
+    Path::new(name).extension().is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+/// Entries of a zip archive that could be ROMs, skipping folders, text files
+/// and macOS metadata.
+fn archive_roms(archive: &zip::ZipArchive<Cursor<Vec<u8>>>) -> Vec<String> {
+    let mut names: Vec<String> = archive.file_names()
+        .filter(|name| !name.ends_with('/') && !name.starts_with("__MACOSX/"))
+        .filter(|name| !["txt", "md", "nfo"].iter().any(|ext| has_extension(name, ext)))
+        .map(String::from)
+        .collect();
+    names.sort();
+    names
+}
+
+fn archive_entry(bytes: Vec<u8>, entry: Option<&str>) -> Result<(String, Vec<u8>), LoadError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))
+        .map_err(|err| LoadError::Archive(err.to_string()))?;
+
+    let name = match entry {
+        Some(name) => name.to_owned(),
+        None => {
+            let mut names = archive_roms(&archive);
+            match names.len() {
+                0 => return Err(LoadError::Empty),
+                1 => names.remove(0),
+                _ => return Err(LoadError::ChooseEntry(names))
+            }
+        }
+    };
+
+    let mut file = archive.by_name(&name).map_err(|_| LoadError::MissingEntry(name.clone()))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok((name, data))
+}
+
+pub fn validate(data: Vec<u8>, config: &Config) -> Result<Rom, LoadError> {
+    if data.is_empty() {
+        return Err(LoadError::Empty);
+    }
+    let capacity = capacity(config);
+    if data.len() > capacity {
+        return Err(LoadError::TooLarge { size: data.len(), capacity });
+    }
+
+    let mut warnings = Vec::new();
+    if data.len() % 2 != 0 {
+        warnings.push(LoadWarning::OddLength(data.len()));
+    }
+    let sha1 = sha1_smol::Sha1::from(&data).digest().to_string();
+
+    Ok(Rom {
+        data,
+        origin: config.load_address,
+        entry: config.load_address,
+        relocatable: true,
+        sha1,
+        warnings,
+        info: None,
+        archive_entry: None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn accepts_a_rom_that_fills_memory() {
+        let rom = validate(vec![0; 3584], &Config::default()).ok().unwrap();
+        assert_eq!(rom.data.len(), 3584);
+        assert!(rom.warnings.is_empty());
+    }
+
+    #[test]
+    fn rejects_oversized_roms() {
+        match validate(vec![0; 3585], &Config::default()) {
+            Err(LoadError::TooLarge { size, capacity }) => assert_eq!((size, capacity), (3585, 3584)),
+            _ => panic!("expected TooLarge")
+        }
+
+        let eti = Config { load_address: 0x600, ..Config::default() };
+        match validate(vec![0; 2561], &eti) {
+            Err(LoadError::TooLarge { capacity, .. }) => assert_eq!(capacity, 2560),
+            _ => panic!("expected TooLarge")
+        }
+    }
+
+    #[test]
+    fn rejects_empty_roms() {
+        assert!(matches!(validate(Vec::new(), &Config::default()), Err(LoadError::Empty)));
+    }
+
+    #[test]
+    fn warns_about_odd_lengths() {
+        let rom = validate(vec![0x00, 0xE0, 0x12], &Config::default()).ok().unwrap();
+        assert_eq!(rom.warnings, vec![LoadWarning::OddLength(3)]);
+    }
+
+    #[test]
+    fn hashes_with_sha1() {
+        let rom = validate(b"abc".to_vec(), &Config::default()).ok().unwrap();
+        assert_eq!(rom.sha1, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let path = env::temp_dir().join("chip8-loader-test-missing.ch8");
+        assert!(matches!(read_rom(&path, None, &Config::default()), Err(LoadError::NotFound)));
+    }
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        use std::io::Write;
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn reads_the_only_rom_in_an_archive() {
+        let path = env::temp_dir().join("chip8-loader-test-single.zip");
+        fs::write(&path, zip(&[("readme.txt", b"hello"), ("game.ch8", &[0x00, 0xE0])])).unwrap();
+        let rom = read_rom(&path, None, &Config::default()).ok().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.data, vec![0x00, 0xE0]);
+        assert_eq!(rom.archive_entry.as_deref(), Some("game.ch8"));
+    }
+
+    #[test]
+    fn asks_which_rom_to_read_from_an_archive() {
+        let path = env::temp_dir().join("chip8-loader-test-pack.zip");
+        fs::write(&path, zip(&[("b.ch8", &[0x12, 0x00]), ("a.ch8", &[0x00, 0xE0])])).unwrap();
+        let choice = read_rom(&path, None, &Config::default());
+        let picked = read_rom(&path, Some("b.ch8"), &Config::default());
+        let missing = read_rom(&path, Some("c.ch8"), &Config::default());
+        fs::remove_file(&path).unwrap();
+
+        match choice {
+            Err(LoadError::ChooseEntry(names)) => assert_eq!(names, vec!["a.ch8", "b.ch8"]),
+            _ => panic!("expected ChooseEntry")
+        }
+        assert_eq!(picked.ok().unwrap().data, vec![0x12, 0x00]);
+        assert!(matches!(missing, Err(LoadError::MissingEntry(_))));
+    }
+
+    #[test]
+    fn reads_octo_cartridges() {
+        let gif = cartridge::tests::cartridge_gif(r#"{"program": "0x00 0xE0", "options": {"tickrate": 7}}"#);
+        let path = env::temp_dir().join("chip8-loader-test-cart.GIF");
+        fs::write(&path, gif).unwrap();
+        let rom = read_rom(&path, None, &Config::default()).ok().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.data, vec![0x00, 0xE0]);
+        assert_eq!(rom.info.unwrap().tickrate, Some(7));
+    }
+
+    #[test]
+    fn reads_record_files_at_their_own_addresses() {
+        let path = env::temp_dir().join("chip8-loader-test-records.hex");
+        fs::write(&path, ":020600001234B2\n:00000001FF\n").unwrap();
+        let rom = read_rom(&path, None, &Config::default()).ok().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((rom.origin, rom.entry, rom.relocatable), (0x600, 0x600, false));
+        assert_eq!(rom.data, vec![0x12, 0x34]);
+
+        let path = env::temp_dir().join("chip8-loader-test-records.s19");
+        fs::write(&path, "S107020000E0120004\nS9030202F8\n").unwrap();
+        let rom = read_rom(&path, None, &Config::default()).ok().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((rom.origin, rom.entry), (0x200, 0x202));
+
+        // an entry of 0 means there isn't one
+        let path = env::temp_dir().join("chip8-loader-test-no-entry.s19");
+        fs::write(&path, "S107020000E0120004\nS9030000FC\n").unwrap();
+        let rom = read_rom(&path, None, &Config::default()).ok().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((rom.origin, rom.entry), (0x200, 0x200));
+
+        let path = env::temp_dir().join("chip8-loader-test-low-records.hex");
+        fs::write(&path, ":020000001234B8\n:00000001FF\n").unwrap();
+        let rom = read_rom(&path, None, &Config::default()).ok().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.warnings, vec![LoadWarning::BelowProgramSpace(0)]);
+
+        let path = env::temp_dir().join("chip8-loader-test-bad-records.hex");
+        fs::write(&path, "garbage").unwrap();
+        let result = read_rom(&path, None, &Config::default());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(LoadError::BadRecords(_))));
+    }
+
+    #[test]
+    fn relocates_raw_binaries_only() {
+        let eti = Config { load_address: 0x600, ..Config::default() };
+        let rom = relocate(validate(vec![0; 16], &Config::default()).ok().unwrap(), &eti).ok().unwrap();
+        assert_eq!((rom.origin, rom.entry), (0x600, 0x600));
+
+        let too_big = validate(vec![0; 3000], &Config::default()).ok().unwrap();
+        assert!(matches!(relocate(too_big, &eti), Err(LoadError::TooLarge { size: 3000, capacity: 2560 })));
+
+        let mut fixed = validate(vec![0; 16], &Config::default()).ok().unwrap();
+        fixed.relocatable = false;
+        assert_eq!(relocate(fixed, &eti).ok().unwrap().origin, 0x200);
+    }
+
+    #[test]
+    fn rejects_corrupt_archives() {
+        let path = env::temp_dir().join("chip8-loader-test-corrupt.zip");
+        fs::write(&path, b"PK not really").unwrap();
+        let result = read_rom(&path, None, &Config::default());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(LoadError::Archive(_))));
+    }
+
+    #[test]
+    fn reads_roms_from_disk() {
+        let path = env::temp_dir().join("chip8-loader-test-read.ch8");
+        fs::write(&path, [0x00, 0xE0]).unwrap();
+        let rom = read_rom(&path, None, &Config::default()).ok().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.data, vec![0x00, 0xE0]);
+    }
+}
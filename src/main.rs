@@ -2,539 +2,685 @@ extern crate piston_window;
 
 use std::env;
 use std::fs;
-use rand::Rng;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use piston_window::*;
 
-const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
-const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
-
-type OpCode = u16;
-
-#[derive(Clone, Copy)]
-struct Machine {
-   memory: [u8; 4096],
-   gfx: [bool; 64*32],
-   v: [u8; 16],
-   stack: [u16; 16],
-   key: [bool; 16],
-   
-   i: u16,
-   pc: u16,
-   sp: u8,
-   delay_timer: u8,
-   sound_timer: u8,
-   draw_flag: bool,
-   await_keypress: bool,
-   keypress_register: u8
-}
+use chip8::cli::{self, Command, Options, Settings};
+use chip8::database::{Database, RomInfo};
+use chip8::{compare, disasm, loader, profile, recompile, trace, watch};
+use chip8::runner::{Control, Frame, Registers, Runner};
+use chip8::{Config, Machine, KEYPAD_LAYOUT};
 
-const CHIP8_FONTSET: [u8; 80] = [
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80  // F
-];
+use browser::Browser;
+use screen::Screen;
 
+mod browser;
+mod inspector;
+mod memview;
+mod screen;
+mod terminal;
 
 fn main() {
-    // Emulator Stuff
-    let args: Vec<String> = env::args().collect();
-    let program_dir = &args[1];
-    let debug_mode: bool = args.contains(&"debug".to_owned());
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Run(options) => run(options, false),
+        Command::Debug(options) => run(options, true),
+        Command::Terminal(options) => run_in_terminal(options),
+        Command::Disasm(path) => {
+            let config = Config::default();
+            let rom = read_rom_or_exit(&path, None, &config);
+            for line in disasm::listing(&rom.data, rom.origin) {
+                println!("{}", line);
+            }
+        },
+        Command::Info(options) => {
+            let rom = read_rom_or_exit(&options.rom, options.entry.as_deref(), &Config::default());
+            println!("File:  {}", options.rom.display());
+            println!("Size:  {} bytes", rom.data.len());
+            println!("SHA-1: {}", rom.sha1);
+            let database = open_database(&options);
+            match rom.info.as_ref().or_else(|| database.as_ref().and_then(|database| database.lookup(&rom.sha1))) {
+                Some(info) => {
+                    println!("Title: {}", info.title);
+                    if !info.authors.is_empty() {
+                        println!("By:    {}", info.authors.join(", "));
+                    }
+                    if let Some(platform) = &info.platform {
+                        println!("Platform: {}", platform);
+                    }
+                    if let Some(tickrate) = info.tickrate {
+                        println!("Tickrate: {} instructions per frame", tickrate);
+                    }
+                },
+                None => println!("Not in the ROM database")
+            }
+        },
+        Command::Compare(options, reference) => compare_with_reference(options, &reference),
+        Command::Recompile(options, output) => recompile_rom(options, &output),
+        Command::Help => println!("{}", cli::USAGE),
+        Command::Version => println!("chip8 {}", env!("CARGO_PKG_VERSION"))
+    }
+}
+
+/// Runs a ROM without a window against a reference trace, exiting with an
+/// error at the first difference.
+fn compare_with_reference(options: Options, path: &Path) {
+    let reference = fs::read_to_string(path).map_err(|err| err.to_string())
+        .and_then(|text| compare::read_reference(&text))
+        .unwrap_or_else(|err| {
+            eprintln!("Couldn't read {}: {}", path.display(), err);
+            process::exit(1);
+        });
+
+    let database = open_database(&options);
+    let rom = read_rom_or_exit(&options.rom, options.entry.as_deref(), &anywhere());
+    let session = open_session(&options, database.as_ref(), &options.rom, rom)
+        .unwrap_or_else(|err| exit_with_load_error(&options.rom, err));
+    match compare::compare(session.machine, &reference, session.settings.speed) {
+        Ok(count) => println!("Matched all {} instructions in {}", count, path.display()),
+        Err(mismatch) => {
+            println!("{}", compare::report(&mismatch));
+            process::exit(1);
+        }
+    }
+}
+
+/// Writes a ROM out as a Rust program that runs it natively.
+fn recompile_rom(options: Options, output: &Path) {
+    let database = open_database(&options);
+    let rom = read_rom_or_exit(&options.rom, options.entry.as_deref(), &anywhere());
+    let session = open_session(&options, database.as_ref(), &options.rom, rom)
+        .unwrap_or_else(|err| exit_with_load_error(&options.rom, err));
+    let settings = &session.settings;
+    let recompiled = recompile::recompile(&session.machine, &session.title, settings.speed, settings.palette)
+        .unwrap_or_else(|err| {
+            eprintln!("Couldn't recompile {}: {}", options.rom.display(), err);
+            process::exit(1);
+        });
+    if let Err(err) = fs::write(output, &recompiled.source) {
+        eprintln!("Couldn't write {}: {}", output.display(), err);
+        process::exit(1);
+    }
+    println!("Wrote {}: {} instructions in {} blocks", output.display(), recompiled.instructions, recompiled.blocks);
+}
+
+fn read_rom_or_exit(path: &Path, entry: Option<&str>, config: &Config) -> loader::Rom {
+    match loader::read_rom(path, entry, config) {
+        Ok(rom) => {
+            for warning in &rom.warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            rom
+        },
+        Err(loader::LoadError::ChooseEntry(names)) => {
+            eprintln!("{} holds several ROMs:", path.display());
+            match choose_entry(&names) {
+                Some(name) => read_rom_or_exit(path, Some(&name), config),
+                None => {
+                    eprintln!("No ROM picked");
+                    process::exit(1);
+                }
+            }
+        },
+        Err(err) => exit_with_load_error(path, err)
+    }
+}
+
+/// Asks on the terminal which of `names` to load, prompting on stderr so
+/// output such as a disassembly can still be piped.
+fn choose_entry(names: &[String]) -> Option<String> {
+    for (n, name) in names.iter().enumerate() {
+        eprintln!("{:>3}) {}", n + 1, name);
+    }
+    eprint!("Pick one [1-{}]: ", names.len());
+    io::stderr().flush().ok()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).ok()?;
+    let n: usize = line.trim().parse().ok()?;
+    names.get(n.checked_sub(1)?).cloned()
+}
+
+fn exit_with_load_error(path: &Path, err: loader::LoadError) -> ! {
+    eprintln!("Couldn't load {}: {}", path.display(), err);
+    process::exit(1);
+}
+
+fn open_database(options: &Options) -> Option<Database> {
+    if !options.use_database {
+        return None;
+    }
+    match &options.database {
+        Some(dir) => match Database::from_dir(dir) {
+            Ok(database) => Some(database),
+            Err(err) => {
+                eprintln!("Warning: {}", err);
+                None
+            }
+        },
+        None => Some(Database::embedded())
+    }
+}
+
+/// Keyboard keys for the named controls a database entry can bind.
+fn game_key(name: &str) -> Option<Key> {
+    match name {
+        "up" => Some(Key::Up),
+        "down" => Some(Key::Down),
+        "left" => Some(Key::Left),
+        "right" => Some(Key::Right),
+        "a" => Some(Key::Return),
+        "b" => Some(Key::RShift),
+        _ => None
+    }
+}
+
+/// The profiler and tracer, which watch the core through its observer hooks.
+type Observers = (Option<profile::Profiler>, Option<trace::Tracer>);
+
+/// A ROM opened in the window, with what it takes to start it again.
+struct Session {
+    machine: Machine,
+    settings: Settings,
+    path: PathBuf,
+    archive_entry: Option<String>,
+    title: String,
+    entry_point: u16,
+    sha1: String
+}
+
+/// Starts `rom`, read from `path`, with settings from the file, the database
+/// and the command line.
+fn open_session(options: &Options, database: Option<&Database>, path: &Path, rom: loader::Rom) -> Result<Session, loader::LoadError> {
+    println!("Loaded {} ({} bytes, sha1 {})", path.display(), rom.data.len(), rom.sha1);
+
+    let info = rom_info(&rom, database);
+    let info = info.as_ref();
+    let mut title = "Chip 8".to_owned();
+    if let Some(info) = info {
+        println!("Recognised {}", info.title);
+        title = format!("Chip 8 - {}", info.title);
+    }
+    let settings = options.resolve(info);
+
+    let archive_entry = rom.archive_entry.clone();
+    let sha1 = rom.sha1.clone();
+    let machine = Machine::start(rom, settings.config, options.seed)?;
+    let entry_point = machine.pc;
+    Ok(Session { machine, settings, path: path.to_path_buf(), archive_entry, title, entry_point, sha1 })
+}
+
+/// What's known about `rom`; settings saved in the file itself win over the database.
+fn rom_info(rom: &loader::Rom, database: Option<&Database>) -> Option<RomInfo> {
+    rom.info.clone().or_else(|| database.and_then(|database| database.lookup(&rom.sha1)).cloned())
+}
+
+/// Reads the session's ROM from disk again and starts it from scratch,
+/// keeping the archive entry. The settings picked when it was opened are
+/// kept too, unless `--watch-reset-settings` asks for them afresh or the
+/// ROM changed into one with settings of its own or in the database.
+fn restart_session(session: &Session, options: &Options, database: Option<&Database>) -> Result<Session, loader::LoadError> {
+    let rom = loader::read_rom(&session.path, session.archive_entry.as_deref(), &anywhere())?;
+    if options.watch_reset_settings || (rom.sha1 != session.sha1 && rom_info(&rom, database).is_some()) {
+        return open_session(options, database, &session.path, rom);
+    }
+    let sha1 = rom.sha1.clone();
+    let machine = Machine::start(rom, session.settings.config, options.seed)?;
+    Ok(Session {
+        entry_point: machine.pc,
+        machine,
+        settings: session.settings.clone(),
+        path: session.path.clone(),
+        archive_entry: session.archive_entry.clone(),
+        title: session.title.clone(),
+        sha1
+    })
+}
 
-    let mut opcode_history: Vec<OpCode> = Vec::new();
+/// Read ROMs as if loaded at 0, and check the fit once the platform is known.
+fn anywhere() -> Config {
+    Config { load_address: 0, ..Config::default() }
+}
 
-    let mut machine = init_machine();
-    let program = fs::read(program_dir).expect("Couldn't open the program!");
-    machine = load_program(machine, program);
+fn run(options: Options, debug_mode: bool) {
+    let database = open_database(&options);
+    let rom = read_rom_or_exit(&options.rom, options.entry.as_deref(), &anywhere());
+    let mut session = open_session(&options, database.as_ref(), &options.rom, rom)
+        .unwrap_or_else(|err| exit_with_load_error(&options.rom, err));
+    let mut watcher = if options.watch { Some(watch::Watcher::new(options.rom.clone())) } else { None };
+    let mut browser: Option<Browser> = None;
+    let mut memory_view = memview::MemoryView::new(&session.machine.memory);
+    let observers = start_observers(&options);
+    let mut notice: Option<(String, [f32; 4], Instant)> = None;
 
     if debug_mode {
         println!("Debug Mode");
-        print_mem(machine);
+        print_mem(&session.machine);
     }
 
+    let keymap: Vec<Key> = options.keymap.chars().map(|c| Key::from(c as u32)).collect();
+    let scale = options.scale;
     let mut paused = false;
+    // whether the core was last told to stop, for pausing or browsing
+    let mut stopped = false;
+
+    // the program runs on its own thread; the window shows the frames it leaves
+    let runner = Runner::spawn(session.machine.clone(), session.settings.speed, observers, inspector::HISTORY_ROWS);
+    let mut frame = frame_of(&session.machine);
 
     // Display Stuff
     let mut window: PistonWindow =
-        WindowSettings::new("Chip 8", [1280, 720])
+        WindowSettings::new(session.title.clone(), [80.0 * scale, 57.0 * scale])
         .exit_on_esc(true).build().unwrap();
 
     let mut event_settings = EventSettings::new();
-    event_settings.set_ups(500);
     event_settings.set_max_fps(60);
     window.set_event_settings(event_settings);
 
+    let mut screen = Screen::new(&mut window);
     let mut glyphs = window.load_font("assets/NotoSans-Regular.ttf").unwrap();
     let sound_indicator: G2dTexture = Texture::from_path(
         &mut window.create_texture_context(),
-        &"assets/soundOnWhite.png",
+        "assets/soundOnWhite.png",
         Flip::None,
         &TextureSettings::new()
     ).unwrap();
 
+    let info_colour = [1.0, 1.0, 0.0, 1.0];
+    let error_colour = [1.0, 0.2, 0.2, 1.0];
+
     'main: while let Some(event) = window.next() {
-        let pc = machine.pc;
-        let opcode: OpCode = (machine.memory[pc as usize] as u16) << 8 | machine.memory[(pc+1) as usize] as u16;
+        if let Some(newer) = runner.frame() {
+            // a display change not drawn yet carries over to the newer frame
+            let dirty = frame.machine.draw_flag;
+            frame = newer;
+            frame.machine.draw_flag |= dirty;
+        }
+        if let Some(fault) = &frame.fault {
+            eprintln!("{}", fault);
+            break 'main;
+        }
+        if let Some(text) = frame.notice.take() {
+            notice = Some((text, error_colour, Instant::now()));
+        }
+
+        // a ROM dropped on the window or picked in the browser replaces the current one
+        let mut switch_to: Option<PathBuf> = None;
+        let mut hard_reset = false;
+
         match event {
-            Event::Loop(Loop::Update(ref _upd)) => {
-                if machine.await_keypress || paused {
-                    continue 'main;
-                }
-                opcode_history.push(opcode);
-                if opcode_history.len() > 20 { opcode_history.remove(0); }
-                machine = run_cycle(machine);
-            },
             Event::Loop(Loop::Render(ref _ren)) => {
-                if paused {
-                    continue 'main;
+                if let Some(watcher) = &mut watcher {
+                    hard_reset = watcher.changed();
                 }
+                memory_view.observe(&frame.machine);
+                let palette = session.settings.palette;
+                screen.update(&mut window, &mut frame.machine, palette);
 
                 // Display the results
-                let opcode_history_ref = &opcode_history;
+                let machine = &frame.machine;
+                let previous = &frame.previous;
+                let memory_view_ref = &memory_view;
+                let screen_ref = &screen;
+                let opcode_history_ref = &frame.history;
+                let browser_ref = &browser;
                 window.draw_2d(&event, |context, graphics, device| {
-                    clear(BLACK, graphics);
+                    clear(palette.background, graphics);
                     // Main Game Display
-                    for i in 0..32 {
-                        for j in 0..64 {
-                            if machine.gfx[(i*64 + j) as usize] {
-                                let (x, y) = ((j*16) as f64, (i*16) as f64);
-                                rectangle(WHITE, // red
-                                    [x, y, 16.0, 16.0],
-                                    context.transform,
-                                    graphics);
+                    screen_ref.draw(scale, &context, graphics);
+
+                    // Debug Panel
+                    for (row, fields) in inspector::panel(previous, machine, opcode_history_ref).iter().enumerate() {
+                        for field in fields {
+                            let colour = if field.changed { [1.0, 1.0, 0.0, 1.0] } else { [0.0, 1.0, 0.0, 1.0] };
+                            text::Text::new_color(colour, scale as u32).draw(
+                                &field.text,
+                                &mut glyphs,
+                                &context.draw_state,
+                                context.transform.trans((65.0 + 3.7 * field.cell as f64) * scale, 1.6 * scale * (1.0 + row as f64)), graphics
+                            ).unwrap();
+                        }
+                    }
+                    
+                    // Sound Indicator
+                    if machine.sound_timer > 0 {
+                        image(&sound_indicator, context.transform.trans(4.0 * scale, 33.0 * scale), graphics);
+                    }
+
+                    // Memory View
+                    let header = match &memory_view_ref.jump {
+                        Some(digits) => format!("Jump to: {}_", digits),
+                        None => format!("Memory, following {}", match memory_view_ref.follow {
+                            memview::Follow::Pc => "PC",
+                            memview::Follow::I => "I",
+                            memview::Follow::Fixed => "nothing"
+                        })
+                    };
+                    text::Text::new_color([0.6, 0.6, 0.6, 1.0], scale as u32).draw(
+                        &header,
+                        &mut glyphs,
+                        &context.draw_state,
+                        context.transform.trans(1.0 * scale, 39.0 * scale), graphics
+                    ).unwrap();
+                    for (n, row) in memory_view_ref.rows(machine, paused).iter().enumerate() {
+                        let y = (40.6 + 1.6 * n as f64) * scale;
+                        text::Text::new_color([0.0, 1.0, 0.0, 1.0], scale as u32).draw(
+                            &format!("{:03X}", row.address),
+                            &mut glyphs,
+                            &context.draw_state,
+                            context.transform.trans(1.0 * scale, y), graphics
+                        ).unwrap();
+                        for (column, &(value, highlight)) in row.bytes.iter().enumerate() {
+                            let colour = match highlight {
+                                memview::Highlight::None => [0.0, 1.0, 0.0, 1.0],
+                                memview::Highlight::Pc => [0.3, 0.6, 1.0, 1.0],
+                                memview::Highlight::I => [1.0, 0.5, 1.0, 1.0],
+                                memview::Highlight::Written => [1.0, 1.0, 0.0, 1.0],
+                                memview::Highlight::Cursor => [1.0, 1.0, 1.0, 1.0]
+                            };
+                            if highlight == memview::Highlight::Cursor {
+                                let x = (5.6 + 2.2 * column as f64 + if column >= 8 { 1.0 } else { 0.0 }) * scale;
+                                rectangle([0.3, 0.3, 0.3, 1.0], [x, y - 1.1 * scale, 1.6 * scale, 1.4 * scale], context.transform, graphics);
                             }
+                            let x = (6.0 + 2.2 * column as f64 + if column >= 8 { 1.0 } else { 0.0 }) * scale;
+                            text::Text::new_color(colour, scale as u32).draw(
+                                &format!("{:02X}", value),
+                                &mut glyphs,
+                                &context.draw_state,
+                                context.transform.trans(x, y), graphics
+                            ).unwrap();
                         }
                     }
 
-                    // Opcode History
-                    for (i, code) in opcode_history_ref.into_iter().enumerate() {
-                        let code_output = format!("0x{:0>4X}", code);
-                        text::Text::new_color([0.0, 1.0, 0.0, 1.0], 32).draw(
-                            &code_output,
+                    // ROM Browser
+                    if let Some(browser) = browser_ref {
+                        rectangle([0.0, 0.0, 0.0, 0.85], [0.0, 0.0, 64.0 * scale, 32.0 * scale], context.transform, graphics);
+                        text::Text::new_color([0.6, 0.6, 0.6, 1.0], scale as u32).draw(
+                            &browser.dir.display().to_string(),
                             &mut glyphs,
                             &context.draw_state,
-                            context.transform.trans(66.0*16.0, 32.0*(2.0 + i as f64)), graphics
+                            context.transform.trans(2.0 * scale, 2.0 * scale), graphics
                         ).unwrap();
+                        let rows = 13;
+                        let first = browser.scroll(rows);
+                        for (n, entry) in browser.entries.iter().enumerate().skip(first).take(rows) {
+                            let colour = if n == browser.selected { [1.0, 1.0, 0.0, 1.0] } else { [1.0, 1.0, 1.0, 1.0] };
+                            let name = if entry.is_dir { format!("{}/", entry.name) } else { entry.name.clone() };
+                            text::Text::new_color(colour, scale as u32).draw(
+                                &name,
+                                &mut glyphs,
+                                &context.draw_state,
+                                context.transform.trans(2.0 * scale, 2.0 * scale * (2.0 + (n - first) as f64)), graphics
+                            ).unwrap();
+                        }
                     }
-                    
-                    // Sound Indicator
-                    if machine.sound_timer > 0 {
-                        image(&sound_indicator, context.transform.trans(64.0, 33.0 * 16.0), graphics);
+
+                    // Notices
+                    if let Some((message, colour, shown)) = &notice {
+                        if shown.elapsed() < Duration::from_secs(3) {
+                            text::Text::new_color(*colour, scale as u32).draw(
+                                message,
+                                &mut glyphs,
+                                &context.draw_state,
+                                context.transform.trans(12.0 * scale, 35.0 * scale), graphics
+                            ).unwrap();
+                        }
                     }
 
                     // Update glyphs before rendering.
                     glyphs.factory.encoder.flush(device);
                 });
-
-
-                //update program counter and timers
-                if machine.draw_flag {
-                    machine.draw_flag = false;
-                }
-                if machine.delay_timer > 0 && !machine.await_keypress {
-                    machine.delay_timer -= 1;
-                }
-                if machine.sound_timer > 0 && !machine.await_keypress {
-                    machine.sound_timer -= 1;
-                }
+            },
+            Event::Input(Input::FileDrag(FileDrag::Drop(ref path)), _) => {
+                switch_to = Some(path.clone());
             },
             Event::Input(ref _inp, _) => {
-                if let Some(press_args) = event.press_args() {
-                    
-                    let pressed_key;
-                    match press_args {
-                        Button::Keyboard(Key::D1) => pressed_key = 0x1,
-                        Button::Keyboard(Key::D2) => pressed_key = 0x2,
-                        Button::Keyboard(Key::D3) => pressed_key = 0x3,
-                        Button::Keyboard(Key::D4) => pressed_key = 0xC,
-                        Button::Keyboard(Key::Q) => pressed_key = 0x4,
-                        Button::Keyboard(Key::W) => pressed_key = 0x5,
-                        Button::Keyboard(Key::E) => pressed_key = 0x6,
-                        Button::Keyboard(Key::R) => pressed_key = 0xD,
-                        Button::Keyboard(Key::A) => pressed_key = 0x7,
-                        Button::Keyboard(Key::S) => pressed_key = 0x8,
-                        Button::Keyboard(Key::D) => pressed_key = 0x9,
-                        Button::Keyboard(Key::F) => pressed_key = 0xE,
-                        Button::Keyboard(Key::Z) => pressed_key = 0xA,
-                        Button::Keyboard(Key::X) => pressed_key = 0x0,
-                        Button::Keyboard(Key::C) => pressed_key = 0xB,
-                        Button::Keyboard(Key::V) => pressed_key = 0xF,
-                        Button::Keyboard(Key::Space) => {
+                if let Some(Button::Keyboard(key)) = event.press_args() {
+                    match key {
+                        Key::Space => {
                             paused = !paused;
-                            pressed_key = 0x10
+                            // while paused the keyboard edits memory instead
+                            runner.send(Control::ReleaseKeys);
+                        },
+                        Key::F3 => memory_view.cycle_follow(),
+                        Key::F4 => {
+                            memory_view.toggle_jump();
+                            runner.send(Control::ReleaseKeys);
                         },
-                        _ => pressed_key = 0x10
+                        Key::PageUp => memory_view.scroll(-(memview::ROWS as isize)),
+                        Key::PageDown => memory_view.scroll(memview::ROWS as isize),
+                        Key::F5 => {
+                            runner.send(Control::SoftReset(session.entry_point));
+                            notice = Some(("Soft reset".to_owned(), info_colour, Instant::now()));
+                        },
+                        Key::F6 => hard_reset = true,
+                        Key::F2 => {
+                            browser = match browser {
+                                Some(_) => None,
+                                None => {
+                                    let dir = session.path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                                    match Browser::open(dir.unwrap_or_else(|| Path::new("."))) {
+                                        Ok(browser) => Some(browser),
+                                        Err(err) => {
+                                            notice = Some((format!("Couldn't list ROMs: {}", err), error_colour, Instant::now()));
+                                            None
+                                        }
+                                    }
+                                }
+                            };
+                            // keys held when the browser opened would otherwise stay down
+                            runner.send(Control::ReleaseKeys);
+                        },
+                        _ => ()
                     }
-                    if pressed_key <= 0xF {
-                        machine.key[pressed_key as usize] = true;
-                        if machine.await_keypress {
-                            machine.v[machine.keypress_register as usize] = pressed_key;
-                            machine.await_keypress = false;
+                }
+
+                if let Some(open) = &mut browser {
+                    if let Some(Button::Keyboard(key)) = event.press_args() {
+                        let picked = match key {
+                            Key::Up => {
+                                open.up();
+                                Ok(())
+                            },
+                            Key::Down => {
+                                open.down();
+                                Ok(())
+                            },
+                            Key::Backspace => open.leave(),
+                            Key::Return => open.activate().map(|path| switch_to = path),
+                            _ => Ok(())
+                        };
+                        if let Err(err) = picked {
+                            notice = Some((format!("Couldn't list ROMs: {}", err), error_colour, Instant::now()));
+                        }
+                        if switch_to.is_some() {
+                            browser = None;
                         }
                     }
-
-                }
-                if let Some(release_args) = event.release_args() {
-                    match release_args {
-                        Button::Keyboard(Key::D1) => machine.key[0x1] = false,
-                        Button::Keyboard(Key::D2) => machine.key[0x2] = false,
-                        Button::Keyboard(Key::D3) => machine.key[0x3] = false,
-                        Button::Keyboard(Key::D4) => machine.key[0xC] = false,
-                        Button::Keyboard(Key::Q) => machine.key[0x4] = false,
-                        Button::Keyboard(Key::W) => machine.key[0x5] = false,
-                        Button::Keyboard(Key::E) => machine.key[0x6] = false,
-                        Button::Keyboard(Key::R) => machine.key[0xD] = false,
-                        Button::Keyboard(Key::A) => machine.key[0x7] = false,
-                        Button::Keyboard(Key::S) => machine.key[0x8] = false,
-                        Button::Keyboard(Key::D) => machine.key[0x9] = false,
-                        Button::Keyboard(Key::F) => machine.key[0xE] = false,
-                        Button::Keyboard(Key::Z) => machine.key[0xA] = false,
-                        Button::Keyboard(Key::X) => machine.key[0x0] = false,
-                        Button::Keyboard(Key::C) => machine.key[0xB] = false,
-                        Button::Keyboard(Key::V) => machine.key[0xF] = false,
-                        _ => ()
+                } else if memory_view.jump.is_some() || paused {
+                    if let Some(Button::Keyboard(key)) = event.press_args() {
+                        match key {
+                            Key::Left if paused => memory_view.move_cursor(-1),
+                            Key::Right if paused => memory_view.move_cursor(1),
+                            Key::Up if paused => memory_view.move_cursor(-(memview::BYTES_PER_ROW as isize)),
+                            Key::Down if paused => memory_view.move_cursor(memview::BYTES_PER_ROW as isize),
+                            Key::Backspace => memory_view.backspace(),
+                            Key::Return => memory_view.confirm_jump(),
+                            _ => if let Some((address, value)) = hex_digit(key).and_then(|digit| memory_view.type_digit(digit)) {
+                                // the core pokes its machine and sends a frame showing it
+                                runner.send(Control::Poke(address, value));
+                            }
+                        }
                     }
+                } else {
+                    press_keypad(&event, &runner, &keymap, &session.settings);
                 }
             },
             _ => {
                 //println!("unknown event type");
             }
         }
-    }
-}
 
-fn init_machine () -> Machine {
-    let mut machine = Machine {
-        memory: [0; 4096],
-        gfx: [false; 64*32],
-        v: [0; 16],
-        stack: [0; 16],
-        key: [false; 16],
-
-        i: 0,
-        pc: 0x200,
-        sp: 0,
-        delay_timer: 0,
-        sound_timer: 0,
-        draw_flag: false,
-        await_keypress: false,
-        keypress_register: 0
-    };
+        if hard_reset {
+            let file_name = session.path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            notice = Some(match restart_session(&session, &options, database.as_ref()) {
+                Ok(restarted) => {
+                    session = restarted;
+                    memory_view.forget(&session.machine.memory);
+                    frame = frame_of(&session.machine);
+                    runner.send(Control::Load(Box::new(session.machine.clone()), session.settings.speed));
+                    window.set_title(session.title.clone());
+                    (format!("Reloaded {}", file_name), info_colour, Instant::now())
+                },
+                Err(err) => (format!("Reload failed: {}", err), error_colour, Instant::now())
+            });
+        }
 
-    for i in 0..80 {
-        machine.memory[i as usize] = CHIP8_FONTSET[i as usize];
+        if let Some(path) = switch_to {
+            let opened = loader::read_rom(&path, None, &anywhere())
+                .and_then(|rom| open_session(&options, database.as_ref(), &path, rom));
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            notice = Some(match opened {
+                Ok(opened) => {
+                    session = opened;
+                    memory_view.forget(&session.machine.memory);
+                    frame = frame_of(&session.machine);
+                    runner.send(Control::Load(Box::new(session.machine.clone()), session.settings.speed));
+                    paused = false;
+                    window.set_title(session.title.clone());
+                    if watcher.is_some() {
+                        watcher = Some(watch::Watcher::new(session.path.clone()));
+                    }
+                    (format!("Opened {}", file_name), info_colour, Instant::now())
+                },
+                Err(err) => (format!("Couldn't open {}: {}", file_name, err), error_colour, Instant::now())
+            });
+        }
+
+        // the program is stopped while paused or browsing
+        if stopped != (paused || browser.is_some()) {
+            stopped = !stopped;
+            runner.send(Control::Pause(stopped));
+        }
     }
-    return machine;
+
+    finish_observers(runner.stop(), &options);
 }
 
-fn load_program (machine: Machine, program: Vec<u8>) -> Machine {
-    let mut new_machine = machine.clone();
+/// The profiler and tracer the options ask for.
+fn start_observers(options: &Options) -> Observers {
+    let profiler = options.profile.as_ref().map(|_| profile::Profiler::new());
+    let tracer = options.trace.as_ref().map(|path| {
+        trace::Tracer::create(path, options.trace_range).unwrap_or_else(|err| {
+            eprintln!("Couldn't create {}: {}", path.display(), err);
+            process::exit(1);
+        })
+    });
+    (profiler, tracer)
+}
 
-    for i in 0..program.len() {
-        new_machine.memory[i + 512] = program[i];
+/// Reports a tracing error and writes the profile once the program stopped.
+fn finish_observers((profiler, tracer): Observers, options: &Options) {
+    if let Some(err) = tracer.and_then(|mut tracer| tracer.take_error()) {
+        eprintln!("Tracing stopped: {}", err);
+    }
+    if let (Some(profiler), Some(path)) = (profiler, &options.profile) {
+        let mut folded_path = path.clone().into_os_string();
+        folded_path.push(".folded");
+        let written = fs::write(path, profiler.report())
+            .and_then(|_| fs::write(&folded_path, profiler.folded()));
+        match written {
+            Ok(()) => println!("Wrote profile to {}", path.display()),
+            Err(err) => eprintln!("Couldn't write profile to {}: {}", path.display(), err)
+        }
     }
+}
 
-    return new_machine;
+/// Runs a ROM in the terminal, for machines without a display.
+fn run_in_terminal(options: Options) {
+    let database = open_database(&options);
+    let rom = read_rom_or_exit(&options.rom, options.entry.as_deref(), &anywhere());
+    let session = open_session(&options, database.as_ref(), &options.rom, rom)
+        .unwrap_or_else(|err| exit_with_load_error(&options.rom, err));
+
+    let bell = Arc::new(AtomicBool::new(false));
+    let observers = (start_observers(&options), terminal::Bell(Arc::clone(&bell)));
+    let runner = Runner::spawn(session.machine.clone(), session.settings.speed, observers, inspector::HISTORY_ROWS);
+    let played = terminal::play(&runner, frame_of(&session.machine), &session.settings, &options, session.entry_point, &bell);
+    let (observers, _) = runner.stop();
+    finish_observers(observers, &options);
+
+    match played {
+        Ok(None) => (),
+        Ok(Some(fault)) => eprintln!("{}", fault),
+        Err(err) => {
+            eprintln!("Couldn't use the terminal: {}", err);
+            process::exit(1);
+        }
+    }
 }
 
-fn run_cycle(prev_state: Machine) -> Machine {
-    let mut next_state = prev_state.clone();
-    let pc = prev_state.pc;
-    // get the opcode
-    let opcode: OpCode = (prev_state.memory[pc as usize] as u16) << 8 | prev_state.memory[(pc+1) as usize] as u16;
-
-
-    // execute the opcode
-    match opcode & 0xF000 {
-        0x0000 => {
-            match opcode {
-                0x00E0 => {
-                    next_state.gfx = [false; 64*32];
-                    next_state.draw_flag = true;
-                    next_state.pc += 2;
-                },
-                0x00EE => {
-                    next_state.sp -= 1;
-                    next_state.pc = next_state.stack[next_state.sp as usize];
-                    next_state.pc += 2;
-                },
-                _ => {
-                    println!("Opcode: 0x0NNN");
-                    next_state.pc += 2;
-                },
-                
-            }
-        },
-        0x1000 => {
-            next_state.pc = opcode & 0x0FFF;
-        },
-        0x2000 => {
-            next_state.stack[next_state.sp as usize] = next_state.pc;
-            next_state.sp += 1;
-            next_state.pc = opcode & 0x0FFF;
-        },
-        0x3000 => {
-            let x = ((opcode & 0x0F00) >> 8) as usize;
-            let v_test = next_state.v[x];
-            if v_test == (opcode & 0x00FF) as u8 {
-                next_state.pc += 4;
-            } else {
-                next_state.pc += 2;
-            }
-        },
-        0x4000 => {
-            let x = ((opcode & 0x0F00) >> 8) as usize;
-            let v_test = next_state.v[x];
-            if v_test != (opcode & 0x00FF) as u8 {
-                next_state.pc += 4;
-            } else {
-                next_state.pc += 2;
-            }
-        },
-        0x5000 => {
-            let x = ((opcode & 0x0F00) >> 8) as usize;
-            let y = ((opcode & 0x00F0) >> 4) as usize;
-
-            if next_state.v[x] == next_state.v[y] {
-                next_state.pc += 4;
-            } else {
-                next_state.pc += 2;
-            }
-        },
-        0x6000 => {
-            let x = ((opcode & 0x0F00) >> 8) as usize;
-            next_state.v[x] = (opcode & 0x00FF) as u8;
-            next_state.pc += 2;
-        },
-        0x7000 => {
-            let x: usize = ((opcode & 0x0F00) >> 8) as usize;
-            next_state.v[x] = next_state.v[x].overflowing_add((opcode & 0x00FF) as u8).0;
-            next_state.pc += 2;
-        },
-        0x8000 => {
-            let x: usize = ((opcode & 0x0F00) >> 8) as usize;
-            let y: usize = ((opcode & 0x00F0) >> 4) as usize;
-            match opcode & 0xF00F {
-                0x8000 => {
-                    next_state.v[x] = next_state.v[y];
-                    next_state.pc += 2;
-                },
-                0x8001 => {
-                    next_state.v[x] = next_state.v[x] | next_state.v[y];
-                    next_state.pc += 2;
-                },
-                0x8002 => {
-                    next_state.v[x] = next_state.v[x] & next_state.v[y];
-                    next_state.pc += 2;
-                },
-                0x8003 => {
-                    next_state.v[x] = next_state.v[x] ^ next_state.v[y];
-                    next_state.pc += 2;
-                },
-                0x8004 => {
-                    let (val, has_overflow) = next_state.v[x].overflowing_add(next_state.v[y]);
-                    
-                    next_state.v[x] = val;
-                    next_state.v[0xF] = if has_overflow {1} else {0};
-                    next_state.pc += 2;
-                },
-                0x8005 => {
-                    let (val, has_overflow) = next_state.v[x].overflowing_sub(next_state.v[y]);
-                    
-                    next_state.v[x] = val;
-                    next_state.v[0xF] = if has_overflow {0} else {1};
-                    next_state.pc += 2;
-                },
-                0x8006 => {
-                    next_state.v[0xF] = next_state.v[x] & 0x01;
-                    next_state.v[x] = next_state.v[x] >> 1;
-                    next_state.pc += 2;
-                },
-                0x8007 => {
-                    let (val, has_overflow) = next_state.v[y].overflowing_sub(next_state.v[x]);
-                    
-                    next_state.v[x] = val;
-                    next_state.v[0xF] = if has_overflow {0} else {1};
-                    next_state.pc += 2;
-                },
-                0x800E => {
-                    next_state.v[0xF] = next_state.v[x] & 0x80;
-                    next_state.v[x] = next_state.v[x] << 1;
-                    next_state.pc += 2;
-                },
-                _ => {
-                    println!("Unknown opcode: {:X}", opcode);
-                    std::process::exit(0);
-                }
-            }
-        },
-        0x9000 => {
-            let x: usize = ((opcode & 0x0F00) >> 8) as usize;
-            let y: usize = ((opcode & 0x00F0) >> 4) as usize;
-            if next_state.v[x] != next_state.v[y] {
-                next_state.pc += 4;
-            } else {
-                next_state.pc += 2;
-            }
-        },
-        0xA000 => {
-            next_state.i = opcode & 0x0FFF;
-            next_state.pc += 2;
-        },
-        0xB000 => {
-            next_state.pc = (opcode & 0x0FFF) + next_state.v[0] as u16;
-        },
-        0xC000 => {
-            let x: usize = ((opcode & 0x0F00) >> 8) as usize;
+/// The hex digit on a keyboard key, for typing addresses and bytes.
+fn hex_digit(key: Key) -> Option<u8> {
+    let code = key.code();
+    match key {
+        Key::D0 | Key::D1 | Key::D2 | Key::D3 | Key::D4 |
+        Key::D5 | Key::D6 | Key::D7 | Key::D8 | Key::D9 => Some((code - Key::D0.code()) as u8),
+        Key::A | Key::B | Key::C | Key::D | Key::E | Key::F => Some((code - Key::A.code()) as u8 + 10),
+        _ => None
+    }
+}
 
-            let mut rng = rand::thread_rng();
-            let rand_val: u8 = rng.gen_range(0, 255);
-            let rand_mask = (opcode & 0x00FF) as u8;
+/// A frame to show until the core sends its first, or after loading another machine.
+fn frame_of(machine: &Machine) -> Frame {
+    Frame { machine: machine.clone(), previous: Registers::of(machine), history: Vec::new(), fault: None, notice: None }
+}
 
-            next_state.v[x] = rand_val & rand_mask;
-            next_state.pc += 2;
-        },
-        0xD000 => {
-            let x : u16 = next_state.v[((opcode & 0x0F00) >> 8) as usize] as u16 %64;
-            let y : u16 = next_state.v[((opcode & 0x00F0) >> 4) as usize] as u16 %32;
-
-            let height = opcode & 0x000F;
-
-            let mut pixel: u8;
-            let i = next_state.i;
-            
-            next_state.v[0xF] = 0;
-            // draw the sprite
-            'rows: for yline in 0..height {
-                if y + yline > 32 {
-                    break 'rows;
-                }
-                pixel = next_state.memory[(i + yline) as usize];
-                'cols: for xline in 0..8 {
-                    if x + xline > 64 {
-                        break 'cols;
-                    }
-                    if (pixel & (0x80 >> xline)) != 0 {
-                        if next_state.gfx[(x+xline + (y+yline)*64) as usize] == true {
-                            next_state.v[0xF] = 1;
-                        }
-                        next_state.gfx[(x+xline + (y+yline)*64) as usize] = !next_state.gfx[(x+xline + (y+yline)*64) as usize];
-                    } 
-                }
-            }
-            next_state.draw_flag = true;
-            next_state.pc += 2;
-        },
-        0xE000 => {
-            let x: usize = ((opcode & 0x0F00) >> 8) as usize;
-            match opcode & 0xF0FF {
-                0xE09E => {
-                    if next_state.key[next_state.v[x] as usize] {
-                        next_state.pc += 4;
-                    } else {
-                        next_state.pc += 2;
-                    }
-                },
-                0xE0A1 => {
-                    if !next_state.key[next_state.v[x] as usize]  {
-                        next_state.pc += 4;
-                    } else {
-                        next_state.pc += 2;
-                    }
-                },
-                _ => {
-                    println!("Unknown opcode: {:X}", opcode);
-                    std::process::exit(0);
-                }
-            }
-        },
-        0xF000 => {
-            let x = ((opcode & 0x0F00) >> 8) as usize;
-            match opcode & 0xF0FF {
-                0xF007 => {
-                    next_state.v[x] = next_state.delay_timer;
-                    next_state.pc += 2;
-                },
-                0xF00A => {
-                    next_state.await_keypress = true;
-                    next_state.keypress_register = x as u8;
-                    next_state.pc += 2;
-                },
-                0xF015 => {
-                    next_state.delay_timer = next_state.v[x];
-                    next_state.pc += 2;
-                },
-                0xF018 => {
-                    next_state.sound_timer = next_state.v[x];
-                    next_state.pc += 2;
-                },
-                0xF01E => {
-                    let (val, has_overflow) = next_state.i.overflowing_add(next_state.v[x] as u16);
-                    next_state.i = val;
-                    next_state.v[0xF] = if has_overflow {1} else {0};
-                    next_state.pc += 2;
-                },
-                0xF029 => {
-                    next_state.i = next_state.v[x] as u16 * 5;
-                    next_state.pc += 2;
-                },
-                0xF033 => {
-                    let i = next_state.i as usize;
-                    next_state.memory[i] = next_state.v[x] / 100;
-                    next_state.memory[i+1] = (next_state.v[x] / 10) % 10;
-                    next_state.memory[i+2] = next_state.v[x] % 10;
-                    next_state.pc += 2;
-                }
-                0xF055 => {
-                    for offset in 0..=x {
-                        next_state.memory[next_state.i as usize + offset] = next_state.v[offset];
-                    }
-                    next_state.i += x as u16 + 1;
-                    next_state.pc += 2;
-                },
-                0xF065 => {
-                    for offset in 0..=x {
-                        next_state.v[offset] = next_state.memory[next_state.i as usize + offset];
-                    }
-                    next_state.i += x as u16 + 1;
-                    next_state.pc += 2;
-                },
-                _ => {
-                    println!("Unknown opcode: {:X}", opcode);
-                    std::process::exit(0);
-                }
-            }
-        },
-        _ => {
-            println!("Unknown opcode: {:X}", opcode);
-            std::process::exit(0);
+/// Presses or releases the keypad keys bound to a keyboard event.
+fn press_keypad<O>(event: &Event, runner: &Runner<O>, keymap: &[Key], settings: &Settings) {
+    let game_keys: Vec<(Key, u8)> = settings.game_keys.iter()
+        .filter_map(|(name, k)| game_key(name).map(|key| (key, *k)))
+        .collect();
+    if let Some(Button::Keyboard(key)) = event.press_args() {
+        if let Some(n) = keymap.iter().position(|&k| k == key) {
+            runner.send(Control::Key(KEYPAD_LAYOUT[n], true));
+        }
+        if let Some(&(_, k)) = game_keys.iter().find(|&&(game_key, _)| game_key == key) {
+            runner.send(Control::Key(k, true));
+        }
+    }
+    if let Some(Button::Keyboard(key)) = event.release_args() {
+        if let Some(n) = keymap.iter().position(|&k| k == key) {
+            runner.send(Control::Key(KEYPAD_LAYOUT[n], false));
+        }
+        if let Some(&(_, k)) = game_keys.iter().find(|&&(game_key, _)| game_key == key) {
+            runner.send(Control::Key(k, false));
         }
     }
-    return next_state;
 }
 
-fn print_mem(machine: Machine) {
+fn print_mem(machine: &Machine) {
     let mem = machine.memory;
     
     println!("0x000 to 0x050:");
     for i in 0..80 {
         print!("0x{:0>4X} ", mem[i as usize]);
         if (i+1)%5 == 0 {
-            print!("\n");
+            println!();
         }
     }
 
@@ -542,20 +688,20 @@ fn print_mem(machine: Machine) {
     for i in 512..4096 {
         print!("0x{:0>4X} ", mem[i as usize]);
         if (i+1)%8 == 0 {
-            print!("\n");
+            println!();
         }
     }
 
 }
 
-fn _print_gfx(machine:Machine) {
-    let gfx = machine.gfx;
+fn _print_gfx(machine: &Machine) {
+    let gfx = machine.gfx.to_bools();
 
     for i in 0..32 {
         for j in 0..64 {
             print!("{}", if gfx[i*64 + j] {"X"} else {" "});
         }
-        print!("\n");
+        println!();
     }
-    print!("\n");
-}
\ No newline at end of file
+    println!();
+}
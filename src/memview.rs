@@ -0,0 +1,276 @@
+//! The hex view of memory under the display. It follows PC or I, marks
+//! bytes the program wrote recently, and can jump to an address or poke
+//! new values.
+
+use chip8::{Machine, MEMORY_SIZE};
+
+pub const BYTES_PER_ROW: usize = 16;
+pub const ROWS: usize = 10;
+
+/// Frames a written byte stays highlighted.
+const WRITE_HIGHLIGHT_FRAMES: u8 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Follow {
+    Pc,
+    I,
+    /// Stay where scrolling or a jump left the view.
+    Fixed
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Highlight {
+    None,
+    /// Part of the instruction at PC.
+    Pc,
+    I,
+    Written,
+    Cursor
+}
+
+pub struct Row {
+    pub address: usize,
+    pub bytes: Vec<(u8, Highlight)>
+}
+
+pub struct MemoryView {
+    pub follow: Follow,
+    /// First address shown, a multiple of `BYTES_PER_ROW`.
+    top: usize,
+    pub cursor: usize,
+    /// The high nibble typed at the cursor, waiting for the low one.
+    pending: Option<u8>,
+    /// Digits of an address being typed for a jump.
+    pub jump: Option<String>,
+    shadow: [u8; MEMORY_SIZE],
+    age: [u8; MEMORY_SIZE]
+}
+
+impl MemoryView {
+    pub fn new(memory: &[u8; MEMORY_SIZE]) -> MemoryView {
+        MemoryView {
+            follow: Follow::Pc,
+            top: 0,
+            cursor: 0,
+            pending: None,
+            jump: None,
+            shadow: *memory,
+            age: [0; MEMORY_SIZE]
+        }
+    }
+
+    /// Starts over with new memory contents, e.g. after a reload, without
+    /// marking every byte as written.
+    pub fn forget(&mut self, memory: &[u8; MEMORY_SIZE]) {
+        self.shadow = *memory;
+        self.age = [0; MEMORY_SIZE];
+        self.pending = None;
+    }
+
+    /// Called once a frame: marks bytes written since the last call, fades
+    /// older marks and scrolls to whatever the view follows.
+    pub fn observe(&mut self, machine: &Machine) {
+        for address in 0..MEMORY_SIZE {
+            if machine.memory[address] != self.shadow[address] {
+                self.age[address] = WRITE_HIGHLIGHT_FRAMES;
+            } else {
+                self.age[address] = self.age[address].saturating_sub(1);
+            }
+        }
+        self.shadow = machine.memory;
+
+        match self.follow {
+            Follow::Pc => self.show(machine.pc as usize),
+            Follow::I => self.show(machine.i as usize),
+            Follow::Fixed => ()
+        }
+    }
+
+    pub fn rows(&self, machine: &Machine, editing: bool) -> Vec<Row> {
+        (0..ROWS).map(|row| {
+            let address = self.top + row * BYTES_PER_ROW;
+            let bytes = (address..address + BYTES_PER_ROW).map(|a| {
+                let highlight = if editing && a == self.cursor {
+                    Highlight::Cursor
+                } else if a == machine.pc as usize || a == machine.pc as usize + 1 {
+                    Highlight::Pc
+                } else if self.age[a] > 0 {
+                    Highlight::Written
+                } else if a == machine.i as usize {
+                    Highlight::I
+                } else {
+                    Highlight::None
+                };
+                (machine.memory[a], highlight)
+            }).collect();
+            Row { address, bytes }
+        }).collect()
+    }
+
+    pub fn cycle_follow(&mut self) {
+        self.follow = match self.follow {
+            Follow::Pc => Follow::I,
+            Follow::I => Follow::Fixed,
+            Follow::Fixed => Follow::Pc
+        };
+    }
+
+    pub fn scroll(&mut self, rows: isize) {
+        self.follow = Follow::Fixed;
+        let top = self.top as isize + rows * BYTES_PER_ROW as isize;
+        self.top = top.clamp(0, last_top() as isize) as usize;
+    }
+
+    pub fn move_cursor(&mut self, bytes: isize) {
+        self.follow = Follow::Fixed;
+        self.pending = None;
+        self.cursor = (self.cursor as isize + bytes).clamp(0, MEMORY_SIZE as isize - 1) as usize;
+        self.show(self.cursor);
+    }
+
+    /// Takes a hex digit for the jump prompt if it's open, otherwise for
+    /// the byte under the cursor: the second digit moves on and returns the
+    /// address and byte to poke.
+    pub fn type_digit(&mut self, digit: u8) -> Option<(u16, u8)> {
+        if let Some(jump) = &mut self.jump {
+            if jump.len() < 3 {
+                jump.push_str(&format!("{:X}", digit));
+            }
+            return None;
+        }
+        match self.pending.take() {
+            None => {
+                self.pending = Some(digit);
+                None
+            },
+            Some(high) => {
+                let address = self.cursor as u16;
+                self.move_cursor(1);
+                Some((address, high << 4 | digit))
+            }
+        }
+    }
+
+    pub fn toggle_jump(&mut self) {
+        self.jump = match self.jump {
+            Some(_) => None,
+            None => Some(String::new())
+        };
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(jump) = &mut self.jump {
+            jump.pop();
+        }
+        self.pending = None;
+    }
+
+    /// Moves the cursor to the address typed at the prompt and closes it.
+    pub fn confirm_jump(&mut self) {
+        if let Some(address) = self.jump.take().and_then(|jump| usize::from_str_radix(&jump, 16).ok()) {
+            self.follow = Follow::Fixed;
+            self.cursor = address.min(MEMORY_SIZE - 1);
+            self.pending = None;
+            self.top = (self.cursor / BYTES_PER_ROW * BYTES_PER_ROW).min(last_top());
+        }
+    }
+
+    /// Scrolls just enough to bring `address` into view.
+    fn show(&mut self, address: usize) {
+        let row_start = address / BYTES_PER_ROW * BYTES_PER_ROW;
+        if row_start < self.top {
+            self.top = row_start;
+        } else if row_start >= self.top + ROWS * BYTES_PER_ROW {
+            self.top = (row_start + BYTES_PER_ROW).saturating_sub(ROWS * BYTES_PER_ROW);
+        }
+        self.top = self.top.min(last_top());
+    }
+}
+
+fn last_top() -> usize {
+    MEMORY_SIZE - ROWS * BYTES_PER_ROW
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::Config;
+
+    #[test]
+    fn follows_pc_and_marks_writes() {
+        let mut machine = Machine::new(Config::default());
+        let mut view = MemoryView::new(&machine.memory);
+        machine.pc = 0x400;
+        machine.memory[0x404] = 0xAA;
+        view.observe(&machine);
+
+        let rows = view.rows(&machine, false);
+        assert_eq!(rows.last().unwrap().address, 0x400);
+        let last = &rows.last().unwrap().bytes;
+        assert_eq!(last[0].1, Highlight::Pc);
+        assert_eq!(last[1].1, Highlight::Pc);
+        assert_eq!(last[4], (0xAA, Highlight::Written));
+
+        for _ in 0..WRITE_HIGHLIGHT_FRAMES {
+            view.observe(&machine);
+        }
+        assert_eq!(view.rows(&machine, false).last().unwrap().bytes[4].1, Highlight::None);
+    }
+
+    #[test]
+    fn follows_i_once_cycled() {
+        let mut machine = Machine::new(Config::default());
+        let mut view = MemoryView::new(&machine.memory);
+        view.cycle_follow();
+        machine.i = 0x123;
+        view.observe(&machine);
+        let rows = view.rows(&machine, false);
+        let row = rows.iter().find(|row| row.address == 0x120).unwrap();
+        assert_eq!(row.bytes[3].1, Highlight::I);
+    }
+
+    #[test]
+    fn jumps_to_a_typed_address() {
+        let machine = Machine::new(Config::default());
+        let mut view = MemoryView::new(&machine.memory);
+        view.toggle_jump();
+        for digit in [0x8, 0x4, 0x2, 0x1] {
+            assert_eq!(view.type_digit(digit), None);
+        }
+        assert_eq!(view.jump.as_deref(), Some("842"));
+        view.backspace();
+        view.type_digit(0x3);
+        view.confirm_jump();
+
+        assert_eq!(view.jump, None);
+        assert_eq!(view.cursor, 0x843);
+        assert_eq!(view.follow, Follow::Fixed);
+        assert_eq!(view.rows(&machine, false)[0].address, 0x840);
+    }
+
+    #[test]
+    fn pokes_bytes_at_the_cursor() {
+        let mut machine = Machine::new(Config::default());
+        let mut view = MemoryView::new(&machine.memory);
+        view.move_cursor(0x300);
+        for digit in [0xD, 0x0, 0x0, 0x1] {
+            if let Some((address, value)) = view.type_digit(digit) {
+                machine.poke(address, value);
+            }
+        }
+        assert_eq!(machine.memory[0x300..0x302], [0xD0, 0x01]);
+        assert_eq!(view.cursor, 0x302);
+        assert_eq!(view.rows(&machine, true).iter().flat_map(|row| &row.bytes)
+            .filter(|(_, highlight)| *highlight == Highlight::Cursor).count(), 1);
+    }
+
+    #[test]
+    fn scrolling_stays_in_memory() {
+        let machine = Machine::new(Config::default());
+        let mut view = MemoryView::new(&machine.memory);
+        view.scroll(-5);
+        assert_eq!(view.rows(&machine, false)[0].address, 0);
+        view.scroll(1000);
+        assert_eq!(view.rows(&machine, false).last().unwrap().address, MEMORY_SIZE - BYTES_PER_ROW);
+    }
+}
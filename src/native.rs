@@ -0,0 +1,66 @@
+//! A bare window for programs made by `chip8 recompile`: the display, the
+//! default keymap and the timers, without the emulator's debugging panels.
+
+use piston_window::*;
+
+use crate::cli::DEFAULT_KEYMAP;
+use crate::display;
+use crate::palette::Palette;
+use crate::{Fault, Machine, KEYPAD_LAYOUT};
+
+/// Window pixels per CHIP-8 pixel.
+const SCALE: f64 = 12.0;
+
+/// Runs `machine` in a window until it is closed or faults. `run_block`
+/// runs at least one instruction from PC and returns how many it ran;
+/// `speed` of them are run per second, with the timers ticking at 60 Hz.
+pub fn play(title: &str, mut machine: Machine, speed: u32, palette: Palette, run_block: fn(&mut Machine) -> Result<u32, Fault>) {
+    let size = [display::WIDTH as f64 * SCALE, display::HEIGHT as f64 * SCALE];
+    let mut window: PistonWindow = WindowSettings::new(title, size)
+        .exit_on_esc(true).build().unwrap();
+
+    let mut event_settings = EventSettings::new();
+    event_settings.set_ups(60);
+    event_settings.set_max_fps(60);
+    window.set_event_settings(event_settings);
+
+    let keymap: Vec<Key> = DEFAULT_KEYMAP.chars().map(|c| Key::from(c as u32)).collect();
+    let per_frame = (speed / 60).max(1);
+
+    while let Some(event) = window.next() {
+        if let Some(Button::Keyboard(key)) = event.press_args() {
+            if let Some(n) = keymap.iter().position(|&k| k == key) {
+                machine.key[KEYPAD_LAYOUT[n] as usize] = true;
+            }
+        }
+        if let Some(Button::Keyboard(key)) = event.release_args() {
+            if let Some(n) = keymap.iter().position(|&k| k == key) {
+                machine.key[KEYPAD_LAYOUT[n] as usize] = false;
+            }
+        }
+
+        if event.update_args().is_some() {
+            let mut ran = 0;
+            while ran < per_frame {
+                match run_block(&mut machine) {
+                    Ok(count) => ran += count,
+                    Err(fault) => {
+                        eprintln!("{}", fault);
+                        return;
+                    }
+                }
+            }
+            machine.tick_timers();
+        }
+
+        window.draw_2d(&event, |context, graphics, _| {
+            clear(palette.background, graphics);
+            for (x, y) in machine.gfx.lit_pixels() {
+                rectangle(palette.foreground,
+                    [x as f64 * SCALE, y as f64 * SCALE, SCALE, SCALE],
+                    context.transform,
+                    graphics);
+            }
+        });
+    }
+}
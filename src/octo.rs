@@ -0,0 +1,581 @@
+//! A CHIP-8 assembler for Octo source, which is what Octo cartridges store.
+//!
+//! It covers the CHIP-8 half of the language: labels (`: name`), `:const`,
+//! `:alias`, `:org`, `:byte` and `:call`, every CHIP-8 instruction in Octo's
+//! notation (`v0 := 5`, `i := sprite`, `sprite v0 v1 5`, ...), `if ... then`,
+//! `if ... begin ... else ... end`, `loop ... while ... again`, calls by
+//! naming a label, and bare numbers as data. Macros, `:calc`, `:next`,
+//! `:unpack`, the `<` and `>` comparisons and the SUPER-CHIP and XO-CHIP
+//! instructions are refused with an error naming them.
+//!
+//! Programs start at 0x200. As in Octo, when `main` isn't the first thing
+//! in the program, 0x200 holds a jump to it. Source without a `main`, such
+//! as the byte listings Octo saves for imported binaries, is laid out from
+//! 0x200 as it stands.
+
+use std::collections::HashMap;
+
+use crate::MEMORY_SIZE;
+
+const ORIGIN: u16 = 0x200;
+
+/// Assembles `source` into the bytes to load at 0x200.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let tokens: Vec<(usize, &str)> = source.lines()
+        .enumerate()
+        .flat_map(|(n, line)| line.split('#').next().unwrap_or("").split_whitespace().map(move |token| (n + 1, token)))
+        .collect();
+
+    // try without the jump first; it's only needed when main isn't at 0x200
+    let assembler = Assembler::run(&tokens, false)?;
+    match assembler.labels.get("main") {
+        Some(&main) if main != ORIGIN => Assembler::run(&tokens, true)?.finish(),
+        _ => assembler.finish()
+    }
+}
+
+/// An instruction whose address is a label defined later.
+struct Fixup<'a> {
+    line: usize,
+    at: u16,
+    label: &'a str
+}
+
+struct Assembler<'a> {
+    tokens: &'a [(usize, &'a str)],
+    next: usize,
+    /// Bytes written so far, by address.
+    memory: Vec<Option<u8>>,
+    here: u16,
+    labels: HashMap<&'a str, u16>,
+    constants: HashMap<&'a str, i64>,
+    aliases: HashMap<&'a str, u8>,
+    fixups: Vec<Fixup<'a>>,
+    /// The jumps of open `begin`s and `else`s, to point past their blocks.
+    branches: Vec<(usize, u16)>,
+    /// Where each open `loop` starts, and the jumps its `while`s leave by.
+    loops: Vec<(usize, u16, Vec<u16>)>
+}
+
+/// A condition in an `if` or `while`.
+enum Condition {
+    Equal(u8, Operand),
+    NotEqual(u8, Operand),
+    KeyDown(u8),
+    KeyUp(u8)
+}
+
+enum Operand {
+    Byte(u8),
+    Register(u8)
+}
+
+impl Condition {
+    /// The instruction that skips the next one when the condition holds.
+    fn skip_if(&self) -> u16 {
+        match *self {
+            Condition::Equal(x, Operand::Byte(nn)) => 0x3000 | (x as u16) << 8 | nn as u16,
+            Condition::NotEqual(x, Operand::Byte(nn)) => 0x4000 | (x as u16) << 8 | nn as u16,
+            Condition::Equal(x, Operand::Register(y)) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::NotEqual(x, Operand::Register(y)) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::KeyDown(x) => 0xE09E | (x as u16) << 8,
+            Condition::KeyUp(x) => 0xE0A1 | (x as u16) << 8
+        }
+    }
+
+    fn negated(self) -> Condition {
+        match self {
+            Condition::Equal(x, operand) => Condition::NotEqual(x, operand),
+            Condition::NotEqual(x, operand) => Condition::Equal(x, operand),
+            Condition::KeyDown(x) => Condition::KeyUp(x),
+            Condition::KeyUp(x) => Condition::KeyDown(x)
+        }
+    }
+}
+
+impl<'a> Assembler<'a> {
+    /// Assembles every statement, starting with a jump to main if asked.
+    fn run(tokens: &'a [(usize, &'a str)], jump_to_main: bool) -> Result<Assembler<'a>, String> {
+        let mut assembler = Assembler {
+            tokens,
+            next: 0,
+            memory: vec![None; MEMORY_SIZE],
+            here: ORIGIN,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            fixups: Vec::new(),
+            branches: Vec::new(),
+            loops: Vec::new()
+        };
+        if jump_to_main {
+            assembler.address_op(0x1000, "main")?;
+        }
+        while assembler.next < tokens.len() {
+            assembler.statement()?;
+        }
+        Ok(assembler)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens.get(self.next.saturating_sub(1)).map_or(0, |&(line, _)| line)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line(), message))
+    }
+
+    fn token(&mut self) -> Result<&'a str, String> {
+        match self.tokens.get(self.next) {
+            Some(&(_, token)) => {
+                self.next += 1;
+                Ok(token)
+            },
+            None => self.error("unexpected end of program".to_owned())
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).map(|&(_, token)| token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.token()?;
+        if token != expected {
+            return self.error(format!("expected '{}', found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), String> {
+        if self.here as usize >= MEMORY_SIZE {
+            return self.error("the program runs past the end of memory".to_owned());
+        }
+        self.memory[self.here as usize] = Some(byte);
+        self.here += 1;
+        Ok(())
+    }
+
+    fn op(&mut self, opcode: u16) -> Result<(), String> {
+        self.emit((opcode >> 8) as u8)?;
+        self.emit(opcode as u8)
+    }
+
+    /// An instruction taking a 12-bit address, which may be a label not
+    /// defined yet.
+    fn address_op(&mut self, opcode: u16, target: &'a str) -> Result<(), String> {
+        match self.labels.get(target).copied().map(i64::from).or_else(|| self.constant(target)) {
+            Some(address) => self.op(opcode | self.check(address, 0xFFF)?),
+            None if is_identifier(target) => {
+                self.fixups.push(Fixup { line: self.line(), at: self.here, label: target });
+                self.op(opcode)
+            },
+            None => self.error(format!("expected an address, found '{}'", target))
+        }
+    }
+
+    /// A jump to be pointed somewhere later; returns its address.
+    fn placeholder_jump(&mut self) -> Result<u16, String> {
+        let at = self.here;
+        self.op(0x1000)?;
+        Ok(at)
+    }
+
+    fn patch(&mut self, at: u16, target: u16) {
+        let high = self.memory[at as usize].unwrap_or(0) & 0xF0;
+        self.memory[at as usize] = Some(high | (target >> 8) as u8);
+        self.memory[at as usize + 1] = Some(target as u8);
+    }
+
+    fn constant(&self, token: &str) -> Option<i64> {
+        self.constants.get(token).copied().or_else(|| number(token))
+    }
+
+    fn check(&self, value: i64, max: u16) -> Result<u16, String> {
+        if value < 0 || value > max as i64 {
+            return self.error(format!("{} doesn't fit in 0..={}", value, max));
+        }
+        Ok(value as u16)
+    }
+
+    fn value(&mut self, max: u16) -> Result<u16, String> {
+        let token = self.token()?;
+        match self.constant(token) {
+            Some(value) => self.check(value, max),
+            None => self.error(format!("expected a number, found '{}'", token))
+        }
+    }
+
+    /// A byte, where negative numbers down to -128 stand for their two's
+    /// complement.
+    fn byte(&mut self, token: &str) -> Result<u8, String> {
+        match self.constant(token) {
+            Some(value) if (-128..=255).contains(&value) => Ok(value as u8),
+            Some(value) => self.error(format!("{} doesn't fit in a byte", value)),
+            None => self.error(format!("expected a number, found '{}'", token))
+        }
+    }
+
+    fn register_of(&self, token: &str) -> Option<u8> {
+        self.aliases.get(token).copied().or_else(|| register(token))
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.token()?;
+        match self.register_of(token) {
+            Some(x) => Ok(x),
+            None => self.error(format!("expected a register, found '{}'", token))
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let token = self.token()?;
+        match self.register_of(token) {
+            Some(y) => Ok(Operand::Register(y)),
+            None => Ok(Operand::Byte(self.byte(token)?))
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.register()?;
+        match self.token()? {
+            "==" => Ok(Condition::Equal(x, self.operand()?)),
+            "!=" => Ok(Condition::NotEqual(x, self.operand()?)),
+            "key" => Ok(Condition::KeyDown(x)),
+            "-key" => Ok(Condition::KeyUp(x)),
+            comparison @ ("<" | ">" | "<=" | ">=") => {
+                self.error(format!("'{}' comparisons aren't supported; use == and != with vf", comparison))
+            },
+            token => self.error(format!("expected a comparison, found '{}'", token))
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.token()?;
+        if let Some(x) = self.register_of(token) {
+            return self.assignment(x);
+        }
+        match token {
+            ":" => {
+                let name = self.token()?;
+                if !is_identifier(name) || self.labels.insert(name, self.here).is_some() {
+                    return self.error(format!("can't define the label '{}'", name));
+                }
+            },
+            ":const" => {
+                let name = self.token()?;
+                let value = self.token()?;
+                let value = match self.constant(value).or_else(|| self.labels.get(value).map(|&address| address as i64)) {
+                    Some(value) => value,
+                    None => return self.error(format!("expected a number, found '{}'", value))
+                };
+                self.constants.insert(name, value);
+            },
+            ":alias" => {
+                let name = self.token()?;
+                let x = self.register()?;
+                self.aliases.insert(name, x);
+            },
+            ":org" => {
+                self.here = self.value(0xFFF)?;
+                if self.here < ORIGIN {
+                    return self.error(format!(":org 0x{:03X} is below the program at 0x200", self.here));
+                }
+            },
+            ":byte" => {
+                let value = self.token()?;
+                let byte = self.byte(value)?;
+                self.emit(byte)?;
+            },
+            ":call" => {
+                let target = self.token()?;
+                self.address_op(0x2000, target)?;
+            },
+            ":breakpoint" => {
+                self.token()?;
+            },
+            ":monitor" => {
+                self.token()?;
+                self.token()?;
+            },
+            ";" | "return" => self.op(0x00EE)?,
+            "clear" => self.op(0x00E0)?,
+            "jump" => {
+                let target = self.token()?;
+                self.address_op(0x1000, target)?;
+            },
+            "jump0" => {
+                let target = self.token()?;
+                self.address_op(0xB000, target)?;
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.value(15)?;
+                self.op(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n)?;
+            },
+            "bcd" => {
+                let x = self.register()?;
+                self.op(0xF033 | (x as u16) << 8)?;
+            },
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    return self.error(format!("'{} vx - vy' is XO-CHIP, which isn't supported", token));
+                }
+                self.op(if token == "save" { 0xF055 } else { 0xF065 } | (x as u16) << 8)?;
+            },
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.op(if token == "delay" { 0xF015 } else { 0xF018 } | (x as u16) << 8)?;
+            },
+            "i" => match self.token()? {
+                ":=" => {
+                    let target = self.token()?;
+                    if target == "hex" {
+                        let x = self.register()?;
+                        self.op(0xF029 | (x as u16) << 8)?;
+                    } else if target == "bighex" || target == "long" {
+                        return self.error(format!("'i := {}' isn't CHIP-8 and isn't supported", target));
+                    } else {
+                        self.address_op(0xA000, target)?;
+                    }
+                },
+                "+=" => {
+                    let x = self.register()?;
+                    self.op(0xF01E | (x as u16) << 8)?;
+                },
+                other => return self.error(format!("expected ':=' or '+=' after i, found '{}'", other))
+            },
+            "if" => {
+                let condition = self.condition()?;
+                match self.token()? {
+                    "then" => self.op(condition.negated().skip_if())?,
+                    "begin" => {
+                        self.op(condition.skip_if())?;
+                        let jump = self.placeholder_jump()?;
+                        self.branches.push((self.line(), jump));
+                    },
+                    other => return self.error(format!("expected 'then' or 'begin', found '{}'", other))
+                }
+            },
+            "else" => {
+                let (_, jump) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return self.error("'else' without 'begin'".to_owned())
+                };
+                let past = self.placeholder_jump()?;
+                self.patch(jump, self.here);
+                self.branches.push((self.line(), past));
+            },
+            "end" => match self.branches.pop() {
+                Some((_, jump)) => self.patch(jump, self.here),
+                None => return self.error("'end' without 'begin'".to_owned())
+            },
+            "loop" => self.loops.push((self.line(), self.here, Vec::new())),
+            "while" => {
+                let condition = self.condition()?;
+                self.op(condition.skip_if())?;
+                let exit = self.placeholder_jump()?;
+                match self.loops.last_mut() {
+                    Some((_, _, exits)) => exits.push(exit),
+                    None => return self.error("'while' outside a loop".to_owned())
+                }
+            },
+            "again" => {
+                let (_, start, exits) = match self.loops.pop() {
+                    Some(open) => open,
+                    None => return self.error("'again' without 'loop'".to_owned())
+                };
+                self.op(0x1000 | start)?;
+                for exit in exits {
+                    self.patch(exit, self.here);
+                }
+            },
+            ":macro" | ":calc" | ":next" | ":unpack" | ":stringmode" | ":assert" | ":pointer" => {
+                return self.error(format!("'{}' isn't supported", token));
+            },
+            "hires" | "lores" | "exit" | "scroll-down" | "scroll-up" | "scroll-left" | "scroll-right" |
+            "saveflags" | "loadflags" | "plane" | "audio" | "pitch" => {
+                return self.error(format!("'{}' is SUPER-CHIP or XO-CHIP, which isn't supported", token));
+            },
+            _ => match self.constant(token) {
+                Some(_) => {
+                    let byte = self.byte(token)?;
+                    self.emit(byte)?;
+                },
+                // naming a label calls it
+                None if is_identifier(token) => self.address_op(0x2000, token)?,
+                None => return self.error(format!("unexpected '{}'", token))
+            }
+        }
+        Ok(())
+    }
+
+    /// `vx := ...`, `vx += ...` and the other register operators.
+    fn assignment(&mut self, x: u8) -> Result<(), String> {
+        let x16 = (x as u16) << 8;
+        let operator = self.token()?;
+        let token = self.token()?;
+        let y = self.register_of(token).map(|y| (y as u16) << 4);
+        let opcode = match (operator, y) {
+            (":=", Some(y)) => 0x8000 | x16 | y,
+            ("|=", Some(y)) => 0x8001 | x16 | y,
+            ("&=", Some(y)) => 0x8002 | x16 | y,
+            ("^=", Some(y)) => 0x8003 | x16 | y,
+            ("+=", Some(y)) => 0x8004 | x16 | y,
+            ("-=", Some(y)) => 0x8005 | x16 | y,
+            (">>=", Some(y)) => 0x8006 | x16 | y,
+            ("=-", Some(y)) => 0x8007 | x16 | y,
+            ("<<=", Some(y)) => 0x800E | x16 | y,
+            (":=", None) => match token {
+                "random" => {
+                    let mask = self.token()?;
+                    0xC000 | x16 | self.byte(mask)? as u16
+                },
+                "delay" => 0xF007 | x16,
+                "key" => 0xF00A | x16,
+                _ => 0x6000 | x16 | self.byte(token)? as u16
+            },
+            ("+=", None) => 0x7000 | x16 | self.byte(token)? as u16,
+            ("-=", None) => 0x7000 | x16 | (self.byte(token)? as u16).wrapping_neg() & 0xFF,
+            _ => return self.error(format!("can't assemble 'v{:X} {} {}'", x, operator, token))
+        };
+        self.op(opcode)
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        if let Some(&(line, _)) = self.branches.last() {
+            return Err(format!("line {}: 'begin' without 'end'", line));
+        }
+        if let Some(&(line, _, _)) = self.loops.last() {
+            return Err(format!("line {}: 'loop' without 'again'", line));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let address = match self.labels.get(fixup.label) {
+                Some(&address) => address,
+                None => return Err(format!("line {}: undefined label '{}'", fixup.line, fixup.label))
+            };
+            self.patch(fixup.at, address);
+        }
+
+        let end = match self.memory.iter().rposition(Option::is_some) {
+            Some(last) => last + 1,
+            None => return Ok(Vec::new())
+        };
+        Ok(self.memory[ORIGIN as usize..end].iter().map(|byte| byte.unwrap_or(0)).collect())
+    }
+}
+
+fn register(token: &str) -> Option<u8> {
+    let digit = token.strip_prefix('v').or_else(|| token.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn number(token: &str) -> Option<i64> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token)
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_identifier(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') &&
+        token.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)) &&
+        register(token).is_none() && number(token).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(bytes: &[u8]) -> Vec<u16> {
+        bytes.chunks(2).map(|pair| (pair[0] as u16) << 8 | *pair.get(1).unwrap_or(&0) as u16).collect()
+    }
+
+    #[test]
+    fn assembles_every_chip8_instruction() {
+        let source = "
+            : main
+            clear return ; jump 0x234 jump0 0x345 :call 0x456
+            v1 := 0x12 v1 += 3 v1 -= 1 v1 := v2 v1 |= v2 v1 &= v2 v1 ^= v2
+            v1 += v2 v1 -= v2 v1 >>= v2 v1 =- v2 v1 <<= v2
+            i := 0x567 v1 := random 0x0F sprite v1 v2 5
+            v1 := delay v1 := key delay := v1 buzzer := v1
+            i += v1 i := hex v1 bcd v1 save v1 load v1
+        ";
+        assert_eq!(words(&assemble(source).unwrap()), vec![
+            0x00E0, 0x00EE, 0x00EE, 0x1234, 0xB345, 0x2456,
+            0x6112, 0x7103, 0x71FF, 0x8120, 0x8121, 0x8122, 0x8123,
+            0x8124, 0x8125, 0x8126, 0x8127, 0x812E,
+            0xA567, 0xC10F, 0xD125,
+            0xF107, 0xF10A, 0xF115, 0xF118,
+            0xF11E, 0xF129, 0xF133, 0xF155, 0xF165
+        ]);
+    }
+
+    #[test]
+    fn resolves_labels_constants_and_aliases() {
+        let source = "
+            :const speed 3
+            :alias x v4
+            : main
+                x := speed
+                draw
+                i := dot
+                jump main
+            : draw ;
+            : dot 0b10000000
+        ";
+        // main is first, so there's no jump to it
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x64, 0x03, 0x22, 0x08, 0xA2, 0x0A, 0x12, 0x00, 0x00, 0xEE, 0x80
+        ]);
+    }
+
+    #[test]
+    fn jumps_to_main_when_it_isnt_first() {
+        assert_eq!(words(&assemble(": helper ; : main helper").unwrap()), vec![0x1204, 0x00EE, 0x2202]);
+    }
+
+    #[test]
+    fn assembles_conditionals_and_loops() {
+        let source = "
+            : main
+            if v0 == 1 then v1 := 2
+            if v0 != v1 begin v2 := 3 else v2 := 4 end
+            loop
+                while v3 -key
+                v3 += 1
+            again
+        ";
+        assert_eq!(words(&assemble(source).unwrap()), vec![
+            0x4001, 0x6102,
+            0x9010, 0x120C, 0x6203, 0x120E, 0x6204,
+            0xE3A1, 0x1216, 0x7301, 0x120E
+        ]);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(assemble(": main\n  jump nowhere"), Err("line 2: undefined label 'nowhere'".to_owned()));
+        assert_eq!(assemble(": main\nhires"), Err("line 2: 'hires' is SUPER-CHIP or XO-CHIP, which isn't supported".to_owned()));
+        assert!(assemble(": main\nif v0 == 1 begin").is_err());
+        assert!(assemble(": main\nv0 := 256").is_err());
+        assert!(assemble(": a : a").is_err());
+    }
+}
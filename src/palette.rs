@@ -0,0 +1,30 @@
+//! Display colours, shared by the command line, cartridge options and the
+//! ROM database.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub foreground: [f32; 4],
+    pub background: [f32; 4]
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            foreground: [1.0, 1.0, 1.0, 1.0],
+            background: [0.0, 0.0, 0.0, 1.0]
+        }
+    }
+}
+
+/// A colour written as six hex digits, with or without a leading `#`.
+pub fn parse_colour(value: &str) -> Result<[f32; 4], String> {
+    let hex = value.trim_start_matches('#');
+    let rgb = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)
+        .ok_or(format!("invalid colour '{}', expected six hex digits", value))?;
+    Ok([
+        ((rgb >> 16) & 0xFF) as f32 / 255.0,
+        ((rgb >> 8) & 0xFF) as f32 / 255.0,
+        (rgb & 0xFF) as f32 / 255.0,
+        1.0
+    ])
+}
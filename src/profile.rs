@@ -0,0 +1,307 @@
+//! The `--profile` report: where a program spends its instructions.
+//!
+//! Counts are taken before each instruction runs. Subroutines are followed
+//! through 2NNN and 00EE, and their inclusive time is the number of
+//! instructions from the call to the matching return, counting those of any
+//! subroutines they call in turn. A recursive subroutine is timed from its
+//! outermost call, so its inclusive time never counts an instruction twice.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::disasm::disassemble;
+use crate::{Machine, Observer, OpCode, MEMORY_SIZE};
+
+/// Hot spots listed in the report.
+const TOP_ADDRESSES: usize = 20;
+
+pub struct Profiler {
+    total: u64,
+    per_address: Vec<u64>,
+    /// The opcode last run at each address, for the disassembly.
+    opcodes: Vec<OpCode>,
+    per_class: HashMap<&'static str, u64>,
+    calls: HashMap<u16, u64>,
+    inclusive: HashMap<u16, u64>,
+    /// Subroutines entered and not yet returned from, with the instruction
+    /// count at the call and the stack the call made.
+    frames: Vec<(u16, u64, usize)>,
+    /// Every call stack seen, as the stack it was called from and the
+    /// subroutine entered; the first is `main`, with nothing called.
+    stacks: Vec<(usize, u16)>,
+    /// Stacks by the stack they were called from and the subroutine.
+    callees: HashMap<(usize, u16), usize>,
+    /// Instructions run in each stack.
+    samples: Vec<u64>
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            total: 0,
+            per_address: vec![0; MEMORY_SIZE],
+            opcodes: vec![0; MEMORY_SIZE],
+            per_class: HashMap::new(),
+            calls: HashMap::new(),
+            inclusive: HashMap::new(),
+            frames: Vec::new(),
+            stacks: vec![(0, 0)],
+            callees: HashMap::new(),
+            samples: vec![0]
+        }
+    }
+
+    /// The report: hot spots with disassembly, opcode classes and subroutines.
+    pub fn report(&self) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "Profile of {} instructions", self.total).unwrap();
+
+        writeln!(out, "\nHot spots\n{:>10} {:>7}  address  opcode  instruction", "count", "%").unwrap();
+        let mut addresses: Vec<usize> = (0..MEMORY_SIZE).filter(|&a| self.per_address[a] > 0).collect();
+        addresses.sort_by(|&a, &b| self.per_address[b].cmp(&self.per_address[a]).then(a.cmp(&b)));
+        for &address in addresses.iter().take(TOP_ADDRESSES) {
+            let count = self.per_address[address];
+            let opcode = self.opcodes[address];
+            writeln!(out, "{:>10} {:>6.2}%  0x{:03X}    {:04X}    {}",
+                count, percent(count), address, opcode, disassemble(opcode)).unwrap();
+        }
+
+        writeln!(out, "\nOpcode classes\n{:>10} {:>7}  class", "count", "%").unwrap();
+        let mut classes: Vec<(&str, u64)> = self.per_class.iter().map(|(&class, &count)| (class, count)).collect();
+        classes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for (class, count) in classes {
+            writeln!(out, "{:>10} {:>6.2}%  {}", count, percent(count), class).unwrap();
+        }
+
+        writeln!(out, "\nSubroutines\n{:>10} {:>10} {:>7}  address", "calls", "inclusive", "%").unwrap();
+        let inclusive = self.inclusive_with_open_frames();
+        let mut subroutines: Vec<(u16, u64)> = self.calls.iter().map(|(&address, &calls)| (address, calls)).collect();
+        subroutines.sort_by_key(|&(address, _)| (std::cmp::Reverse(inclusive.get(&address).copied().unwrap_or(0)), address));
+        for (address, calls) in subroutines {
+            let time = inclusive.get(&address).copied().unwrap_or(0);
+            writeln!(out, "{:>10} {:>10} {:>6.2}%  0x{:03X}", calls, time, percent(time), address).unwrap();
+        }
+        out
+    }
+
+    /// Call stacks in the folded format flamegraph tools read, one
+    /// `main;sub_2A0;sub_31C 42` line per stack, sorted.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = (0..self.stacks.len()).filter(|&n| self.samples[n] > 0).map(|n| {
+            let mut names = Vec::new();
+            let mut stack = n;
+            while stack != 0 {
+                let (caller, address) = self.stacks[stack];
+                names.push(format!("sub_{:03X}", address));
+                stack = caller;
+            }
+            names.push("main".to_owned());
+            names.reverse();
+            format!("{} {}", names.join(";"), self.samples[n])
+        }).collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    /// Inclusive times, counting subroutines still running up to now.
+    fn inclusive_with_open_frames(&self) -> HashMap<u16, u64> {
+        let mut inclusive = self.inclusive.clone();
+        for (n, &(address, start, _)) in self.frames.iter().enumerate() {
+            if !self.frames[..n].iter().any(|&(outer, _, _)| outer == address) {
+                *inclusive.entry(address).or_insert(0) += self.total - start;
+            }
+        }
+        inclusive
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+/// Counts every instruction as it's fetched, FX0A retries included, since
+/// those are where a waiting program spends its time.
+impl Observer for Profiler {
+    fn fetch(&mut self, machine: &Machine, opcode: OpCode) {
+        let pc = machine.pc as usize;
+        self.total += 1;
+        self.per_address[pc] += 1;
+        self.opcodes[pc] = opcode;
+        *self.per_class.entry(class(opcode)).or_insert(0) += 1;
+
+        let stack = self.frames.last().map_or(0, |&(_, _, stack)| stack);
+        self.samples[stack] += 1;
+
+        if opcode & 0xF000 == 0x2000 {
+            let target = opcode & 0x0FFF;
+            *self.calls.entry(target).or_insert(0) += 1;
+            let (stacks, samples) = (&mut self.stacks, &mut self.samples);
+            let callee = *self.callees.entry((stack, target)).or_insert_with(|| {
+                stacks.push((stack, target));
+                samples.push(0);
+                stacks.len() - 1
+            });
+            self.frames.push((target, self.total, callee));
+        } else if opcode == 0x00EE {
+            if let Some((address, start, _)) = self.frames.pop() {
+                // an outer call of the same subroutine already covers this one
+                if !self.frames.iter().any(|&(outer, _, _)| outer == address) {
+                    *self.inclusive.entry(address).or_insert(0) += self.total - start;
+                }
+            }
+        }
+    }
+
+    /// Forgets the subroutines that were running, which a reset abandons
+    /// without returning from. The counts so far stay in the report.
+    fn reset(&mut self) {
+        self.frames.clear();
+    }
+}
+
+/// The instruction an opcode is, in the usual `8XY4` style.
+fn class(opcode: OpCode) -> &'static str {
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "00E0",
+            0x00EE => "00EE",
+            _ => "0NNN"
+        },
+        0x1000 => "1NNN",
+        0x2000 => "2NNN",
+        0x3000 => "3XNN",
+        0x4000 => "4XNN",
+        0x5000 if opcode & 0x000F == 0 => "5XY0",
+        0x6000 => "6XNN",
+        0x7000 => "7XNN",
+        0x8000 => match opcode & 0x000F {
+            0x0 => "8XY0",
+            0x1 => "8XY1",
+            0x2 => "8XY2",
+            0x3 => "8XY3",
+            0x4 => "8XY4",
+            0x5 => "8XY5",
+            0x6 => "8XY6",
+            0x7 => "8XY7",
+            0xE => "8XYE",
+            _ => "unknown"
+        },
+        0x9000 if opcode & 0x000F == 0 => "9XY0",
+        0xA000 => "ANNN",
+        0xB000 => "BNNN",
+        0xC000 => "CXNN",
+        0xD000 => "DXYN",
+        0xE000 => match opcode & 0x00FF {
+            0x9E => "EX9E",
+            0xA1 => "EXA1",
+            _ => "unknown"
+        },
+        0xF000 => match opcode & 0x00FF {
+            0x07 => "FX07",
+            0x0A => "FX0A",
+            0x15 => "FX15",
+            0x18 => "FX18",
+            0x1E => "FX1E",
+            0x29 => "FX29",
+            0x33 => "FX33",
+            0x55 => "FX55",
+            0x65 => "FX65",
+            _ => "unknown"
+        },
+        _ => "unknown"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    // 200: CALL 206; JP 204 (spins) / 206: LD V0, 1; CALL 20C; RET / 20C: RET
+    const PROGRAM: [u8; 16] = [
+        0x22, 0x06, 0x12, 0x04, 0x12, 0x04,
+        0x60, 0x01, 0x22, 0x0C, 0x00, 0xEE,
+        0x00, 0xEE, 0x00, 0x00
+    ];
+
+    fn profile(instructions: usize) -> Profiler {
+        profile_program(&PROGRAM, instructions)
+    }
+
+    fn profile_program(program: &[u8], instructions: usize) -> Profiler {
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(program, 0x200);
+        let mut profiler = Profiler::new();
+        for _ in 0..instructions {
+            machine.step_with(&mut profiler).unwrap();
+        }
+        profiler
+    }
+
+    #[test]
+    fn counts_addresses_classes_and_calls() {
+        let profiler = profile(10);
+        assert_eq!(profiler.total, 10);
+        assert_eq!(profiler.per_address[0x204], 4);
+        assert_eq!(profiler.per_class["2NNN"], 2);
+        assert_eq!(profiler.per_class["1NNN"], 5);
+        assert_eq!(profiler.calls[&0x206], 1);
+        assert_eq!(profiler.calls[&0x20C], 1);
+        // 206, 208, 20C, 20A
+        assert_eq!(profiler.inclusive[&0x206], 4);
+        assert_eq!(profiler.inclusive[&0x20C], 1);
+        assert!(profiler.frames.is_empty());
+    }
+
+    #[test]
+    fn reports_hot_spots_with_disassembly() {
+        let report = profile(10).report();
+        assert!(report.starts_with("Profile of 10 instructions"));
+        let hottest = report.lines().nth(4).unwrap();
+        assert!(hottest.contains("0x204") && hottest.ends_with("JP 0x204"), "{}", hottest);
+        assert!(report.contains(" 50.00%  1NNN"));
+        assert!(report.lines().any(|line| line.ends_with("1          4  40.00%  0x206")));
+    }
+
+    #[test]
+    fn folds_call_stacks() {
+        assert_eq!(profile(10).folded(), "main 6\nmain;sub_206 3\nmain;sub_206;sub_20C 1\n");
+    }
+
+    #[test]
+    fn counts_subroutines_still_running() {
+        // stops inside 206 before its call
+        let profiler = profile(2);
+        assert_eq!(profiler.inclusive_with_open_frames()[&0x206], 1);
+    }
+
+    #[test]
+    fn starts_the_call_stack_over_on_reset() {
+        // stops inside 206, then starts again from 200
+        let mut profiler = profile(2);
+        profiler.reset();
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(&PROGRAM, 0x200);
+        machine.step_with(&mut profiler).unwrap();
+        machine.step_with(&mut profiler).unwrap();
+        assert_eq!(profiler.frames.len(), 1);
+        assert_eq!(profiler.folded(), "main 2\nmain;sub_206 2\n");
+    }
+
+    #[test]
+    fn times_recursion_from_the_outermost_call() {
+        // 200: LD V0, 3; CALL 206; JP 204 / 206: ADD V0, -1; SE V0, 0; CALL 206; RET
+        let program = [0x60, 0x03, 0x22, 0x06, 0x12, 0x04, 0x70, 0xFF, 0x30, 0x00, 0x22, 0x06, 0x00, 0xEE];
+        // three calls deep, then three returns
+        let profiler = profile_program(&program, 14);
+        assert_eq!(profiler.calls[&0x206], 3);
+        assert_eq!(profiler.inclusive[&0x206], 11);
+
+        // all three calls still running
+        let profiler = profile_program(&program, 9);
+        assert_eq!(profiler.inclusive_with_open_frames()[&0x206], 7);
+    }
+}
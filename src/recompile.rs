@@ -0,0 +1,406 @@
+//! `chip8 recompile`: translates a loaded program into Rust source that
+//! runs it natively against this library.
+//!
+//! Code is found by following jumps, calls and skips from the entry point.
+//! It is split into blocks that are only entered at the top, each becoming
+//! a function that works on the `Machine` directly. The generated
+//! `run_block` calls the block at PC only while memory still holds the
+//! bytes it was translated from; anything else, such as the target of a
+//! BNNN or code the program wrote itself, is run by the interpreter one
+//! instruction at a time. Random numbers, key waits and faults are handed
+//! to the interpreter too, through `Machine::run_opcode`.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::palette::Palette;
+use crate::decode::{decode, Instruction};
+use crate::disasm::disassemble;
+use crate::{ConfigError, Machine, OpCode, MEMORY_SIZE};
+
+/// The generated program, with counts for a summary.
+pub struct Recompiled {
+    pub source: String,
+    pub blocks: usize,
+    pub instructions: usize
+}
+
+/// Straight-line code from `start`, one opcode per two bytes.
+#[derive(Debug, PartialEq)]
+struct Block {
+    start: u16,
+    opcodes: Vec<OpCode>
+}
+
+impl Block {
+    fn end(&self) -> u16 {
+        self.start + 2 * self.opcodes.len() as u16
+    }
+}
+
+/// Translates the program in `machine`, starting from its PC, into the
+/// source of a Rust program that opens a window titled `title` and runs
+/// it at `speed` instructions per second. The machine's config is baked
+/// into the program, so one the core can't run is refused.
+pub fn recompile(machine: &Machine, title: &str, speed: u32, palette: Palette) -> Result<Recompiled, ConfigError> {
+    machine.config.validate()?;
+    let (reached, leaders) = discover(&machine.memory, machine.pc);
+    let blocks = split(&machine.memory, &reached, &leaders);
+
+    let mut source = String::new();
+    write_header(&mut source, machine, title, speed, palette);
+    write_dispatch(&mut source, &blocks);
+    for block in &blocks {
+        source.push('\n');
+        write_block(&mut source, block, machine);
+    }
+
+    Ok(Recompiled { source, blocks: blocks.len(), instructions: reached.len() })
+}
+
+fn opcode_at(memory: &[u8; MEMORY_SIZE], address: u16) -> OpCode {
+    let address = address as usize;
+    (memory[address] as u16) << 8 | memory[address + 1] as u16
+}
+
+/// Whether control can leave straight-line code after `instruction`, or
+/// memory can change under it.
+fn ends_block(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Return | Instruction::Jump(_) | Instruction::Call(_) | Instruction::JumpOffset(_) |
+        Instruction::SkipIfEqual { .. } | Instruction::SkipIfNotEqual { .. } |
+        Instruction::SkipIfRegistersEqual { .. } | Instruction::SkipIfRegistersDiffer { .. } |
+        Instruction::SkipIfKey { .. } | Instruction::SkipUnlessKey { .. } |
+        Instruction::WaitKey { .. } | Instruction::StoreBcd { .. } | Instruction::StoreRegisters { .. } |
+        Instruction::Unknown)
+}
+
+/// The addresses of every instruction reachable from `entry`, and of those
+/// that start a block: the entry and everything a block can continue at.
+fn discover(memory: &[u8; MEMORY_SIZE], entry: u16) -> (BTreeSet<u16>, BTreeSet<u16>) {
+    let mut reached = BTreeSet::new();
+    let mut leaders = BTreeSet::new();
+    leaders.insert(entry);
+    let mut pending = vec![entry];
+
+    while let Some(address) = pending.pop() {
+        if address as usize + 1 >= MEMORY_SIZE || !reached.insert(address) {
+            continue;
+        }
+        let instruction = decode(opcode_at(memory, address));
+        let next = address + 2;
+        let successors = match instruction {
+            Instruction::Jump(target) => vec![target],
+            Instruction::Call(target) => vec![target, next],
+            // BNNN's target is only known at run time; the interpreter runs it
+            Instruction::Return | Instruction::JumpOffset(_) | Instruction::Unknown => vec![],
+            Instruction::SkipIfEqual { .. } | Instruction::SkipIfNotEqual { .. } |
+            Instruction::SkipIfRegistersEqual { .. } | Instruction::SkipIfRegistersDiffer { .. } |
+            Instruction::SkipIfKey { .. } | Instruction::SkipUnlessKey { .. } => vec![next, next + 2],
+            _ => vec![next]
+        };
+        if ends_block(instruction) {
+            leaders.extend(&successors);
+        }
+        pending.extend(successors);
+    }
+    (reached, leaders)
+}
+
+/// Cuts the reached code into blocks at the leaders and after every
+/// instruction that ends one.
+fn split(memory: &[u8; MEMORY_SIZE], reached: &BTreeSet<u16>, leaders: &BTreeSet<u16>) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    for &start in leaders.iter().filter(|address| reached.contains(address)) {
+        let mut block = Block { start, opcodes: Vec::new() };
+        let mut address = start;
+        loop {
+            let opcode = opcode_at(memory, address);
+            block.opcodes.push(opcode);
+            address += 2;
+            if ends_block(decode(opcode)) || leaders.contains(&address) || !reached.contains(&address) {
+                break;
+            }
+        }
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn write_header(out: &mut String, machine: &Machine, title: &str, speed: u32, palette: Palette) {
+    // everything the loader put in memory past the font, less zeros at either end
+    let memory = &machine.memory[80..];
+    let first = memory.iter().position(|&byte| byte != 0);
+    let last = memory.iter().rposition(|&byte| byte != 0);
+    let (base, image) = match (first, last) {
+        (Some(first), Some(last)) => (80 + first, &memory[first..=last]),
+        _ => (machine.config.load_address as usize, &memory[..0])
+    };
+
+    // plain comments rather than `//!`, so the file can also be `include!`d
+    writeln!(out, "// {}, recompiled by `chip8 recompile`.", title).unwrap();
+    writeln!(out, "//").unwrap();
+    writeln!(out, "// Build it in a crate that depends on `chip8`, for example by copying it").unwrap();
+    writeln!(out, "// into that crate's `examples` folder and running `cargo run --release --example`.").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "use chip8::display;").unwrap();
+    writeln!(out, "use chip8::palette::Palette;").unwrap();
+    writeln!(out, "use chip8::{{Config, Fault, Machine}};").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "const CONFIG: Config = {:?};", machine.config).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "const BASE: u16 = 0x{:03X};", base).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "const IMAGE: [u8; {}] = [", image.len()).unwrap();
+    for (n, line) in image.chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+        let comma = if (n + 1) * 16 < image.len() { "," } else { "" };
+        writeln!(out, "    {}{}", bytes.join(", "), comma).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "fn main() {{").unwrap();
+    writeln!(out, "    let mut machine = Machine::new(CONFIG);").unwrap();
+    writeln!(out, "    machine.load_program(&IMAGE, BASE);").unwrap();
+    writeln!(out, "    machine.pc = 0x{:03X};", machine.pc).unwrap();
+    writeln!(out, "    let palette = {:?};", palette).unwrap();
+    writeln!(out, "    chip8::native::play({:?}, machine, {}, palette, run_block);", title, speed).unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_dispatch(out: &mut String, blocks: &[Block]) {
+    writeln!(out).unwrap();
+    writeln!(out, "/// Runs the block at PC if memory still holds the code it was translated").unwrap();
+    writeln!(out, "/// from, or else interprets one instruction. Returns how many ran.").unwrap();
+    writeln!(out, "fn run_block(machine: &mut Machine) -> Result<u32, Fault> {{").unwrap();
+    writeln!(out, "    let pc = machine.pc as usize;").unwrap();
+    writeln!(out, "    match machine.pc {{").unwrap();
+    for block in blocks {
+        let bytes: Vec<String> = block.opcodes.iter()
+            .map(|opcode| format!("0x{:02X}, 0x{:02X}", opcode >> 8, opcode & 0xFF))
+            .collect();
+        writeln!(out, "        0x{:03X} if machine.memory[pc..pc + {}] == [{}] => block_{:03x}(machine),",
+            block.start, 2 * block.opcodes.len(), bytes.join(", "), block.start).unwrap();
+    }
+    writeln!(out, "        _ => machine.step().map(|_| 1)").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_block(out: &mut String, block: &Block, machine: &Machine) {
+    writeln!(out, "fn block_{:03x}(machine: &mut Machine) -> Result<u32, Fault> {{", block.start).unwrap();
+    let mut sets_pc = false;
+    for (n, &opcode) in block.opcodes.iter().enumerate() {
+        let address = block.start + 2 * n as u16;
+        let instruction = decode(opcode);
+        writeln!(out, "    // 0x{:03X}: {}", address, disassemble(opcode)).unwrap();
+        for line in translate(address, opcode, instruction, machine, n as u32 + 1) {
+            writeln!(out, "    {}", line).unwrap();
+        }
+        sets_pc = ends_block(instruction) &&
+            !matches!(instruction, Instruction::StoreBcd { .. } | Instruction::StoreRegisters { .. });
+    }
+    if !sets_pc {
+        writeln!(out, "    machine.pc = 0x{:03X};", block.end()).unwrap();
+    }
+    writeln!(out, "    Ok({})", block.opcodes.len()).unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+/// The Rust for one instruction, with the machine's quirks settled.
+/// `count` is how many instructions of the block have run once it's done.
+fn translate(address: u16, opcode: OpCode, instruction: Instruction, machine: &Machine, count: u32) -> Vec<String> {
+    let config = machine.config;
+    let skip = |condition: String| vec![
+        format!("machine.pc = if {} {{ 0x{:03X} }} else {{ 0x{:03X} }};", condition, address + 4, address + 2)
+    ];
+    let interpret = || vec![
+        format!("machine.pc = 0x{:03X};", address),
+        format!("machine.run_opcode(0x{:04X})?;", opcode)
+    ];
+    // raises the interpreter's fault for this instruction
+    let fault = || vec![
+        format!("    machine.pc = 0x{:03X};", address),
+        format!("    return machine.run_opcode(0x{:04X}).map(|_| {});", opcode, count)
+    ];
+    let vf_reset = || if config.vf_reset { vec!["machine.v[0xF] = 0;".to_owned()] } else { vec![] };
+    let shift_source = |x: u8, y: u8| if config.shift_uses_vy { y } else { x };
+
+    match instruction {
+        Instruction::Clear => vec![
+            "machine.gfx.clear();".to_owned(),
+            "machine.draw_flag = true;".to_owned()
+        ],
+        Instruction::Return => [
+            vec!["if machine.sp == 0 {".to_owned()],
+            fault(),
+            vec![
+                "}".to_owned(),
+                "machine.sp -= 1;".to_owned(),
+                "machine.pc = machine.stack[machine.sp as usize] + 2;".to_owned()
+            ]
+        ].concat(),
+        Instruction::Sys => vec![],
+        Instruction::Jump(target) => vec![format!("machine.pc = 0x{:03X};", target)],
+        Instruction::Call(target) => [
+            vec![format!("if machine.sp >= {} {{", config.stack_depth)],
+            fault(),
+            vec![
+                "}".to_owned(),
+                format!("machine.stack[machine.sp as usize] = 0x{:03X};", address),
+                "machine.sp += 1;".to_owned(),
+                format!("machine.pc = 0x{:03X};", target)
+            ]
+        ].concat(),
+        Instruction::SkipIfEqual { x, nn } => skip(format!("machine.v[0x{:X}] == 0x{:02X}", x, nn)),
+        Instruction::SkipIfNotEqual { x, nn } => skip(format!("machine.v[0x{:X}] != 0x{:02X}", x, nn)),
+        Instruction::SkipIfRegistersEqual { x, y } => skip(format!("machine.v[0x{:X}] == machine.v[0x{:X}]", x, y)),
+        Instruction::SkipIfRegistersDiffer { x, y } => skip(format!("machine.v[0x{:X}] != machine.v[0x{:X}]", x, y)),
+        Instruction::SkipIfKey { x } => skip(format!("machine.key[machine.v[0x{:X}] as usize]", x)),
+        Instruction::SkipUnlessKey { x } => skip(format!("!machine.key[machine.v[0x{:X}] as usize]", x)),
+        Instruction::Load { x, nn } => vec![format!("machine.v[0x{:X}] = 0x{:02X};", x, nn)],
+        Instruction::Add { x, nn } => vec![format!("machine.v[0x{:X}] = machine.v[0x{:X}].wrapping_add(0x{:02X});", x, x, nn)],
+        Instruction::Copy { x, y } => vec![format!("machine.v[0x{:X}] = machine.v[0x{:X}];", x, y)],
+        Instruction::Or { x, y } => [vec![format!("machine.v[0x{:X}] |= machine.v[0x{:X}];", x, y)], vf_reset()].concat(),
+        Instruction::And { x, y } => [vec![format!("machine.v[0x{:X}] &= machine.v[0x{:X}];", x, y)], vf_reset()].concat(),
+        Instruction::Xor { x, y } => [vec![format!("machine.v[0x{:X}] ^= machine.v[0x{:X}];", x, y)], vf_reset()].concat(),
+        Instruction::AddRegisters { x, y } => vec![
+            format!("let (value, overflow) = machine.v[0x{:X}].overflowing_add(machine.v[0x{:X}]);", x, y),
+            format!("machine.v[0x{:X}] = value;", x),
+            "machine.v[0xF] = overflow as u8;".to_owned()
+        ],
+        Instruction::Sub { x, y } => vec![
+            format!("let (value, overflow) = machine.v[0x{:X}].overflowing_sub(machine.v[0x{:X}]);", x, y),
+            format!("machine.v[0x{:X}] = value;", x),
+            "machine.v[0xF] = !overflow as u8;".to_owned()
+        ],
+        Instruction::SubReversed { x, y } => vec![
+            format!("let (value, overflow) = machine.v[0x{:X}].overflowing_sub(machine.v[0x{:X}]);", y, x),
+            format!("machine.v[0x{:X}] = value;", x),
+            "machine.v[0xF] = !overflow as u8;".to_owned()
+        ],
+        Instruction::ShiftRight { x, y } => vec![
+            format!("let source = machine.v[0x{:X}];", shift_source(x, y)),
+            format!("machine.v[0x{:X}] = source >> 1;", x),
+            "machine.v[0xF] = source & 0x01;".to_owned()
+        ],
+        Instruction::ShiftLeft { x, y } => vec![
+            format!("let source = machine.v[0x{:X}];", shift_source(x, y)),
+            format!("machine.v[0x{:X}] = source << 1;", x),
+            "machine.v[0xF] = source >> 7;".to_owned()
+        ],
+        Instruction::LoadIndex(address) => vec![format!("machine.i = 0x{:03X};", address)],
+        Instruction::JumpOffset(address) => vec![format!("machine.pc = 0x{:03X} + machine.v[0x0] as u16;", address)],
+        Instruction::Random { .. } | Instruction::WaitKey { .. } | Instruction::Unknown => interpret(),
+        Instruction::Draw { x, y, height } => vec![
+            format!("let x = machine.v[0x{:X}] as usize % display::WIDTH;", x),
+            format!("let y = machine.v[0x{:X}] as usize % display::HEIGHT;", y),
+            "let i = machine.i as usize;".to_owned(),
+            format!("let visible = (display::HEIGHT - y).min({});", height),
+            "let collision = machine.gfx.draw_sprite(x, y, &machine.memory[i..i + visible]);".to_owned(),
+            "machine.v[0xF] = collision as u8;".to_owned(),
+            "machine.draw_flag = true;".to_owned()
+        ],
+        Instruction::LoadDelay { x } => vec![format!("machine.v[0x{:X}] = machine.delay_timer;", x)],
+        Instruction::SetDelay { x } => vec![format!("machine.delay_timer = machine.v[0x{:X}];", x)],
+        Instruction::SetSound { x } => vec![format!("machine.sound_timer = machine.v[0x{:X}];", x)],
+        Instruction::AddIndex { x } => vec![
+            format!("let (value, overflow) = machine.i.overflowing_add(machine.v[0x{:X}] as u16);", x),
+            "machine.i = value;".to_owned(),
+            "machine.v[0xF] = overflow as u8;".to_owned()
+        ],
+        Instruction::LoadFont { x } => vec![format!("machine.i = machine.v[0x{:X}] as u16 * 5;", x)],
+        Instruction::StoreBcd { x } => vec![
+            format!("let value = machine.v[0x{:X}];", x),
+            "let i = machine.i as usize;".to_owned(),
+            "machine.memory[i..i + 3].copy_from_slice(&[value / 100, value / 10 % 10, value % 10]);".to_owned()
+        ],
+        Instruction::StoreRegisters { x } => vec![
+            "let i = machine.i as usize;".to_owned(),
+            format!("machine.memory[i..=i + {}].copy_from_slice(&machine.v[..=0x{:X}]);", x, x),
+            format!("machine.i += {};", x as u16 + 1)
+        ],
+        Instruction::LoadRegisters { x } => vec![
+            "let i = machine.i as usize;".to_owned(),
+            format!("machine.v[..=0x{:X}].copy_from_slice(&machine.memory[i..=i + {}]);", x, x),
+            format!("machine.i += {};", x as u16 + 1)
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn machine(program: &[u8]) -> Machine {
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(program, 0x200);
+        machine
+    }
+
+    fn blocks(machine: &Machine) -> Vec<(u16, usize)> {
+        let (reached, leaders) = discover(&machine.memory, machine.pc);
+        split(&machine.memory, &reached, &leaders).iter()
+            .map(|block| (block.start, block.opcodes.len()))
+            .collect()
+    }
+
+    #[test]
+    fn splits_code_at_calls_skips_and_jump_targets() {
+        let machine = machine(&[
+            0x60, 0x01, // 200: LD V0, 1
+            0x22, 0x0C, // 202: CALL 0x20C
+            0x30, 0x05, // 204: SE V0, 5
+            0x12, 0x02, // 206: JP 0x202
+            0x12, 0x08, // 208: JP 0x208
+            0xFF, 0xFF, // 20A: data, never reached
+            0x70, 0x01, // 20C: ADD V0, 1
+            0x00, 0xEE  // 20E: RET
+        ]);
+        assert_eq!(blocks(&machine), vec![
+            (0x200, 1), (0x202, 1), (0x204, 1), (0x206, 1), (0x208, 1), (0x20C, 2)
+        ]);
+    }
+
+    #[test]
+    fn stops_at_computed_jumps() {
+        let machine = machine(&[0x60, 0x02, 0xB2, 0x06, 0x12, 0x00, 0x00, 0xE0]);
+        assert_eq!(blocks(&machine), vec![(0x200, 2)]);
+    }
+
+    #[test]
+    fn settles_quirks_when_translating() {
+        let mut vip = machine(&[]);
+        vip.config = Config::preset("vip").unwrap();
+        let or = translate(0x200, 0x8121, decode(0x8121), &vip, 1);
+        assert_eq!(or, vec!["machine.v[0x1] |= machine.v[0x2];", "machine.v[0xF] = 0;"]);
+        let shift = translate(0x200, 0x8126, decode(0x8126), &vip, 1);
+        assert_eq!(shift[0], "let source = machine.v[0x2];");
+
+        let modern = machine(&[]);
+        assert_eq!(translate(0x200, 0x8121, decode(0x8121), &modern, 1).len(), 1);
+        assert_eq!(translate(0x200, 0x8126, decode(0x8126), &modern, 1)[0], "let source = machine.v[0x1];");
+    }
+
+    #[test]
+    fn generates_a_guarded_dispatch() {
+        let recompiled = recompile(&machine(&[0x60, 0x01, 0x12, 0x02]), "Loop", 600, Palette {
+            foreground: [1.0, 1.0, 1.0, 1.0],
+            background: [0.0, 0.0, 0.0, 1.0]
+        }).unwrap();
+        assert_eq!((recompiled.blocks, recompiled.instructions), (2, 2));
+        assert!(recompiled.source.contains(
+            "0x202 if machine.memory[pc..pc + 2] == [0x12, 0x02] => block_202(machine),"));
+        assert!(recompiled.source.contains("_ => machine.step().map(|_| 1)"));
+        assert!(recompiled.source.contains("const IMAGE: [u8; 4] = [\n    0x60, 0x01, 0x12, 0x02\n];"));
+    }
+
+    #[test]
+    fn still_generates_the_checked_in_example() {
+        // tests/recompiled.rs runs this against the interpreter
+        let program = include_bytes!("../tests/recompiled/counter.ch8");
+        let recompiled = recompile(&machine(program), "Chip 8", 500, Palette::default()).unwrap();
+        assert_eq!(recompiled.source, include_str!("../tests/recompiled/counter.rs"));
+    }
+}
@@ -0,0 +1,205 @@
+//! Intel HEX and Motorola S-record files, which carry their own load
+//! addresses and optionally an entry point.
+
+use std::collections::BTreeMap;
+
+use crate::MEMORY_SIZE;
+
+/// Memory contents described by a record file, flattened into one block.
+/// Gaps between records are filled with zeros.
+#[derive(Debug, PartialEq)]
+pub struct Image {
+    pub origin: u16,
+    pub data: Vec<u8>,
+    pub entry: Option<u16>
+}
+
+pub fn parse_intel_hex(text: &str) -> Result<Image, String> {
+    let mut bytes = BTreeMap::new();
+    let mut entry = None;
+    let mut base: u32 = 0;
+
+    for (n, line) in numbered_lines(text) {
+        let record = line.strip_prefix(':').ok_or(format!("line {}: expected ':'", n))?;
+        let record = decode_hex(record).ok_or(format!("line {}: invalid hex digits", n))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(format!("line {}: wrong record length", n));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(format!("line {}: checksum mismatch", n));
+        }
+
+        let address = (record[1] as u32) << 8 | record[2] as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => insert(&mut bytes, base.checked_add(address), data, n)?,
+            0x01 => break,
+            0x02 => base = be_value(data) << 4,
+            0x03 => {
+                // CS:IP
+                let value = be_value(data);
+                entry = Some(((value >> 16) << 4) + (value & 0xFFFF));
+            },
+            0x04 => base = be_value(data) << 16,
+            0x05 => entry = Some(be_value(data)),
+            kind => return Err(format!("line {}: unknown record type {:02X}", n, kind))
+        }
+    }
+    image(bytes, entry)
+}
+
+pub fn parse_srecord(text: &str) -> Result<Image, String> {
+    let mut bytes = BTreeMap::new();
+    let mut entry = None;
+
+    for (n, line) in numbered_lines(text) {
+        let record = line.strip_prefix('S').ok_or(format!("line {}: expected 'S'", n))?;
+        let kind = record.chars().next().ok_or(format!("line {}: missing record type", n))?;
+        let record = record.get(1..).and_then(decode_hex).ok_or(format!("line {}: invalid hex digits", n))?;
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            return Err(format!("line {}: wrong record length", n));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+            return Err(format!("line {}: checksum mismatch", n));
+        }
+
+        let address_size = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(format!("line {}: unknown record type S{}", n, kind))
+        };
+        if record.len() < address_size + 2 {
+            return Err(format!("line {}: record too short", n));
+        }
+        let address = be_value(&record[1..=address_size]);
+        let data = &record[address_size + 1..record.len() - 1];
+        match kind {
+            '1' | '2' | '3' => insert(&mut bytes, Some(address), data, n)?,
+            '7' | '8' | '9' => entry = Some(address),
+            // header and record counts
+            _ => ()
+        }
+    }
+    image(bytes, entry)
+}
+
+/// Adds a data record's bytes at `address`, which is `None` if working it
+/// out overflowed. Data past the end of memory is refused here, naming the line.
+fn insert(bytes: &mut BTreeMap<u32, u8>, address: Option<u32>, data: &[u8], line: usize) -> Result<(), String> {
+    let end = address.and_then(|address| address.checked_add(data.len() as u32))
+        .filter(|&end| end as usize <= MEMORY_SIZE)
+        .ok_or(format!("line {}: data is outside the {} bytes of memory", line, MEMORY_SIZE))?;
+    let start = end - data.len() as u32;
+    for (offset, byte) in data.iter().enumerate() {
+        bytes.insert(start + offset as u32, *byte);
+    }
+    Ok(())
+}
+
+fn numbered_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len()).step_by(2)
+        .map(|n| u8::from_str_radix(&digits[n..n + 2], 16).ok())
+        .collect()
+}
+
+fn be_value(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+fn image(bytes: BTreeMap<u32, u8>, entry: Option<u32>) -> Result<Image, String> {
+    let (&first, &last) = match (bytes.keys().next(), bytes.keys().next_back()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err("no data records".to_owned())
+    };
+    // tools write an entry of 0 when there isn't one
+    let entry = entry.filter(|&entry| entry != 0);
+    if let Some(entry) = entry.filter(|&entry| entry as usize >= MEMORY_SIZE) {
+        return Err(format!("entry point 0x{:X} is outside memory", entry));
+    }
+
+    let mut data = vec![0; (last - first + 1) as usize];
+    for (address, byte) in bytes {
+        data[(address - first) as usize] = byte;
+    }
+    Ok(Image { origin: first as u16, data, entry: entry.map(|entry| entry as u16) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_intel_hex() {
+        let text = "\
+            :0402000000E0120008\n\
+            :02020600A20054\n\
+            :00000001FF\n";
+        let image = parse_intel_hex(text).unwrap();
+        assert_eq!(image, Image { origin: 0x200, data: vec![0x00, 0xE0, 0x12, 0x00, 0x00, 0x00, 0xA2, 0x00], entry: None });
+    }
+
+    #[test]
+    fn intel_hex_start_and_extended_addresses() {
+        let text = "\
+            :020000040000FA\n\
+            :020600001234B2\n\
+            :0400000500000600F1\n\
+            :00000001FF\n";
+        let image = parse_intel_hex(text).unwrap();
+        assert_eq!(image.origin, 0x600);
+        assert_eq!(image.entry, Some(0x600));
+    }
+
+    #[test]
+    fn rejects_bad_intel_hex() {
+        assert!(parse_intel_hex(":0402000000E0120009\n").unwrap_err().contains("checksum"));
+        assert!(parse_intel_hex("0402000000E0120008\n").is_err());
+        assert!(parse_intel_hex(":04020000\n").is_err());
+        assert!(parse_intel_hex(":00000001FF\n").unwrap_err().contains("no data"));
+        assert_eq!(parse_intel_hex(":01100000AA45\n").unwrap_err(), "line 1: data is outside the 4096 bytes of memory");
+    }
+
+    #[test]
+    fn rejects_addresses_that_overflow() {
+        // an extended linear address of 0xFFFF0000 puts 0xFFFF0000 + 0xFFFF past u32
+        let text = ":02000004FFFFFC\n:02FFFF001234BA\n:00000001FF\n";
+        assert_eq!(parse_intel_hex(text).unwrap_err(), "line 2: data is outside the 4096 bytes of memory");
+        assert!(parse_srecord("S307FFFFFFFF1234B6\n").unwrap_err().starts_with("line 1: data is outside"));
+    }
+
+    #[test]
+    fn parses_srecords() {
+        let text = "\
+            S00600004844521B\n\
+            S107020000E0120004\n\
+            S9030200FA\n";
+        let image = parse_srecord(text).unwrap();
+        assert_eq!(image, Image { origin: 0x200, data: vec![0x00, 0xE0, 0x12, 0x00], entry: Some(0x200) });
+    }
+
+    #[test]
+    fn treats_an_entry_of_zero_as_none() {
+        let image = parse_srecord("S107020000E0120004\nS9030000FC\n").unwrap();
+        assert_eq!(image.entry, None);
+        let image = parse_intel_hex(":020600001234B2\n:0400000500000000F7\n:00000001FF\n").unwrap();
+        assert_eq!(image.entry, None);
+    }
+
+    #[test]
+    fn rejects_bad_srecords() {
+        assert!(parse_srecord("S107020000E0120005\n").unwrap_err().contains("checksum"));
+        assert!(parse_srecord("SX07020000E0120004\n").is_err());
+        assert!(parse_srecord("S107\n").is_err());
+    }
+}
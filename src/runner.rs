@@ -0,0 +1,491 @@
+//! Runs a machine on a thread of its own, so the window can stall, be
+//! dragged or drop frames without slowing the program down.
+//!
+//! The core keeps its own clock: instruction `n` is due `n / speed` seconds
+//! after it starts and timer tick `t` at `t / 60`, and it sleeps until the
+//! next of them. Waking late runs everything that fell due at once, so the
+//! rate holds on average, but falling more than `MAX_LAG` behind (after the
+//! process was suspended, say) restarts the clock instead of racing to catch
+//! up. Input comes in over a channel, which also wakes the core early.
+//! Instructions are stepped through a `DecodeCache`, which pokes, resets
+//! and loads clear of stale entries.
+//! After every timer tick a copy of the machine is left in a slot for the
+//! frontend; a newer frame replaces one it hasn't taken yet, so a slow
+//! frontend sees fewer frames rather than holding the core back. Each frame
+//! also carries the registers as of the last one the frontend could have
+//! seen, taken as it's published rather than before every instruction.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::mem;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::decode::DecodeCache;
+use crate::{Fault, Machine, Observer, OpCode, STACK_SIZE};
+
+/// Timer ticks per second.
+const TIMER_HZ: u64 = 60;
+
+/// How far behind the core may fall before it gives up catching up.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// What the frontend can tell the core.
+pub enum Control {
+    /// A keypad key went down (`true`) or up.
+    Key(u8, bool),
+    ReleaseKeys,
+    /// Stops or resumes the program, timers included.
+    Pause(bool),
+    /// Writes a byte, as the memory view's editor does.
+    Poke(u16, u8),
+    /// `Machine::soft_reset` to the entry point.
+    SoftReset(u16),
+    /// Swaps in another machine, after a hard reset or opening another ROM,
+    /// and runs it at the given speed.
+    Load(Box<Machine>, u32)
+}
+
+/// The machine as the core left it, for the frontend to show.
+pub struct Frame {
+    pub machine: Machine,
+    /// The registers in the frame before, to show what changed since.
+    pub previous: Registers,
+    /// The most recent instructions, oldest first, each logged once even
+    /// while FX0A waits.
+    pub history: Vec<(u16, OpCode)>,
+    /// Set on the last frame when the program faulted and the core stopped.
+    pub fault: Option<Fault>,
+    /// Something the observer reported going wrong, such as a trace file
+    /// that can't be written, for the frontend to show once.
+    pub notice: Option<String>
+}
+
+/// The part of a machine the debug panel compares between frames: all but
+/// the memory and display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; STACK_SIZE],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub key: [bool; 16],
+    pub await_keypress: bool,
+    pub keypress_register: u8,
+    pub awaited_key: Option<u8>
+}
+
+impl Registers {
+    pub fn of(machine: &Machine) -> Registers {
+        Registers {
+            v: machine.v,
+            i: machine.i,
+            pc: machine.pc,
+            sp: machine.sp,
+            stack: machine.stack,
+            delay_timer: machine.delay_timer,
+            sound_timer: machine.sound_timer,
+            key: machine.key,
+            await_keypress: machine.await_keypress,
+            keypress_register: machine.keypress_register,
+            awaited_key: machine.awaited_key
+        }
+    }
+}
+
+/// A machine running on its own thread, watched by an observer that goes
+/// with it.
+pub struct Runner<O> {
+    controls: Sender<Control>,
+    frames: Arc<Mutex<Option<Frame>>>,
+    thread: JoinHandle<O>
+}
+
+impl<O: Observer + Send + 'static> Runner<O> {
+    /// Starts running `machine` at `speed` instructions per second, keeping
+    /// the last `history` instructions for each frame.
+    pub fn spawn(machine: Machine, speed: u32, observer: O, history: usize) -> Runner<O> {
+        let (controls, received) = mpsc::channel();
+        let frames = Arc::new(Mutex::new(None));
+        let core = Core {
+            previous: Registers::of(&machine),
+            machine,
+            cache: DecodeCache::new(),
+            history: Vec::new(),
+            history_length: history,
+            speed,
+            paused: false,
+            observer,
+            frames: Arc::clone(&frames)
+        };
+        let thread = thread::spawn(move || core.run(received));
+        Runner { controls, frames, thread }
+    }
+}
+
+impl<O> Runner<O> {
+    /// Passes `control` to the core. Once the core has stopped on a fault
+    /// this does nothing.
+    pub fn send(&self, control: Control) {
+        let _ = self.controls.send(control);
+    }
+
+    /// The newest frame, if there is one the frontend hasn't taken.
+    pub fn frame(&self) -> Option<Frame> {
+        self.frames.lock().unwrap().take()
+    }
+
+    /// Stops the core and hands back its observer.
+    pub fn stop(self) -> O {
+        drop(self.controls);
+        self.thread.join().expect("the core thread panicked")
+    }
+}
+
+/// When instructions and timer ticks fall due, from the time it started.
+struct Clock {
+    start: Instant,
+    instructions: u64,
+    ticks: u64
+}
+
+/// What the clock says to do next.
+#[derive(Debug, PartialEq)]
+enum Due {
+    Instruction,
+    Tick
+}
+
+impl Clock {
+    fn new() -> Clock {
+        Clock { start: Instant::now(), instructions: 0, ticks: 0 }
+    }
+
+    /// The time after the start that event `count` of one happening `rate`
+    /// times a second is due.
+    fn at(count: u64, rate: u64) -> Duration {
+        Duration::from_nanos((count as u128 * 1_000_000_000 / rate as u128) as u64)
+    }
+
+    fn next_instruction(&self, speed: u32) -> Duration {
+        Clock::at(self.instructions, speed as u64)
+    }
+
+    fn next_tick(&self) -> Duration {
+        Clock::at(self.ticks + 1, TIMER_HZ)
+    }
+
+    /// How long after `now` the next instruction or tick is due.
+    fn wait(&self, speed: u32, now: Duration) -> Duration {
+        self.next_instruction(speed).min(self.next_tick()).saturating_sub(now)
+    }
+
+    /// Whether `now` is so far past the next tick that the clock should
+    /// start again rather than catch up.
+    fn lagging(&self, now: Duration) -> bool {
+        now > self.next_tick() + MAX_LAG
+    }
+
+    /// The next instruction or tick due by `now`, counted as done; an
+    /// instruction goes first when both are due at once.
+    fn take_due(&mut self, speed: u32, now: Duration) -> Option<Due> {
+        let (instruction, tick) = (self.next_instruction(speed), self.next_tick());
+        if instruction.min(tick) > now {
+            None
+        } else if instruction <= tick {
+            self.instructions += 1;
+            Some(Due::Instruction)
+        } else {
+            self.ticks += 1;
+            Some(Due::Tick)
+        }
+    }
+}
+
+struct Core<O> {
+    machine: Machine,
+    cache: DecodeCache,
+    /// The registers in the last frame published.
+    previous: Registers,
+    history: Vec<(u16, OpCode)>,
+    history_length: usize,
+    speed: u32,
+    paused: bool,
+    observer: O,
+    frames: Arc<Mutex<Option<Frame>>>
+}
+
+impl<O: Observer> Core<O> {
+    fn run(mut self, controls: Receiver<Control>) -> O {
+        let mut clock = Clock::new();
+        self.publish(None);
+        loop {
+            // sleep until something is due, or for as long as it takes while paused
+            let first = if self.paused {
+                match controls.recv() {
+                    Ok(control) => Some(control),
+                    Err(_) => return self.observer
+                }
+            } else {
+                match controls.recv_timeout(clock.wait(self.speed, clock.start.elapsed())) {
+                    Ok(control) => Some(control),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return self.observer
+                }
+            };
+            let mut changed = false;
+            for control in first.into_iter().chain(controls.try_iter()) {
+                self.apply(control, &mut clock);
+                changed = true;
+            }
+            if self.paused {
+                if changed {
+                    self.publish(None);
+                }
+                continue;
+            }
+
+            let now = clock.start.elapsed();
+            if clock.lagging(now) {
+                clock = Clock::new();
+                continue;
+            }
+            while let Some(due) = clock.take_due(self.speed, now) {
+                match due {
+                    Due::Instruction => if let Err(fault) = self.step() {
+                        self.publish(Some(fault));
+                        return self.observer;
+                    },
+                    Due::Tick => {
+                        self.machine.tick_timers_with(&mut self.observer);
+                        self.publish(None);
+                        changed = false;
+                    }
+                }
+            }
+            if changed {
+                self.publish(None);
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<(), Fault> {
+        // FX0A re-executes until a key completes it; only log it once
+        if !self.machine.await_keypress {
+            self.history.push((self.machine.pc, self.machine.opcode()));
+            if self.history.len() > self.history_length {
+                self.history.remove(0);
+            }
+        }
+        self.machine.step_cached(&mut self.cache, &mut self.observer)
+    }
+
+    fn apply(&mut self, control: Control, clock: &mut Clock) {
+        match control {
+            Control::Key(key, down) => self.machine.key[key as usize] = down,
+            Control::ReleaseKeys => self.machine.key = [false; 16],
+            Control::Pause(paused) => {
+                if self.paused && !paused {
+                    *clock = Clock::new();
+                }
+                self.paused = paused;
+            },
+            Control::Poke(address, value) => self.machine.poke_with(address, value, &mut self.cache),
+            Control::SoftReset(entry) => {
+                self.machine.soft_reset(entry);
+                self.cache.clear();
+                self.observer.reset();
+                self.previous = Registers::of(&self.machine);
+                self.history.clear();
+            },
+            Control::Load(machine, speed) => {
+                self.machine = *machine;
+                self.cache.clear();
+                self.observer.reset();
+                self.previous = Registers::of(&self.machine);
+                self.history.clear();
+                self.speed = speed;
+                *clock = Clock::new();
+            }
+        }
+    }
+
+    /// Leaves a copy of the machine for the frontend. A display change or
+    /// notice in a frame it never took carries over to this one, and so do
+    /// the registers it was to be compared with.
+    fn publish(&mut self, fault: Option<Fault>) {
+        let mut frame = Frame {
+            machine: self.machine.clone(),
+            previous: mem::replace(&mut self.previous, Registers::of(&self.machine)),
+            history: self.history.clone(),
+            fault,
+            notice: self.observer.take_notice()
+        };
+        self.machine.draw_flag = false;
+        let mut slot = self.frames.lock().unwrap();
+        if let Some(unseen) = slot.take() {
+            frame.machine.draw_flag |= unseen.machine.draw_flag;
+            frame.notice = frame.notice.or(unseen.notice);
+            frame.previous = unseen.previous;
+        }
+        *slot = Some(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    /// Counts instructions and timer ticks.
+    #[derive(Default)]
+    struct Counter {
+        instructions: u64,
+        ticks: u64
+    }
+
+    impl Observer for Counter {
+        fn fetch(&mut self, _machine: &Machine, _opcode: OpCode) {
+            self.instructions += 1;
+        }
+        fn timers(&mut self, _delay: u8, _sound: u8) {
+            self.ticks += 1;
+        }
+    }
+
+    fn machine(program: &[u8]) -> Machine {
+        let mut machine = Machine::new(Config::default());
+        machine.load_program(program, 0x200);
+        machine
+    }
+
+    /// Waits for a frame that passes `check`.
+    fn wait_for<O: Observer + Send + 'static>(runner: &Runner<O>, check: impl Fn(&Frame) -> bool) -> Frame {
+        let started = Instant::now();
+        loop {
+            if let Some(frame) = runner.frame().filter(|frame| check(frame)) {
+                return frame;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "no matching frame");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn schedules_instructions_and_ticks() {
+        let mut clock = Clock::new();
+        let mut due = Vec::new();
+        while let Some(event) = clock.take_due(3000, Duration::from_millis(500)) {
+            due.push(event);
+        }
+        // instructions at 0, 1/3000 s, ... 1500/3000 s; ticks at 1/60 s to 30/60 s
+        assert_eq!(due.iter().filter(|&event| *event == Due::Instruction).count(), 1501);
+        assert_eq!(due.iter().filter(|&event| *event == Due::Tick).count(), 30);
+        // the instruction at 50/3000 s goes before the tick due with it
+        assert_eq!(due[50..52], [Due::Instruction, Due::Tick]);
+
+        assert_eq!(clock.wait(3000, Duration::from_millis(500)), Duration::from_nanos(333_333));
+        assert!(!clock.lagging(Duration::from_millis(600)));
+        assert!(clock.lagging(Duration::from_millis(700)));
+    }
+
+    #[test]
+    fn never_runs_ahead_of_its_clock() {
+        // LD V0, 0xFF; LD DT, V0; JP 0x204
+        let started = Instant::now();
+        let runner = Runner::spawn(machine(&[0x60, 0xFF, 0xF0, 0x15, 0x12, 0x04]), 3000, Counter::default(), 4);
+        thread::sleep(Duration::from_millis(200));
+        let counter = runner.stop();
+        let elapsed = started.elapsed().as_secs_f64();
+        // a busy machine may run fewer, but never more than are due
+        assert!(counter.instructions > 0 && counter.instructions as f64 <= elapsed * 3000.0 + 1.0,
+            "{} instructions in {:.3}s", counter.instructions, elapsed);
+        // each tick counts down DT, which LD DT set once
+        assert!(counter.ticks as f64 <= elapsed * 60.0 + 1.0, "{} ticks in {:.3}s", counter.ticks, elapsed);
+    }
+
+    #[test]
+    fn takes_controls_while_paused() {
+        let runner = Runner::spawn(machine(&[0x12, 0x00]), 1000, (), 4);
+        runner.send(Control::Pause(true));
+        runner.send(Control::Poke(0x300, 0xAB));
+        runner.send(Control::Key(5, true));
+        let frame = wait_for(&runner, |frame| frame.machine.memory[0x300] == 0xAB);
+        assert!(frame.machine.key[5]);
+        assert_eq!(frame.history, vec![(0x200, 0x1200); frame.history.len()]);
+        runner.stop();
+    }
+
+    #[test]
+    fn runs_code_poked_over_cached_instructions() {
+        // LD V0, 1; JP 0x200
+        let runner = Runner::spawn(machine(&[0x60, 0x01, 0x12, 0x00]), 1000, (), 4);
+        wait_for(&runner, |frame| frame.machine.v[0] == 1);
+        runner.send(Control::Poke(0x201, 0x07));
+        wait_for(&runner, |frame| frame.machine.v[0] == 7);
+
+        runner.send(Control::Load(Box::new(machine(&[0x60, 0x09, 0x12, 0x00])), 1000));
+        wait_for(&runner, |frame| frame.machine.v[0] == 9);
+        runner.stop();
+    }
+
+    #[test]
+    fn compares_with_the_last_frame_taken() {
+        // LD V0, 1; JP 0x202
+        let runner = Runner::spawn(machine(&[0x60, 0x01, 0x12, 0x02]), 1000, (), 4);
+        let taken = wait_for(&runner, |frame| frame.machine.v[0] == 1);
+        // however many frames went by untaken in between
+        thread::sleep(Duration::from_millis(50));
+        let next = wait_for(&runner, |_| true);
+        assert_eq!(next.previous, Registers::of(&taken.machine));
+        runner.stop();
+    }
+
+    #[test]
+    fn passes_on_observer_notices() {
+        /// Reports one problem.
+        struct Failing(Option<String>);
+
+        impl Observer for Failing {
+            fn take_notice(&mut self) -> Option<String> {
+                self.0.take()
+            }
+        }
+
+        let runner = Runner::spawn(machine(&[0x12, 0x00]), 1000, Failing(Some("disk full".to_owned())), 4);
+        let frame = wait_for(&runner, |frame| frame.notice.is_some());
+        assert_eq!(frame.notice.as_deref(), Some("disk full"));
+        runner.stop();
+    }
+
+    #[test]
+    fn resets_the_observer_with_the_machine() {
+        /// Counts resets.
+        struct Resets(u32);
+
+        impl Observer for Resets {
+            fn reset(&mut self) {
+                self.0 += 1;
+            }
+        }
+
+        let runner = Runner::spawn(machine(&[0x12, 0x00]), 1000, Resets(0), 4);
+        runner.send(Control::SoftReset(0x200));
+        runner.send(Control::Load(Box::new(machine(&[0x12, 0x00])), 1000));
+        // controls already sent are applied before the core stops
+        assert_eq!(runner.stop().0, 2);
+    }
+
+    #[test]
+    fn stops_on_a_fault() {
+        let runner = Runner::spawn(machine(&[0x00, 0xEE]), 1000, (), 4);
+        let frame = wait_for(&runner, |frame| frame.fault.is_some());
+        assert_eq!(frame.fault, Some(Fault::StackUnderflow { pc: 0x200 }));
+        assert_eq!(frame.history, vec![(0x200, 0x00EE)]);
+        runner.send(Control::Pause(true));
+        runner.stop();
+    }
+}
@@ -0,0 +1,84 @@
+//! The CHIP-8 display as one 64x32 texture, drawn scaled up in a single
+//! image call. It's only uploaded again when the core reports a new frame
+//! or the palette changes.
+
+use piston_window::texture::{CreateTexture, Format, UpdateTexture};
+use piston_window::*;
+
+use chip8::palette::Palette;
+use chip8::display::{self, Display};
+use chip8::Machine;
+
+const SIZE: [u32; 2] = [display::WIDTH as u32, display::HEIGHT as u32];
+
+pub struct Screen {
+    texture: G2dTexture,
+    context: G2dTextureContext,
+    /// The palette the texture was last drawn in.
+    palette: Option<Palette>
+}
+
+impl Screen {
+    pub fn new(window: &mut PistonWindow) -> Screen {
+        let mut context = window.create_texture_context();
+        let settings = TextureSettings::new().filter(Filter::Nearest);
+        let blank = vec![0; SIZE[0] as usize * SIZE[1] as usize * 4];
+        let texture = CreateTexture::create(&mut context, Format::Rgba8, &blank, SIZE, &settings).unwrap();
+        Screen { texture, context, palette: None }
+    }
+
+    /// Uploads the display if it changed since the last frame, or was drawn
+    /// in another palette.
+    pub fn update(&mut self, window: &mut PistonWindow, machine: &mut Machine, palette: Palette) {
+        let changed = machine.take_frame().is_some();
+        if changed || self.palette != Some(palette) {
+            let pixels = rgba(&machine.gfx, palette);
+            UpdateTexture::update(&mut self.texture, &mut self.context, Format::Rgba8, &pixels, [0, 0], SIZE).unwrap();
+            self.context.encoder.flush(&mut window.device);
+            self.palette = Some(palette);
+        }
+    }
+
+    /// Draws the display with its top left corner at the origin, `scale`
+    /// window pixels to a CHIP-8 pixel.
+    pub fn draw(&self, scale: f64, context: &Context, graphics: &mut G2d) {
+        image(&self.texture, context.transform.scale(scale, scale), graphics);
+    }
+}
+
+/// The display as RGBA bytes, row by row. Palette colours are linear, as
+/// piston takes them for drawing, and textures hold sRGB.
+fn rgba(gfx: &Display, palette: Palette) -> Vec<u8> {
+    let bytes = |colour| {
+        let srgb = color::gamma_linear_to_srgb(colour);
+        [srgb[0], srgb[1], srgb[2], srgb[3]].map(|c| (c * 255.0).round() as u8)
+    };
+    let (on, off) = (bytes(palette.foreground), bytes(palette.background));
+
+    let mut pixels = Vec::with_capacity(display::WIDTH * display::HEIGHT * 4);
+    for y in 0..display::HEIGHT {
+        let row = gfx.row(y);
+        for x in 0..display::WIDTH {
+            let lit = row & (1 << (display::WIDTH - 1 - x)) != 0;
+            pixels.extend_from_slice(if lit { &on } else { &off });
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_the_display_to_rgba() {
+        let mut gfx = Display::new();
+        gfx.set(1, 0, true);
+        gfx.set(63, 31, true);
+        let palette = Palette { foreground: [1.0, 0.0, 0.0, 1.0], background: [0.0, 0.0, 0.0, 1.0] };
+        let pixels = rgba(&gfx, palette);
+        assert_eq!(pixels.len(), 64 * 32 * 4);
+        assert_eq!(pixels[..8], [0, 0, 0, 255, 255, 0, 0, 255]);
+        assert_eq!(pixels[pixels.len() - 4..], [255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,308 @@
+//! The terminal frontend, for machines without a display: the screen drawn
+//! in Unicode half blocks or braille, the debug panel beside it, and the
+//! keypad read from the keyboard with the same keymap and game keys as the
+//! window, except that B is Tab, as terminals don't report Shift alone.
+//!
+//! Most terminals only report key presses, repeating them while a key is
+//! held, so a key counts as held until `HOLD` passes without a repeat.
+//! Terminals that speak the kitty keyboard protocol report releases, and
+//! those are used instead.
+
+use std::io::{self, Stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags};
+use crossterm::style::{Color, Print, ResetColor, SetForegroundColor};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, queue};
+
+use chip8::cli::{Options, Settings};
+use chip8::display::{self, Display};
+use chip8::runner::{Control, Frame, Runner};
+use chip8::{Fault, Observer, KEYPAD_LAYOUT};
+
+use crate::inspector;
+
+/// How long a key stays down after its last press or repeat.
+const HOLD: Duration = Duration::from_millis(200);
+
+/// Time between redraws.
+const FRAME: Duration = Duration::from_millis(16);
+
+/// Columns per panel cell.
+const CELL_WIDTH: u16 = 9;
+
+/// Braille dots in bit order, as (x, y) within the 2x4 cell.
+const DOTS: [(usize, usize); 8] = [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2), (0, 3), (1, 3)];
+
+/// Watches the core for the sound timer starting, so the terminal can ring
+/// its bell.
+pub struct Bell(pub Arc<AtomicBool>);
+
+impl Observer for Bell {
+    fn sound(&mut self, playing: bool) {
+        if playing {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Terminal keys for the named controls a database entry can bind.
+fn game_key(name: &str) -> Option<KeyCode> {
+    match name {
+        "up" => Some(KeyCode::Up),
+        "down" => Some(KeyCode::Down),
+        "left" => Some(KeyCode::Left),
+        "right" => Some(KeyCode::Right),
+        "a" => Some(KeyCode::Enter),
+        "b" => Some(KeyCode::Tab),
+        _ => None
+    }
+}
+
+/// The keypad key `code` stands for, through the keymap or a game key.
+fn keypad_key(code: KeyCode, keymap: &[char], game_keys: &[(KeyCode, u8)]) -> Option<u8> {
+    if let KeyCode::Char(c) = code {
+        if let Some(n) = keymap.iter().position(|&k| k == c.to_ascii_lowercase()) {
+            return Some(KEYPAD_LAYOUT[n]);
+        }
+    }
+    game_keys.iter().find(|&&(game_key, _)| game_key == code).map(|&(_, key)| key)
+}
+
+/// The terminal in raw mode on the alternate screen, put back when dropped.
+struct Terminal {
+    out: Stdout,
+    /// Key releases are reported.
+    releases: bool
+}
+
+impl Terminal {
+    fn open() -> io::Result<Terminal> {
+        terminal::enable_raw_mode()?;
+        let mut terminal = Terminal { out: io::stdout(), releases: false };
+        queue!(terminal.out, terminal::EnterAlternateScreen, cursor::Hide)?;
+        if terminal::supports_keyboard_enhancement().unwrap_or(false) {
+            queue!(terminal.out, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+            terminal.releases = true;
+        }
+        terminal.out.flush()?;
+        Ok(terminal)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.releases {
+            let _ = queue!(self.out, PopKeyboardEnhancementFlags);
+        }
+        let _ = queue!(self.out, ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = self.out.flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Shows the frames `runner` leaves and passes it the keypad until Esc or
+/// Ctrl-C, or until the program faults, which is returned. Space pauses and
+/// F5 restarts the program at `entry`. `game_keys` are the database's
+/// bindings of named controls to keypad keys.
+pub fn play<O>(runner: &Runner<O>, mut frame: Frame, settings: &Settings, options: &Options, entry: u16, bell: &AtomicBool) -> io::Result<Option<Fault>> {
+    let mut terminal = Terminal::open()?;
+    let keymap: Vec<char> = options.keymap.chars().collect();
+    let game_keys: Vec<(KeyCode, u8)> = settings.game_keys.iter()
+        .filter_map(|(name, key)| game_key(name).map(|code| (code, *key)))
+        .collect();
+    // when each keypad key was last pressed or repeated
+    let mut held: [Option<Instant>; 16] = [None; 16];
+    let mut paused = false;
+    let mut panel_rows = 0;
+    let mut full = true;
+    // shown on the status line from when the core reports it
+    let mut notice: Option<String> = None;
+
+    loop {
+        let next_frame = Instant::now() + FRAME;
+        while event::poll(next_frame.saturating_duration_since(Instant::now()))? {
+            match event::read()? {
+                Event::Key(KeyEvent { kind: KeyEventKind::Press, code: KeyCode::Esc, .. }) => return Ok(None),
+                Event::Key(KeyEvent { kind: KeyEventKind::Press, code: KeyCode::Char('c'), modifiers, .. })
+                    if modifiers.contains(KeyModifiers::CONTROL) => return Ok(None),
+                Event::Key(KeyEvent { kind: KeyEventKind::Press, code: KeyCode::Char(' '), .. }) => {
+                    paused = !paused;
+                    runner.send(Control::Pause(paused));
+                    runner.send(Control::ReleaseKeys);
+                    held = [None; 16];
+                },
+                Event::Key(KeyEvent { kind: KeyEventKind::Press, code: KeyCode::F(5), .. }) => {
+                    runner.send(Control::SoftReset(entry));
+                },
+                Event::Key(KeyEvent { kind, code, .. }) => {
+                    if let Some(key) = keypad_key(code, &keymap, &game_keys) {
+                        if kind == KeyEventKind::Release {
+                            held[key as usize] = None;
+                            runner.send(Control::Key(key, false));
+                        } else {
+                            if held[key as usize].is_none() {
+                                runner.send(Control::Key(key, true));
+                            }
+                            held[key as usize] = Some(Instant::now());
+                        }
+                    }
+                },
+                Event::Resize(..) => full = true,
+                _ => ()
+            }
+        }
+
+        if !terminal.releases {
+            for (key, pressed) in held.iter_mut().enumerate() {
+                if pressed.is_some_and(|at| at.elapsed() >= HOLD) {
+                    *pressed = None;
+                    runner.send(Control::Key(key as u8, false));
+                }
+            }
+        }
+
+        if let Some(newer) = runner.frame() {
+            // a display change not drawn yet carries over to the newer frame
+            let dirty = frame.machine.draw_flag;
+            frame = newer;
+            frame.machine.draw_flag |= dirty;
+        }
+        if let Some(fault) = frame.fault.take() {
+            return Ok(Some(fault));
+        }
+        if let Some(text) = frame.notice.take() {
+            notice = Some(text);
+        }
+        if bell.swap(false, Ordering::Relaxed) {
+            queue!(terminal.out, Print('\x07'))?;
+        }
+
+        panel_rows = draw(&mut terminal.out, &mut frame, options.braille, paused, notice.as_deref(), full, panel_rows)?;
+        full = false;
+    }
+}
+
+/// Draws the display if it changed, or everything when `full`, and the
+/// panel and status line, which shows `notice` if there is one. Returns
+/// the number of panel rows drawn, which the next call clears up to.
+fn draw(out: &mut Stdout, frame: &mut Frame, braille: bool, paused: bool, notice: Option<&str>, full: bool, panel_rows: usize) -> io::Result<usize> {
+    let lines = if frame.machine.take_frame().is_some() || full {
+        Some(if braille { braille_lines(&frame.machine.gfx) } else { half_block_lines(&frame.machine.gfx) })
+    } else {
+        None
+    };
+    let (width, height) = if braille {
+        (display::WIDTH as u16 / 2, display::HEIGHT as u16 / 4)
+    } else {
+        (display::WIDTH as u16, display::HEIGHT as u16 / 2)
+    };
+
+    if full {
+        let edge = "─".repeat(width as usize);
+        queue!(out, terminal::Clear(ClearType::All), cursor::MoveTo(0, 0), Print(format!("┌{}┐", edge)))?;
+        for y in 1..=height {
+            queue!(out, cursor::MoveTo(0, y), Print('│'), cursor::MoveTo(width + 1, y), Print('│'))?;
+        }
+        queue!(out, cursor::MoveTo(0, height + 1), Print(format!("└{}┘", edge)))?;
+    }
+    if let Some(lines) = lines {
+        for (y, line) in lines.iter().enumerate() {
+            queue!(out, cursor::MoveTo(1, 1 + y as u16), Print(line))?;
+        }
+    }
+
+    let status = match notice {
+        Some(notice) => notice,
+        None if paused => "Paused: Space resumes",
+        None => "Space pauses, F5 restarts, Esc quits"
+    };
+    queue!(out, cursor::MoveTo(0, height + 2), terminal::Clear(ClearType::UntilNewLine), Print(status))?;
+
+    let left = width + 3;
+    let rows = inspector::panel(&frame.previous, &frame.machine, &frame.history);
+    for row in 0..rows.len().max(panel_rows) {
+        queue!(out, cursor::MoveTo(left, row as u16), terminal::Clear(ClearType::UntilNewLine))?;
+        for field in rows.get(row).into_iter().flatten() {
+            let colour = if field.changed { Color::Yellow } else { Color::Green };
+            queue!(out,
+                cursor::MoveTo(left + field.cell as u16 * CELL_WIDTH, row as u16),
+                SetForegroundColor(colour),
+                Print(&field.text),
+                ResetColor)?;
+        }
+    }
+    out.flush()?;
+    Ok(rows.len())
+}
+
+/// The display two pixel rows to a line, with `▀`, `▄` and `█`.
+fn half_block_lines(gfx: &Display) -> Vec<String> {
+    (0..display::HEIGHT).step_by(2).map(|y| {
+        (0..display::WIDTH).map(|x| match (gfx.get(x, y), gfx.get(x, y + 1)) {
+            (true, true) => '█',
+            (true, false) => '▀',
+            (false, true) => '▄',
+            (false, false) => ' '
+        }).collect()
+    }).collect()
+}
+
+/// The display as braille, a 2x4 block of pixels to a character.
+fn braille_lines(gfx: &Display) -> Vec<String> {
+    (0..display::HEIGHT).step_by(4).map(|y| {
+        (0..display::WIDTH).step_by(2).map(|x| {
+            let dots = DOTS.iter().enumerate()
+                .filter(|&(_, &(dx, dy))| gfx.get(x + dx, y + dy))
+                .fold(0, |dots, (bit, _)| dots | 1 << bit);
+            char::from_u32(0x2800 + dots).unwrap()
+        }).collect()
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_half_blocks() {
+        let mut gfx = Display::new();
+        gfx.set(0, 0, true);
+        gfx.set(1, 1, true);
+        gfx.set(2, 0, true);
+        gfx.set(2, 1, true);
+        let lines = half_block_lines(&gfx);
+        assert_eq!(lines.len(), 16);
+        assert!(lines[0].starts_with("▀▄█ "));
+        assert_eq!(lines[15].chars().count(), 64);
+    }
+
+    #[test]
+    fn draws_braille() {
+        let mut gfx = Display::new();
+        gfx.set(0, 0, true);
+        gfx.set(1, 3, true);
+        gfx.set(63, 31, true);
+        let lines = braille_lines(&gfx);
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0].chars().next(), Some('\u{2881}'));
+        assert_eq!(lines[7].chars().last(), Some('\u{2880}'));
+        assert_eq!(lines[7].chars().count(), 32);
+    }
+
+    #[test]
+    fn maps_keymap_and_game_keys_to_the_keypad() {
+        let keymap: Vec<char> = "1234qwerasdfzxcv".chars().collect();
+        let game_keys = [(KeyCode::Up, 5), (KeyCode::Tab, 0xA)];
+        assert_eq!(keypad_key(KeyCode::Char('W'), &keymap, &game_keys), Some(0x5));
+        assert_eq!(keypad_key(KeyCode::Char('x'), &keymap, &game_keys), Some(0x0));
+        assert_eq!(keypad_key(KeyCode::Up, &keymap, &game_keys), Some(5));
+        assert_eq!(keypad_key(KeyCode::Tab, &keymap, &game_keys), Some(0xA));
+        assert_eq!(keypad_key(KeyCode::Down, &keymap, &game_keys), None);
+        assert_eq!(game_key("b"), Some(KeyCode::Tab));
+    }
+}
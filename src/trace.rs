@@ -0,0 +1,187 @@
+//! Execution traces written by `--trace`, one line per instruction with the
+//! state it was fetched in:
+//!
+//! ```text
+//! 0000000001 0200 6A02 v=00000000000000000000000000000000 i=0000 sp=0 LD VA, 0x02
+//! ```
+//!
+//! The fields are the cycle count from 1, PC, the opcode, V0 to VF as one
+//! run of hex digits, I, SP and the disassembly. All numbers but the cycle
+//! and SP are hex. The format is kept stable so traces can be diffed.
+//! An FX0A waiting for a key is written once, not on every retry.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::disasm::disassemble;
+use crate::{Machine, Observer, OpCode};
+
+/// The state logged for one instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: OpCode,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8
+}
+
+impl Record {
+    pub fn of(cycle: u64, machine: &Machine) -> Record {
+        let pc = machine.pc as usize;
+        Record {
+            cycle,
+            pc: machine.pc,
+            opcode: (machine.memory[pc] as u16) << 8 | machine.memory[pc + 1] as u16,
+            v: machine.v,
+            i: machine.i,
+            sp: machine.sp
+        }
+    }
+
+    /// Reads a line in the format above; the disassembly is ignored.
+    pub fn parse(line: &str) -> Result<Record, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            return Err("expected cycle, PC, opcode, v=, i= and sp=".to_owned());
+        }
+        let hex = |name: &str, digits: &str| u16::from_str_radix(digits, 16).map_err(|_| format!("invalid {} '{}'", name, digits));
+        let field = |n: usize, key: &str| fields[n].strip_prefix(key).ok_or(format!("expected {}", key));
+
+        let v_digits = field(3, "v=")?;
+        if v_digits.len() != 32 || !v_digits.is_ascii() {
+            return Err(format!("invalid registers '{}', expected 32 hex digits", v_digits));
+        }
+        let mut v = [0; 16];
+        for (x, value) in v.iter_mut().enumerate() {
+            *value = u8::from_str_radix(&v_digits[x*2..x*2 + 2], 16).map_err(|_| format!("invalid registers '{}'", v_digits))?;
+        }
+
+        Ok(Record {
+            cycle: fields[0].parse().map_err(|_| format!("invalid cycle '{}'", fields[0]))?,
+            pc: hex("PC", fields[1])?,
+            opcode: hex("opcode", fields[2])?,
+            v,
+            i: hex("I", field(4, "i=")?)?,
+            sp: field(5, "sp=")?.parse().map_err(|_| format!("invalid SP '{}'", fields[5]))?
+        })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let v: String = self.v.iter().map(|x| format!("{:02X}", x)).collect();
+        write!(f, "{:010} {:04X} {:04X} v={} i={:04X} sp={} {}",
+            self.cycle, self.pc, self.opcode, v, self.i, self.sp, disassemble(self.opcode))
+    }
+}
+
+pub struct Tracer {
+    out: BufWriter<File>,
+    /// Only instructions at addresses in this inclusive range are written.
+    range: Option<(u16, u16)>,
+    cycle: u64,
+    /// The first write that failed, until it's taken.
+    error: Option<io::Error>,
+    /// Set by a failed write; nothing more is written after it.
+    failed: bool
+}
+
+impl Tracer {
+    pub fn create(path: &Path, range: Option<(u16, u16)>) -> io::Result<Tracer> {
+        Ok(Tracer { out: BufWriter::new(File::create(path)?), range, cycle: 0, error: None, failed: false })
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+impl Observer for Tracer {
+    fn fetch(&mut self, machine: &Machine, _opcode: OpCode) {
+        // an FX0A already waiting is being retried
+        if machine.await_keypress || self.failed {
+            return;
+        }
+        self.cycle += 1;
+        if let Some((start, end)) = self.range {
+            if machine.pc < start || machine.pc > end {
+                return;
+            }
+        }
+        if let Err(err) = writeln!(self.out, "{}", Record::of(self.cycle, machine)) {
+            self.error = Some(err);
+            self.failed = true;
+        }
+    }
+
+    fn take_notice(&mut self) -> Option<String> {
+        self.take_error().map(|err| format!("Tracing stopped: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn formats_the_state_before_each_instruction() {
+        let mut machine = Machine::new(Config::default());
+        machine.memory[0x200..0x202].copy_from_slice(&[0x6A, 0x02]);
+        machine.v[0xF] = 1;
+        machine.i = 0x3A0;
+        machine.sp = 2;
+        let line = Record::of(7, &machine).to_string();
+        assert_eq!(line, format!("0000000007 0200 6A02 v={}01 i=03A0 sp=2 LD VA, 0x02", "00".repeat(15)));
+        assert_eq!(Record::parse(&line), Ok(Record::of(7, &machine)));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(Record::parse("1 0200 6A02").is_err());
+        assert!(Record::parse("1 0200 6A02 v=00 i=0000 sp=0").is_err());
+        assert!(Record::parse(&format!("1 0200 6A02 v={} sp=0 i=0000", "00".repeat(16))).is_err());
+        assert!(Record::parse(&format!("x 0200 6A02 v={} i=0000 sp=0", "00".repeat(16))).is_err());
+    }
+
+    #[test]
+    fn writes_only_the_requested_range() {
+        let path = env::temp_dir().join("chip8-trace-test.log");
+        let mut machine = Machine::new(Config::default());
+        {
+            let mut tracer = Tracer::create(&path, Some((0x202, 0x203))).unwrap();
+            for pc in [0x200, 0x202, 0x204] {
+                machine.pc = pc;
+                tracer.fetch(&machine, 0x00E0);
+            }
+            machine.await_keypress = true;
+            tracer.fetch(&machine, 0xF00A);
+            assert!(tracer.take_error().is_none());
+        }
+        let trace = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("0000000002 0202 "));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn reports_a_failed_write_once_and_stops() {
+        let mut tracer = Tracer::create(Path::new("/dev/full"), None).unwrap();
+        let machine = Machine::new(Config::default());
+        // enough lines to fill the buffer and make it write
+        for _ in 0..1000 {
+            tracer.fetch(&machine, 0x00E0);
+        }
+        assert!(tracer.take_notice().unwrap().starts_with("Tracing stopped: "));
+        tracer.fetch(&machine, 0x00E0);
+        assert_eq!(tracer.take_notice(), None);
+    }
+}
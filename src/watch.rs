@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Polls a file's modification time to spot rebuilds.
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    // a change seen on the last poll, reported once the file stops changing
+    pending: Option<SystemTime>,
+    last_poll: Instant
+}
+
+impl Watcher {
+    pub fn new(path: PathBuf) -> Watcher {
+        let modified = modified(&path);
+        Watcher { path, modified, pending: None, last_poll: Instant::now() }
+    }
+
+    /// True once after the file changes and has been left alone for a poll,
+    /// so a half-written build isn't picked up.
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        self.poll()
+    }
+
+    fn poll(&mut self) -> bool {
+        let current = modified(&self.path);
+        if current.is_none() || current == self.modified {
+            self.pending = None;
+            return false;
+        }
+        if self.pending == current {
+            self.modified = current;
+            self.pending = None;
+            return true;
+        }
+        self.pending = current;
+        false
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn reports_a_change_once_it_settles() {
+        let path = env::temp_dir().join("chip8-watch-test.ch8");
+        fs::write(&path, [0x00, 0xE0]).unwrap();
+        let mut watcher = Watcher::new(path.clone());
+        assert!(!watcher.poll());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.poll());
+    }
+}
@@ -0,0 +1,149 @@
+// Chip 8, recompiled by `chip8 recompile`.
+//
+// Build it in a crate that depends on `chip8`, for example by copying it
+// into that crate's `examples` folder and running `cargo run --release --example`.
+
+use chip8::display;
+use chip8::palette::Palette;
+use chip8::{Config, Fault, Machine};
+
+const CONFIG: Config = Config { vf_reset: false, shift_uses_vy: false, stack_depth: 16, key_wait_release: true, load_address: 512 };
+
+const BASE: u16 = 0x201;
+
+const IMAGE: [u8; 47] = [
+    0xE0, 0x60, 0x05, 0x61, 0x0A, 0xF0, 0x29, 0xD0, 0x15, 0x22, 0x20, 0x72, 0x01, 0x32, 0x40, 0x12,
+    0x06, 0xC3, 0x0F, 0x62, 0x00, 0x12, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80,
+    0x24, 0x81, 0x26, 0xA3, 0x00, 0xF2, 0x33, 0xF3, 0x55, 0xF3, 0x65, 0x8E, 0x35, 0x00, 0xEE
+];
+
+fn main() {
+    let mut machine = Machine::new(CONFIG);
+    machine.load_program(&IMAGE, BASE);
+    machine.pc = 0x200;
+    let palette = Palette { foreground: [1.0, 1.0, 1.0, 1.0], background: [0.0, 0.0, 0.0, 1.0] };
+    chip8::native::play("Chip 8", machine, 500, palette, run_block);
+}
+
+/// Runs the block at PC if memory still holds the code it was translated
+/// from, or else interprets one instruction. Returns how many ran.
+fn run_block(machine: &mut Machine) -> Result<u32, Fault> {
+    let pc = machine.pc as usize;
+    match machine.pc {
+        0x200 if machine.memory[pc..pc + 6] == [0x00, 0xE0, 0x60, 0x05, 0x61, 0x0A] => block_200(machine),
+        0x206 if machine.memory[pc..pc + 6] == [0xF0, 0x29, 0xD0, 0x15, 0x22, 0x20] => block_206(machine),
+        0x20C if machine.memory[pc..pc + 4] == [0x72, 0x01, 0x32, 0x40] => block_20c(machine),
+        0x210 if machine.memory[pc..pc + 2] == [0x12, 0x06] => block_210(machine),
+        0x212 if machine.memory[pc..pc + 6] == [0xC3, 0x0F, 0x62, 0x00, 0x12, 0x06] => block_212(machine),
+        0x220 if machine.memory[pc..pc + 8] == [0x80, 0x24, 0x81, 0x26, 0xA3, 0x00, 0xF2, 0x33] => block_220(machine),
+        0x228 if machine.memory[pc..pc + 2] == [0xF3, 0x55] => block_228(machine),
+        0x22A if machine.memory[pc..pc + 6] == [0xF3, 0x65, 0x8E, 0x35, 0x00, 0xEE] => block_22a(machine),
+        _ => machine.step().map(|_| 1)
+    }
+}
+
+fn block_200(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x200: CLS
+    machine.gfx.clear();
+    machine.draw_flag = true;
+    // 0x202: LD V0, 0x05
+    machine.v[0x0] = 0x05;
+    // 0x204: LD V1, 0x0A
+    machine.v[0x1] = 0x0A;
+    machine.pc = 0x206;
+    Ok(3)
+}
+
+fn block_206(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x206: LD F, V0
+    machine.i = machine.v[0x0] as u16 * 5;
+    // 0x208: DRW V0, V1, 5
+    let x = machine.v[0x0] as usize % display::WIDTH;
+    let y = machine.v[0x1] as usize % display::HEIGHT;
+    let i = machine.i as usize;
+    let visible = (display::HEIGHT - y).min(5);
+    let collision = machine.gfx.draw_sprite(x, y, &machine.memory[i..i + visible]);
+    machine.v[0xF] = collision as u8;
+    machine.draw_flag = true;
+    // 0x20A: CALL 0x220
+    if machine.sp >= 16 {
+        machine.pc = 0x20A;
+        return machine.run_opcode(0x2220).map(|_| 3);
+    }
+    machine.stack[machine.sp as usize] = 0x20A;
+    machine.sp += 1;
+    machine.pc = 0x220;
+    Ok(3)
+}
+
+fn block_20c(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x20C: ADD V2, 0x01
+    machine.v[0x2] = machine.v[0x2].wrapping_add(0x01);
+    // 0x20E: SE V2, 0x40
+    machine.pc = if machine.v[0x2] == 0x40 { 0x212 } else { 0x210 };
+    Ok(2)
+}
+
+fn block_210(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x210: JP 0x206
+    machine.pc = 0x206;
+    Ok(1)
+}
+
+fn block_212(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x212: RND V3, 0x0F
+    machine.pc = 0x212;
+    machine.run_opcode(0xC30F)?;
+    // 0x214: LD V2, 0x00
+    machine.v[0x2] = 0x00;
+    // 0x216: JP 0x206
+    machine.pc = 0x206;
+    Ok(3)
+}
+
+fn block_220(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x220: ADD V0, V2
+    let (value, overflow) = machine.v[0x0].overflowing_add(machine.v[0x2]);
+    machine.v[0x0] = value;
+    machine.v[0xF] = overflow as u8;
+    // 0x222: SHR V1, V2
+    let source = machine.v[0x1];
+    machine.v[0x1] = source >> 1;
+    machine.v[0xF] = source & 0x01;
+    // 0x224: LD I, 0x300
+    machine.i = 0x300;
+    // 0x226: LD B, V2
+    let value = machine.v[0x2];
+    let i = machine.i as usize;
+    machine.memory[i..i + 3].copy_from_slice(&[value / 100, value / 10 % 10, value % 10]);
+    machine.pc = 0x228;
+    Ok(4)
+}
+
+fn block_228(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x228: LD [I], V3
+    let i = machine.i as usize;
+    machine.memory[i..=i + 3].copy_from_slice(&machine.v[..=0x3]);
+    machine.i += 4;
+    machine.pc = 0x22A;
+    Ok(1)
+}
+
+fn block_22a(machine: &mut Machine) -> Result<u32, Fault> {
+    // 0x22A: LD V3, [I]
+    let i = machine.i as usize;
+    machine.v[..=0x3].copy_from_slice(&machine.memory[i..=i + 3]);
+    machine.i += 4;
+    // 0x22C: SUB VE, V3
+    let (value, overflow) = machine.v[0xE].overflowing_sub(machine.v[0x3]);
+    machine.v[0xE] = value;
+    machine.v[0xF] = !overflow as u8;
+    // 0x22E: RET
+    if machine.sp == 0 {
+        machine.pc = 0x22E;
+        return machine.run_opcode(0x00EE).map(|_| 3);
+    }
+    machine.sp -= 1;
+    machine.pc = machine.stack[machine.sp as usize] + 2;
+    Ok(3)
+}